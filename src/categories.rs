@@ -0,0 +1,109 @@
+use regex::{Regex, RegexBuilder};
+use serde::{Serialize, Deserialize};
+
+use crate::db::Rule;
+
+// Separator between the levels of a category path, as displayed by aw-webui
+pub const PATH_SEPARATOR: &str = " > ";
+
+// Full path of an event category according to the rules of a ruleset. Events may be
+// reported either with the full path or with just the name of the leaf category.
+// Categories the ruleset doesn't know are kept as they are.
+pub fn resolve_category(rules: &[Rule], category: &str) -> String {
+    if rules.iter().any(|r| r.name.join(PATH_SEPARATOR) == category) {
+        return category.to_string();
+    }
+    let mut matches = rules.iter().filter(|r| r.name.last().map(String::as_str) == Some(category));
+    match (matches.next(), matches.next()) {
+        (Some(rule), None) => rule.name.join(PATH_SEPARATOR),
+        _ => category.to_string(),
+    }
+}
+
+pub fn compile_rule(rule: &Rule) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(&rule.regex)
+        .case_insensitive(rule.ignore_case)
+        .build()
+}
+
+// Category settings as exported from the aw-webui settings page, either the
+// full export object or just the list of categories
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum WebUiExport {
+    Settings { categories: Vec<WebUiCategory> },
+    Categories(Vec<WebUiCategory>),
+}
+
+impl WebUiExport {
+    pub fn into_categories(self) -> Vec<WebUiCategory> {
+        match self {
+            WebUiExport::Settings { categories } => categories,
+            WebUiExport::Categories(categories) => categories,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WebUiCategory {
+    name: Vec<String>,
+    #[serde(default)]
+    rule: Option<WebUiRule>,
+}
+
+#[derive(Deserialize)]
+pub struct WebUiRule {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    regex: Option<String>,
+    #[serde(default)]
+    ignore_case: bool,
+}
+
+// A category that could not be turned into a rule
+#[derive(Debug, Serialize)]
+pub struct SkippedCategory {
+    pub name: Vec<String>,
+    pub reason: String,
+}
+
+// Converts aw-webui categories into rules. Categories without a rule (type `none`)
+// only group their children, whose names already carry the full path, so they
+// need no rule of their own.
+pub fn import_webui_categories(categories: Vec<WebUiCategory>) -> (Vec<Rule>, Vec<SkippedCategory>) {
+    let mut rules = Vec::new();
+    let mut skipped = Vec::new();
+    for category in categories {
+        let skip = |reason: &str| SkippedCategory { name: category.name.clone(), reason: reason.to_string() };
+        if category.name.is_empty() || category.name.iter().any(|n| n.trim().is_empty()) {
+            skipped.push(skip("category name is empty"));
+            continue;
+        }
+        let rule = match category.rule {
+            Some(rule) => rule,
+            None => continue,
+        };
+        match rule.kind.as_str() {
+            "none" => continue,
+            "regex" => (),
+            kind => {
+                skipped.push(skip(&format!("unsupported rule type `{}`", kind)));
+                continue;
+            }
+        }
+        let regex = match rule.regex {
+            Some(regex) if !regex.is_empty() => regex,
+            _ => {
+                skipped.push(skip("rule has no regex"));
+                continue;
+            }
+        };
+        let rule = Rule { name: category.name.clone(), regex, ignore_case: rule.ignore_case };
+        match compile_rule(&rule) {
+            Ok(_) => rules.push(rule),
+            Err(err) => skipped.push(skip(&format!("invalid regex: {}", err))),
+        }
+    }
+    (rules, skipped)
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::retention::RetentionAction;
+use crate::validation::EventPolicy;
+
+// Application settings, read from the Rocket figment (Rocket.toml or ROCKET_* env vars)
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    // How uploaded events that don't fit their hour are handled
+    #[serde(default)]
+    pub event_policy: EventPolicy,
+    // Fill the database with demo users and activity on startup, for development only
+    #[serde(default)]
+    pub seed: bool,
+    // Key login tokens are signed with. Release builds refuse to start without one.
+    #[serde(default)]
+    pub jwt_secret: Option<String>,
+    #[serde(default = "default_jwt_issuer")]
+    pub jwt_issuer: String,
+    #[serde(default = "default_jwt_audience")]
+    pub jwt_audience: String,
+    // How long a login lasts, in seconds
+    #[serde(default = "default_jwt_expiry")]
+    pub jwt_expiry: i64,
+    // Address the server is reached at, for links pointing back to it
+    #[serde(default = "default_public_url")]
+    pub public_url: String,
+    // OAuth providers users can log in with, by name
+    #[serde(default)]
+    pub oauth_providers: HashMap<String, OAuthProvider>,
+    // How emails are sent
+    #[serde(default)]
+    pub mailer: MailerConfig,
+    // Sender of the emails, as a mailbox such as `aw-leaderboard <noreply@example.com>`
+    #[serde(default = "default_mail_from")]
+    pub mail_from: String,
+    // Limits on requests to the login and upload endpoints
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    // Days a deleted account is kept before it's removed for good, during which logging
+    // in again restores it. With 0 accounts are removed right away.
+    #[serde(default)]
+    pub account_deletion_grace_days: u32,
+    // How long hourly activity is kept
+    #[serde(default)]
+    pub retention: Retention,
+    // Run the hourly task that removes deleted accounts and prunes activity
+    #[serde(default = "default_maintenance")]
+    pub maintenance: bool,
+}
+
+impl Config {
+    // Absolute link to a path of the server
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.public_url.trim_end_matches('/'), path)
+    }
+}
+
+// An OAuth2 provider using the authorization code flow, such as GitHub
+#[derive(Debug, Deserialize)]
+pub struct OAuthProvider {
+    pub client_id: String,
+    pub client_secret: String,
+    pub auth_url: String,
+    pub token_url: String,
+    // Returns the logged in account as JSON
+    pub userinfo_url: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // Fields of the userinfo response holding the account ID, username and email
+    #[serde(default = "default_id_field")]
+    pub id_field: String,
+    #[serde(default = "default_username_field")]
+    pub username_field: String,
+    #[serde(default = "default_email_field")]
+    pub email_field: String,
+}
+
+fn default_jwt_issuer() -> String {
+    "aw-leaderboard".to_string()
+}
+
+fn default_jwt_audience() -> String {
+    "aw-leaderboard".to_string()
+}
+
+fn default_jwt_expiry() -> i64 {
+    7 * 24 * 60 * 60
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum MailerConfig {
+    // Writes emails to the log instead of sending them, for development
+    #[default]
+    Log,
+    // Appends emails to a file, for tests
+    File { path: PathBuf },
+    Smtp {
+        host: String,
+        // Defaults to the port of the TLS mode
+        #[serde(default)]
+        port: Option<u16>,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        tls: SmtpTls,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    // Upgrades the connection with STARTTLS, on port 587 by default
+    #[default]
+    Starttls,
+    // TLS from the start, on port 465 by default
+    Tls,
+    // Plain text, for relays on the local machine only
+    None,
+}
+
+// Token buckets per client IP and per account, for each group of routes. A group is
+// configured as a whole, e.g. `auth = { per_ip = { burst = 20, per_minute = 10 }, per_account = { ... } }`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub enabled: bool,
+    // Logging in, signing up, two-factor codes and password resets
+    pub auth: RouteLimits,
+    // Activity uploads
+    pub ingest: RouteLimits,
+    // Locking accounts after failed logins
+    pub lockout: Lockout,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            enabled: true,
+            auth: RouteLimits {
+                per_ip: Limit { burst: 20, per_minute: 10 },
+                per_account: Limit { burst: 10, per_minute: 5 },
+            },
+            ingest: RouteLimits {
+                per_ip: Limit { burst: 120, per_minute: 60 },
+                per_account: Limit { burst: 240, per_minute: 120 },
+            },
+            lockout: Lockout::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteLimits {
+    pub per_ip: Limit,
+    pub per_account: Limit,
+}
+
+// Up to `burst` requests at once, refilled at `per_minute`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Lockout {
+    // Failed logins in a row before an account is locked
+    pub failures: u32,
+    // How long the first lockout lasts, doubled with every further failure
+    pub seconds: u64,
+    pub max_seconds: u64,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Lockout { failures: 5, seconds: 30, max_seconds: 60 * 60 }
+    }
+}
+
+// Hourly activity older than `days` is pruned by the maintenance task, e.g.
+// `retention = { days = 365, action = "compact" }`. Users may choose their own number of days.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    // Kept forever if unset
+    pub days: Option<u32>,
+    pub action: RetentionAction,
+    // Only logs what would be pruned
+    pub dry_run: bool,
+}
+
+fn default_maintenance() -> bool {
+    true
+}
+
+fn default_public_url() -> String {
+    "http://localhost:8000".to_string()
+}
+
+fn default_id_field() -> String {
+    "id".to_string()
+}
+
+fn default_username_field() -> String {
+    "login".to_string()
+}
+
+fn default_email_field() -> String {
+    "email".to_string()
+}
+
+fn default_mail_from() -> String {
+    "aw-leaderboard <noreply@localhost>".to_string()
+}
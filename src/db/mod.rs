@@ -0,0 +1,476 @@
+use bcrypt::verify;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use serde_with::serde_as;
+use sha2::{Digest, Sha256};
+use std::{collections::{hash_map::Entry, HashMap}, env, sync::Arc, time::Duration};
+use chrono::{prelude::*};
+use r2d2_sqlite::SqliteConnectionManager;
+use uuid::Uuid;
+
+use crate::categories::resolve_category;
+use crate::error::DatastoreError;
+use crate::privacy::Privacy;
+use crate::retention::RetentionAction;
+
+pub mod postgres;
+pub mod sqlite;
+
+use self::postgres::PostgresDb;
+use self::sqlite::SqliteDb;
+
+// The datastore the server runs on, shared between all requests and background tasks
+pub type Db = Arc<dyn Datastore>;
+
+// Structs of this module only serialize fields that may be shown to anyone allowed to
+// see the row, so ids of their owner are skipped. User holds the password hash and email
+// so it doesn't derive Serialize at all, pages and responses show users as a PublicUser
+// or PrivateUser instead. Sessions are only ever shown to their own user, who may see
+// where they logged in from.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub email: String,
+    pub password: String,
+    pub created_at: Option<DateTime<Utc>>,
+    // Unverified users are left out of the leaderboards
+    pub email_verified: bool,
+    // When a user who deleted their account is removed for good, None for active users
+    pub deletion_due: Option<DateTime<Utc>>,
+}
+
+// A user as anyone may see them
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicUser {
+    pub username: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl From<&User> for PublicUser {
+    fn from(user: &User) -> Self {
+        PublicUser { username: user.username.clone(), created_at: user.created_at }
+    }
+}
+
+// The logged in user as they see themselves
+#[derive(Debug, Clone, Serialize)]
+pub struct PrivateUser {
+    pub id: i64,
+    pub username: String,
+    pub created_at: Option<DateTime<Utc>>,
+    pub email_verified: bool,
+}
+
+impl From<&User> for PrivateUser {
+    fn from(user: &User) -> Self {
+        PrivateUser {
+            id: user.id,
+            username: user.username.clone(),
+            created_at: user.created_at,
+            email_verified: user.email_verified,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Device {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: i64,
+    pub name: String,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+// Data is a JSON object of the shape:
+// {
+//  "time": {
+//    "category 1": <float duration in seconds>,
+//    "category 2": <float duration in seconds>,
+//  },
+// }
+#[derive(Debug, Serialize)]
+pub struct Activity {
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub device_id: Uuid,
+    pub events: Vec<Event>,
+    pub ruleset_id: i64,
+}
+
+// Time of a device in a category on a UTC day. Once the hours of a day were compacted
+// this is all that's left of them. Rollups compacted before they were kept per device
+// have no device.
+#[derive(Debug, Serialize)]
+pub struct DailyRollup {
+    pub day: DateTime<Utc>,
+    pub device_id: Option<Uuid>,
+    pub category: String,
+    pub duration: u64,
+}
+
+use serde_with::{DurationSeconds};
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub timestamp: DateTime<Utc>,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub duration: Duration,
+    pub category: String,
+}
+
+// How a report for an hour that already has activity is handled
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportMode {
+    // The new events replace the stored ones
+    #[default]
+    Replace,
+    // The new events are added to the stored ones, skipping exact duplicates
+    Merge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportOutcome {
+    Created,
+    Replaced,
+    Unchanged,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Ruleset {
+    pub id: i64,
+    #[serde(skip)]
+    pub user_id: i64,
+    pub name: String,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub name: Vec<String>,
+    pub regex: String,
+    #[serde(default)]
+    pub ignore_case: bool,
+}
+
+// API keys are only ever stored hashed, the secret is shown once on creation.
+// A key scoped to a device may only be used to report activity for that device.
+#[derive(Debug, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    #[serde(skip)]
+    pub user_id: i64,
+    pub device_id: Option<Uuid>,
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+// A login, referenced by the login token so it can be listed and revoked
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: i64,
+    pub created: DateTime<Utc>,
+    pub last_used: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+// Account of an OAuth provider a user logs in with
+#[derive(Debug, Serialize)]
+pub struct OauthIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub created: DateTime<Utc>,
+}
+
+// Second factor of a user. Enrollment is pending until a code of the secret was confirmed.
+#[derive(Debug)]
+pub struct Totp {
+    pub secret: String,
+    pub enabled: bool,
+    // Recovery codes that are left
+    pub recovery_codes: i64,
+}
+
+pub const API_KEY_PREFIX: &str = "awlb_";
+
+// Midnight UTC of the day containing the given time
+pub fn start_of_day(t: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&t.date_naive().and_hms_opt(0, 0, 0).unwrap())
+}
+
+type Result<T> = std::result::Result<T, DatastoreError>;
+
+// Opens the database DATABASE_URL points to: PostgreSQL for postgres:// URLs,
+// otherwise an SQLite file at that path.
+pub fn open() -> Result<Db> {
+    match env::var("DATABASE_URL") {
+        Ok(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+            Ok(Arc::new(PostgresDb::new(url.parse()?)?))
+        }
+        Ok(path) => Ok(Arc::new(SqliteDb::new(SqliteConnectionManager::file(path))?)),
+        Err(_) => {
+            log::warn!("DATABASE_URL was unset, using in-memory database");
+            Ok(Arc::new(SqliteDb::new(SqliteConnectionManager::memory())?))
+        }
+    }
+}
+
+// Operations every storage backend provides. Lookups of missing rows fail with
+// DatastoreError::NotFound, whatever the backend.
+pub trait Datastore: Send + Sync {
+    fn add_user(&self, username: &str, email: &str, password: &str) -> Result<()>;
+    fn get_user(&self, username: &str) -> Result<User>;
+    fn get_user_by_id(&self, user_id: i64) -> Result<User>;
+    fn get_user_by_email(&self, email: &str) -> Result<User>;
+    fn set_email_verified(&self, user_id: i64) -> Result<()>;
+    fn set_password(&self, user_id: i64, password: &str) -> Result<()>;
+    // Removes a user and everything of theirs in a single transaction
+    fn delete_user(&self, user_id: i64) -> Result<()>;
+    // Sets when a user is removed for good, or cancels the deletion with None. Until
+    // then they are left out of the leaderboards and their API keys are refused.
+    fn schedule_user_deletion(&self, user_id: i64, due: Option<DateTime<Utc>>) -> Result<()>;
+    // Users whose deletion is due at the given time
+    fn get_users_due_for_deletion(&self, now: DateTime<Utc>) -> Result<Vec<i64>>;
+
+    fn add_device(&self, user_id: i64, device_id: Uuid, name: &str) -> Result<()>;
+    fn get_devices(&self, user_id: i64) -> Result<Vec<Device>>;
+    fn get_device(&self, device_id: &Uuid) -> Result<Device>;
+    // Removes a device along with its activity, its rollups and the keys scoped to it
+    fn delete_device(&self, device_id: &Uuid) -> Result<()>;
+    // Calls `f` with each hour of activity of a device, oldest first, as it is read from
+    // the database. Errors of `f` stop the iteration and are returned.
+    fn for_each_activity(&self, device_id: &Uuid, f: &mut (dyn FnMut(Activity) -> Result<()> + Send)) -> Result<()>;
+
+    // Time per verified user and category since the given day, from the daily rollups
+    fn get_daily_totals(&self, since: Option<DateTime<Utc>>) -> Result<Vec<(String, String, u64)>>;
+    // Time per category of a user since the given day, from the daily rollups
+    fn get_category_totals(&self, user_id: i64, since: Option<DateTime<Utc>>) -> Result<Vec<(String, u64)>>;
+    // Daily rollups of a user, oldest first
+    fn get_daily_rollups(&self, user_id: i64) -> Result<Vec<DailyRollup>>;
+    // Recomputes the daily rollups from the stored activity, but those of days whose
+    // activity was compacted
+    fn rebuild_rollups(&self) -> Result<()>;
+
+    // Days of hourly activity a user keeps, None for the default of the server
+    fn get_retention(&self, user_id: i64) -> Result<Option<u32>>;
+    fn set_retention(&self, user_id: i64, days: Option<u32>) -> Result<()>;
+    // Retention of every user by id
+    fn get_retentions(&self) -> Result<Vec<(i64, Option<u32>)>>;
+    // Hours of activity of a user before the start of the day of `before`
+    fn count_activity_before(&self, user_id: i64, before: DateTime<Utc>) -> Result<usize>;
+    // Removes the hours of activity of a user before the start of the day of `before` in a
+    // single transaction, returning how many. Compacting keeps their time in the rollups,
+    // deleting takes it off them.
+    fn prune_activity(&self, user_id: i64, before: DateTime<Utc>, action: RetentionAction) -> Result<usize>;
+
+    fn create_ruleset(&self, user_id: i64, name: &str, rules: Vec<Rule>) -> Result<i64>;
+    fn get_ruleset(&self, ruleset_id: i64) -> Result<Ruleset>;
+    fn get_rulesets(&self, user_id: i64) -> Result<Vec<Ruleset>>;
+    // Rebuilds the rollups of the owner of the ruleset, so reported time moves to the
+    // categories of the new rules
+    fn update_ruleset(&self, ruleset_id: i64, name: &str, rules: Vec<Rule>) -> Result<()>;
+    // Rulesets that activity was reported with can't be deleted
+    fn delete_ruleset(&self, ruleset_id: i64) -> Result<()>;
+
+    // Reports many hours for a device in a single transaction.
+    // Hours that are rejected get an error in their slot without affecting the others,
+    // while a database error rolls back the whole batch.
+    fn report_activity_batch(&self, device_id: &Uuid, ruleset_id: i64, reports: Vec<(DateTime<Utc>, Vec<Event>)>, mode: ReportMode) -> Result<Vec<Result<ReportOutcome>>>;
+
+    // Returns the stored key together with its secret, which is not retrievable later
+    fn create_api_key(&self, user_id: i64, device_id: Option<&Uuid>, name: &str) -> Result<(ApiKey, String)>;
+    fn get_api_keys(&self, user_id: i64) -> Result<Vec<ApiKey>>;
+    // Looks up the key matching a secret and marks it as used
+    fn use_api_key(&self, secret: &str) -> Result<ApiKey>;
+    fn revoke_api_key(&self, user_id: i64, key_id: i64) -> Result<()>;
+
+    fn create_session(&self, user_id: i64, expires: DateTime<Utc>, user_agent: Option<&str>, ip: Option<&str>) -> Result<Session>;
+    // Looks up an unexpired session and marks it as used
+    fn use_session(&self, session_id: &Uuid) -> Result<Session>;
+    // Unexpired sessions of a user, most recently used first
+    fn get_sessions(&self, user_id: i64) -> Result<Vec<Session>>;
+    fn revoke_session(&self, user_id: i64, session_id: &Uuid) -> Result<()>;
+    // Revokes all sessions of a user except the given one, returning how many were revoked
+    fn revoke_other_sessions(&self, user_id: i64, keep: Option<&Uuid>) -> Result<usize>;
+
+    // User an account of an OAuth provider is linked to
+    fn get_oauth_user(&self, provider: &str, subject: &str) -> Result<User>;
+    // Fails with a conflict if the account is already linked
+    fn link_oauth_identity(&self, user_id: i64, provider: &str, subject: &str) -> Result<()>;
+    // Provider accounts linked to a user, oldest first
+    fn get_oauth_identities(&self, user_id: i64) -> Result<Vec<OauthIdentity>>;
+
+    fn get_totp(&self, user_id: i64) -> Result<Option<Totp>>;
+    // Starts enrollment with a new secret, replacing a pending one.
+    // Fails with a conflict if two-factor authentication is already enabled.
+    fn set_totp_secret(&self, user_id: i64, secret: &str) -> Result<()>;
+    // Enables the pending secret once a code of the given step was confirmed, returning
+    // the recovery codes. Like API key secrets they are only stored hashed.
+    fn enable_totp(&self, user_id: i64, step: i64) -> Result<Vec<String>>;
+    // Records the use of a code, returning false for steps at or before the last used
+    // one so a code can't be used twice
+    fn use_totp_step(&self, user_id: i64, step: i64) -> Result<bool>;
+    // Uses up a recovery code, returning whether it was one
+    fn use_recovery_code(&self, user_id: i64, code: &str) -> Result<bool>;
+    // Replaces the recovery codes of a user with new ones
+    fn reset_recovery_codes(&self, user_id: i64) -> Result<Vec<String>>;
+    fn disable_totp(&self, user_id: i64) -> Result<()>;
+
+    // What others may see of a user
+    fn get_privacy(&self, user_id: i64) -> Result<Privacy>;
+    // Replaces the privacy settings of a user, category settings included
+    fn set_privacy(&self, user_id: i64, privacy: &Privacy) -> Result<()>;
+    // Privacy settings of the users on the leaderboards, which are verified users with
+    // a public profile, by username
+    fn get_listed_privacy(&self) -> Result<HashMap<String, Privacy>>;
+
+    fn check_password(&self, username: &str, password: &str) -> Result<bool> {
+        match self.get_user(username) {
+            // A stored hash bcrypt can't read matches no password
+            Ok(user) => Ok(verify(password, &user.password).unwrap_or_else(|err| {
+                log::error!("Unreadable password hash of user `{}`: {}", username, err);
+                false
+            })),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn report_activity(&self, device_id: &Uuid, ruleset_id: i64, hour: DateTime<Utc>, events: Vec<Event>, mode: ReportMode) -> Result<ReportOutcome> {
+        let mut outcomes = self.report_activity_batch(device_id, ruleset_id, vec![(hour, events)], mode)?;
+        outcomes.pop().unwrap()
+    }
+
+    // Add more functions to handle claims and other stuff...
+}
+
+// The rest are helpers shared by the backends
+
+// Tables with rows of a user besides activity, which belongs to their devices. Rows are
+// deleted in this order, before the rows they reference.
+const USER_TABLES: &[&str] = &[
+    "api_key", "activity_daily", "device", "ruleset", "session", "oauth_identity", "totp", "recovery_code",
+    "category_sharing",
+];
+
+// Condition on rows of `activity` for those counted in the rollups. An hour of a device
+// reported with several rulesets only counts once, with the ruleset it was last added with.
+const COUNTED_ACTIVITY: &str = "NOT EXISTS (
+    SELECT 1 FROM activity AS newer
+    WHERE newer.device_id = activity.device_id AND newer.timestamp = activity.timestamp AND newer.id > activity.id
+)";
+
+fn timestamp_to_datetime(ts: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(ts, 0).unwrap()
+}
+
+fn generate_api_key_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", API_KEY_PREFIX, hex::encode(bytes))
+}
+
+fn hash_api_key(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+const RECOVERY_CODES: usize = 10;
+
+// Codes like `3f9a-c07b-81d2-5e60`
+fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0u8; 8];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = hex::encode(bytes);
+            format!("{}-{}-{}-{}", &code[..4], &code[4..8], &code[8..12], &code[12..])
+        })
+        .collect()
+}
+
+// Recovery codes are hashed without the dashes and in lowercase, however they are typed
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars().filter(char::is_ascii_alphanumeric).collect();
+    hex::encode(Sha256::digest(normalized.to_ascii_lowercase().as_bytes()))
+}
+
+fn check_report_hour(hour: DateTime<Utc>, compacted_until: Option<DateTime<Utc>>) -> Result<()> {
+    // Check that hour is exactly on the hour
+    if hour.minute() != 0 || hour.second() != 0 {
+        return Err(DatastoreError::BadRequest("Hour must be on the hour".to_string()));
+    }
+    // Only the rollups are left of compacted hours, reporting one again would count it twice
+    if let Some(until) = compacted_until.filter(|until| hour < *until) {
+        return Err(DatastoreError::BadRequest(format!(
+            "Activity before {} is past its retention and was compacted",
+            until
+        )));
+    }
+    Ok(())
+}
+
+// Events an hour ends up with when a report arrives for it, or None if the stored ones stay as they are
+fn reported_events(stored: &[Event], events: Vec<Event>, mode: ReportMode) -> Option<Vec<Event>> {
+    let events = match mode {
+        ReportMode::Replace => events,
+        ReportMode::Merge => {
+            let mut merged = stored.to_vec();
+            for event in events {
+                if !merged.contains(&event) {
+                    merged.push(event);
+                }
+            }
+            merged.sort_by_key(|e| e.timestamp);
+            merged
+        }
+    };
+    if events == stored {
+        None
+    } else {
+        Some(events)
+    }
+}
+
+// Difference between the old and new events of an hour per category, to apply to the daily rollups.
+// Categories are stored as full paths from the ruleset, so parents can be summed by prefix.
+fn rollup_deltas(rules: &[Rule], old: &[Event], new: &[Event]) -> HashMap<String, i64> {
+    let mut deltas: HashMap<String, i64> = HashMap::new();
+    for event in old {
+        *deltas.entry(resolve_category(rules, &event.category)).or_default() -= event.duration.as_secs() as i64;
+    }
+    for event in new {
+        *deltas.entry(resolve_category(rules, &event.category)).or_default() += event.duration.as_secs() as i64;
+    }
+    deltas.retain(|_, delta| *delta != 0);
+    deltas
+}
+
+// An hour of activity as (user, device, hour, events, ruleset)
+type RollupActivity = (i64, Uuid, DateTime<Utc>, Vec<Event>, i64);
+
+// Totals per user, device, day and category of hours of activity
+fn rollup_totals(
+    activity: Vec<RollupActivity>,
+    mut get_rules: impl FnMut(i64) -> Result<Vec<Rule>>,
+) -> Result<HashMap<(i64, Uuid, i64, String), u64>> {
+    let mut totals: HashMap<(i64, Uuid, i64, String), u64> = HashMap::new();
+    let mut rulesets: HashMap<i64, Vec<Rule>> = HashMap::new();
+    for (user_id, device_id, hour, events, ruleset_id) in activity {
+        let day = start_of_day(hour).timestamp();
+        if let Entry::Vacant(entry) = rulesets.entry(ruleset_id) {
+            entry.insert(get_rules(ruleset_id)?);
+        }
+        for event in events {
+            let category = resolve_category(&rulesets[&ruleset_id], &event.category);
+            *totals.entry((user_id, device_id, day, category)).or_default() += event.duration.as_secs();
+        }
+    }
+    Ok(totals)
+}
@@ -0,0 +1,1023 @@
+use std::collections::HashMap;
+
+use bcrypt::{hash, DEFAULT_COST};
+use postgres::{fallible_iterator::FallibleIterator, Client, GenericClient, NoTls, Row};
+use r2d2_postgres::PostgresConnectionManager;
+use chrono::{prelude::*};
+use uuid::Uuid;
+
+use super::{
+    check_report_hour, generate_api_key_secret, generate_recovery_codes, hash_api_key, hash_recovery_code,
+    reported_events, rollup_deltas, rollup_totals, start_of_day, timestamp_to_datetime, Activity, ApiKey, DailyRollup,
+    Datastore, Device, Event, ReportMode, ReportOutcome, Result, Rule, Ruleset, OauthIdentity, Session, Totp, User, COUNTED_ACTIVITY, USER_TABLES,
+};
+use crate::error::DatastoreError;
+use crate::migrations::postgres as migrations;
+use crate::privacy::{CategorySharing, Privacy, Visibility};
+use crate::retention::RetentionAction;
+
+type Pool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
+
+pub struct PostgresDb {
+    // Only taken when dropping, see Drop below
+    pool: Option<Pool>,
+}
+
+fn user_from_row(row: &Row) -> User {
+    User {
+        id: row.get(0),
+        username: row.get(1),
+        email: row.get(2),
+        password: row.get(3),
+        created_at: row.get::<_, Option<i64>>(4).map(timestamp_to_datetime),
+        email_verified: row.get(5),
+        deletion_due: row.get::<_, Option<i64>>(6).map(timestamp_to_datetime),
+    }
+}
+
+fn device_from_row(row: &Row) -> Device {
+    Device {
+        id: row.get(0),
+        user_id: row.get(1),
+        name: row.get(2),
+        last_seen: row.get::<_, Option<i64>>(3).map(timestamp_to_datetime),
+    }
+}
+
+fn ruleset_from_row(row: &Row) -> Ruleset {
+    Ruleset {
+        id: row.get(0),
+        user_id: row.get(1),
+        name: row.get(2),
+        rules: serde_json::from_str(row.get(3)).unwrap(),
+    }
+}
+
+fn api_key_from_row(row: &Row) -> ApiKey {
+    ApiKey {
+        id: row.get(0),
+        user_id: row.get(1),
+        device_id: row.get(2),
+        name: row.get(3),
+        created: timestamp_to_datetime(row.get(4)),
+        last_used: row.get::<_, Option<i64>>(5).map(timestamp_to_datetime),
+    }
+}
+
+fn session_from_row(row: &Row) -> Session {
+    Session {
+        id: row.get(0),
+        user_id: row.get(1),
+        created: timestamp_to_datetime(row.get(2)),
+        last_used: timestamp_to_datetime(row.get(3)),
+        expires: timestamp_to_datetime(row.get(4)),
+        user_agent: row.get(5),
+        ip: row.get(6),
+    }
+}
+
+fn totals_from_rows(rows: Vec<Row>) -> Vec<(String, u64)> {
+    rows.iter()
+        .map(|row| (row.get(0), row.get::<_, i64>(1).max(0) as u64))
+        .collect()
+}
+
+impl PostgresDb {
+    pub fn new(config: postgres::Config) -> Result<PostgresDb> {
+        let manager = PostgresConnectionManager::new(config, NoTls);
+        // Connections are opened as needed rather than filling the pool upfront
+        let pool = r2d2::Pool::builder().min_idle(Some(1)).build(manager)?;
+        let db = PostgresDb { pool: Some(pool) };
+        db.init()?;
+        Ok(db)
+    }
+
+    // The postgres client blocks on a tokio runtime of its own, which panics when done
+    // from one of Rocket's async workers. block_in_place hands the worker's other tasks
+    // to another thread and leaves the runtime while the query runs, and outside of a
+    // runtime it just runs the query.
+    fn with_conn<T>(&self, f: impl FnOnce(&mut Client) -> Result<T>) -> Result<T> {
+        let pool = self.pool.as_ref().unwrap();
+        rocket::tokio::task::block_in_place(|| f(&mut *pool.get()?))
+    }
+
+    fn init(&self) -> Result<()> {
+        self.with_conn(migrations::migrate)?;
+        // Backfill rollups for databases created before they existed, or before they were
+        // kept per device
+        let (rollups, activities): (i64, i64) = self.with_conn(|conn| {
+            let row = conn.query_one(
+                "SELECT (SELECT COUNT(*) FROM activity_daily WHERE device_id IS NOT NULL), (SELECT COUNT(*) FROM activity)",
+                &[],
+            )?;
+            Ok((row.get(0), row.get(1)))
+        })?;
+        if rollups == 0 && activities > 0 {
+            self.rebuild_rollups()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PostgresDb {
+    // Closing the connections blocks on their runtimes too
+    fn drop(&mut self) {
+        let pool = self.pool.take();
+        std::thread::spawn(move || drop(pool)).join().ok();
+    }
+}
+
+impl Datastore for PostgresDb {
+    fn add_user(&self, username: &str, email: &str, password: &str) -> Result<()> {
+        let hashed_password = hash(password, DEFAULT_COST).unwrap();
+
+        self.with_conn(|conn| {
+            let inserted = conn.execute(
+                "INSERT INTO \"user\" (username, email, password, created_at) VALUES ($1, $2, $3, $4)",
+                &[&username, &email, &hashed_password, &Utc::now().timestamp()],
+            );
+            match inserted {
+                Ok(_) => Ok(()),
+                // The unique constraint that was violated tells which column is taken
+                Err(err) => match err.as_db_error().and_then(|err| err.constraint()) {
+                    Some("user_username_key") => Err(DatastoreError::UserAlreadyExists { username: username.to_string() }),
+                    Some("user_email_key") => Err(DatastoreError::EmailAlreadyExists { email: email.to_string() }),
+                    _ => Err(err.into()),
+                },
+            }
+        })
+    }
+
+    fn get_user(&self, username: &str) -> Result<User> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "SELECT id, username, email, password, created_at, email_verified, deletion_due FROM \"user\" WHERE username = $1",
+                &[&username],
+            )?)
+        })?;
+        match row {
+            Some(row) => Ok(user_from_row(&row)),
+            None => Err(DatastoreError::NotFound(format!("user `{}`", username))),
+        }
+    }
+
+    fn get_user_by_id(&self, user_id: i64) -> Result<User> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "SELECT id, username, email, password, created_at, email_verified, deletion_due FROM \"user\" WHERE id = $1",
+                &[&user_id],
+            )?)
+        })?;
+        match row {
+            Some(row) => Ok(user_from_row(&row)),
+            None => Err(DatastoreError::NotFound(format!("user `{}`", user_id))),
+        }
+    }
+
+    fn get_user_by_email(&self, email: &str) -> Result<User> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "SELECT id, username, email, password, created_at, email_verified, deletion_due FROM \"user\" WHERE email = $1",
+                &[&email],
+            )?)
+        })?;
+        match row {
+            Some(row) => Ok(user_from_row(&row)),
+            None => Err(DatastoreError::NotFound(format!("user with email `{}`", email))),
+        }
+    }
+
+    fn set_email_verified(&self, user_id: i64) -> Result<()> {
+        let updated = self.with_conn(|conn| {
+            Ok(conn.execute("UPDATE \"user\" SET email_verified = TRUE WHERE id = $1", &[&user_id])?)
+        })?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        Ok(())
+    }
+
+    fn set_password(&self, user_id: i64, password: &str) -> Result<()> {
+        let hashed_password = hash(password, DEFAULT_COST).unwrap();
+        let updated = self.with_conn(|conn| {
+            Ok(conn.execute("UPDATE \"user\" SET password = $1 WHERE id = $2", &[&hashed_password, &user_id])?)
+        })?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        Ok(())
+    }
+
+    fn delete_user(&self, user_id: i64) -> Result<()> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM activity WHERE device_id IN (SELECT id FROM device WHERE user_id = $1)",
+                &[&user_id],
+            )?;
+            for table in USER_TABLES {
+                tx.execute(&format!("DELETE FROM {} WHERE user_id = $1", table), &[&user_id])?;
+            }
+            let deleted = tx.execute("DELETE FROM \"user\" WHERE id = $1", &[&user_id])?;
+            if deleted == 0 {
+                return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn schedule_user_deletion(&self, user_id: i64, due: Option<DateTime<Utc>>) -> Result<()> {
+        let due = due.map(|due| due.timestamp());
+        let updated = self.with_conn(|conn| {
+            Ok(conn.execute("UPDATE \"user\" SET deletion_due = $1 WHERE id = $2", &[&due, &user_id])?)
+        })?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        Ok(())
+    }
+
+    fn get_users_due_for_deletion(&self, now: DateTime<Utc>) -> Result<Vec<i64>> {
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query("SELECT id FROM \"user\" WHERE deletion_due <= $1", &[&now.timestamp()])?)
+        })?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn add_device(&self, user_id: i64, device_id: Uuid, name: &str) -> Result<()> {
+        self.with_conn(|conn| {
+            let inserted = conn.execute(
+                "INSERT INTO device (id, user_id, name) VALUES ($1, $2, $3)",
+                &[&device_id, &user_id, &name],
+            );
+            match inserted {
+                Ok(_) => Ok(()),
+                Err(err) if err.as_db_error().and_then(|err| err.constraint()) == Some("device_pkey") => {
+                    Err(DatastoreError::Conflict(format!("device `{}` already exists", device_id)))
+                }
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn get_devices(&self, user_id: i64) -> Result<Vec<Device>> {
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query(
+                "SELECT device.id, device.user_id, device.name, MAX(activity.timestamp)
+                 FROM device LEFT JOIN activity ON activity.device_id = device.id
+                 WHERE device.user_id = $1
+                 GROUP BY device.id",
+                &[&user_id],
+            )?)
+        })?;
+        Ok(rows.iter().map(device_from_row).collect())
+    }
+
+    fn get_device(&self, device_id: &Uuid) -> Result<Device> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "SELECT device.id, device.user_id, device.name, MAX(activity.timestamp)
+                 FROM device LEFT JOIN activity ON activity.device_id = device.id
+                 WHERE device.id = $1
+                 GROUP BY device.id",
+                &[device_id],
+            )?)
+        })?;
+        match row {
+            Some(row) => Ok(device_from_row(&row)),
+            None => Err(DatastoreError::NotFound(format!("device `{}`", device_id))),
+        }
+    }
+
+    fn delete_device(&self, device_id: &Uuid) -> Result<()> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            tx.execute("DELETE FROM activity_daily WHERE device_id = $1", &[device_id])?;
+            tx.execute("DELETE FROM activity WHERE device_id = $1", &[device_id])?;
+            tx.execute("DELETE FROM api_key WHERE device_id = $1", &[device_id])?;
+            let deleted = tx.execute("DELETE FROM device WHERE id = $1", &[device_id])?;
+            if deleted == 0 {
+                return Err(DatastoreError::NotFound(format!("device `{}`", device_id)));
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn for_each_activity(&self, device_id: &Uuid, f: &mut (dyn FnMut(Activity) -> Result<()> + Send)) -> Result<()> {
+        self.with_conn(|conn| {
+            let mut rows = conn.query_raw(
+                "SELECT id, timestamp, events, ruleset_id FROM activity WHERE device_id = $1 ORDER BY timestamp",
+                [device_id],
+            )?;
+            while let Some(row) = rows.next()? {
+                f(Activity {
+                    id: row.get(0),
+                    timestamp: timestamp_to_datetime(row.get(1)),
+                    device_id: *device_id,
+                    events: serde_json::from_str(row.get(2)).unwrap(),
+                    ruleset_id: row.get(3),
+                })?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_daily_totals(&self, since: Option<DateTime<Utc>>) -> Result<Vec<(String, String, u64)>> {
+        let since = since.map(|t| t.timestamp()).unwrap_or(i64::MIN);
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query(
+                "SELECT \"user\".username, activity_daily.category, SUM(activity_daily.duration)::BIGINT FROM activity_daily
+                 JOIN \"user\" ON activity_daily.user_id = \"user\".id
+                 WHERE activity_daily.day >= $1 AND \"user\".email_verified
+                 GROUP BY \"user\".id, activity_daily.category",
+                &[&since],
+            )?)
+        })?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get::<_, i64>(2).max(0) as u64))
+            .collect())
+    }
+
+    fn get_category_totals(&self, user_id: i64, since: Option<DateTime<Utc>>) -> Result<Vec<(String, u64)>> {
+        let since = since.map(|t| t.timestamp()).unwrap_or(i64::MIN);
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query(
+                "SELECT category, SUM(duration)::BIGINT FROM activity_daily
+                 WHERE user_id = $1 AND day >= $2
+                 GROUP BY category",
+                &[&user_id, &since],
+            )?)
+        })?;
+        Ok(totals_from_rows(rows))
+    }
+
+    fn get_daily_rollups(&self, user_id: i64) -> Result<Vec<DailyRollup>> {
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query(
+                "SELECT day, device_id, category, duration FROM activity_daily
+                 WHERE user_id = $1
+                 ORDER BY day, device_id, category",
+                &[&user_id],
+            )?)
+        })?;
+        Ok(rows
+            .iter()
+            .map(|row| DailyRollup {
+                day: timestamp_to_datetime(row.get(0)),
+                device_id: row.get(1),
+                category: row.get(2),
+                duration: row.get::<_, i64>(3).max(0) as u64,
+            })
+            .collect())
+    }
+
+    fn rebuild_rollups(&self) -> Result<()> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            rebuild_rollups_tx(&mut tx, None)?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn get_retention(&self, user_id: i64) -> Result<Option<u32>> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt("SELECT retention_days FROM \"user\" WHERE id = $1", &[&user_id])?)
+        })?;
+        match row {
+            Some(row) => Ok(row.get::<_, Option<i64>>(0).map(|days| days as u32)),
+            None => Err(DatastoreError::NotFound(format!("user `{}`", user_id))),
+        }
+    }
+
+    fn set_retention(&self, user_id: i64, days: Option<u32>) -> Result<()> {
+        let days = days.map(i64::from);
+        let updated = self.with_conn(|conn| {
+            Ok(conn.execute("UPDATE \"user\" SET retention_days = $1 WHERE id = $2", &[&days, &user_id])?)
+        })?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        Ok(())
+    }
+
+    fn get_retentions(&self) -> Result<Vec<(i64, Option<u32>)>> {
+        let rows = self.with_conn(|conn| Ok(conn.query("SELECT id, retention_days FROM \"user\" ORDER BY id", &[])?))?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get(0), row.get::<_, Option<i64>>(1).map(|days| days as u32)))
+            .collect())
+    }
+
+    fn count_activity_before(&self, user_id: i64, before: DateTime<Utc>) -> Result<usize> {
+        let before = start_of_day(before).timestamp();
+        let count: i64 = self.with_conn(|conn| {
+            Ok(conn
+                .query_one(
+                    "SELECT COUNT(*) FROM activity JOIN device ON activity.device_id = device.id
+                     WHERE device.user_id = $1 AND activity.timestamp < $2",
+                    &[&user_id, &before],
+                )?
+                .get(0))
+        })?;
+        Ok(count as usize)
+    }
+
+    fn prune_activity(&self, user_id: i64, before: DateTime<Utc>, action: RetentionAction) -> Result<usize> {
+        let before = start_of_day(before).timestamp();
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            match action {
+                RetentionAction::Delete => {
+                    let activity = tx.query(
+                        &format!(
+                            "SELECT activity.device_id, activity.ruleset_id, activity.timestamp, activity.events FROM activity
+                             JOIN device ON activity.device_id = device.id
+                             WHERE device.user_id = $1 AND activity.timestamp < $2 AND {}",
+                            COUNTED_ACTIVITY
+                        ),
+                        &[&user_id, &before],
+                    )?;
+                    for row in activity {
+                        let events: Vec<Event> = serde_json::from_str(row.get(3)).unwrap();
+                        update_rollups(&mut tx, &row.get::<_, Uuid>(0), row.get(1), timestamp_to_datetime(row.get(2)), &events, &[])?;
+                    }
+                    // Days compacted before have no activity left to take off, only their rollups
+                    tx.execute(
+                        "DELETE FROM activity_daily WHERE user_id = $1 AND day < $2
+                         AND day < (SELECT compacted_until FROM \"user\" WHERE id = $1)",
+                        &[&user_id, &before],
+                    )?;
+                }
+                // The rollups of these days are all that will be left of them
+                RetentionAction::Compact => {
+                    tx.execute(
+                        "UPDATE \"user\" SET compacted_until = GREATEST(compacted_until, $2) WHERE id = $1",
+                        &[&user_id, &before],
+                    )?;
+                }
+            }
+            let pruned = tx.execute(
+                "DELETE FROM activity WHERE timestamp < $2 AND device_id IN (SELECT id FROM device WHERE user_id = $1)",
+                &[&user_id, &before],
+            )?;
+            tx.commit()?;
+            Ok(pruned as usize)
+        })
+    }
+
+    fn create_ruleset(&self, user_id: i64, name: &str, rules: Vec<Rule>) -> Result<i64> {
+        let rules_json = serde_json::to_string(&rules).unwrap();
+        self.with_conn(|conn| {
+            let row = conn.query_one(
+                "INSERT INTO ruleset (user_id, name, rules) VALUES ($1, $2, $3) RETURNING id",
+                &[&user_id, &name, &rules_json],
+            )?;
+            Ok(row.get(0))
+        })
+    }
+
+    fn get_ruleset(&self, ruleset_id: i64) -> Result<Ruleset> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt("SELECT id, user_id, name, rules FROM ruleset WHERE id = $1", &[&ruleset_id])?)
+        })?;
+        match row {
+            Some(row) => Ok(ruleset_from_row(&row)),
+            None => Err(DatastoreError::NotFound(format!("ruleset `{}`", ruleset_id))),
+        }
+    }
+
+    fn get_rulesets(&self, user_id: i64) -> Result<Vec<Ruleset>> {
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query("SELECT id, user_id, name, rules FROM ruleset WHERE user_id = $1 ORDER BY id", &[&user_id])?)
+        })?;
+        Ok(rows.iter().map(ruleset_from_row).collect())
+    }
+
+    fn update_ruleset(&self, ruleset_id: i64, name: &str, rules: Vec<Rule>) -> Result<()> {
+        let rules_json = serde_json::to_string(&rules).unwrap();
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            let row = tx.query_opt(
+                "UPDATE ruleset SET name = $1, rules = $2 WHERE id = $3 RETURNING user_id",
+                &[&name, &rules_json, &ruleset_id],
+            )?;
+            let Some(row) = row else {
+                return Err(DatastoreError::NotFound(format!("ruleset `{}`", ruleset_id)));
+            };
+            // The rollups hold the categories of the old rules
+            rebuild_rollups_tx(&mut tx, Some(row.get(0)))?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn delete_ruleset(&self, ruleset_id: i64) -> Result<()> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            let references: i64 = tx
+                .query_one("SELECT COUNT(*) FROM activity WHERE ruleset_id = $1", &[&ruleset_id])?
+                .get(0);
+            if references > 0 {
+                return Err(DatastoreError::Conflict(format!(
+                    "ruleset `{}` is used by {} hours of activity",
+                    ruleset_id, references
+                )));
+            }
+            let deleted = tx.execute("DELETE FROM ruleset WHERE id = $1", &[&ruleset_id])?;
+            if deleted == 0 {
+                return Err(DatastoreError::NotFound(format!("ruleset `{}`", ruleset_id)));
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn report_activity_batch(&self, device_id: &Uuid, ruleset_id: i64, reports: Vec<(DateTime<Utc>, Vec<Event>)>, mode: ReportMode) -> Result<Vec<Result<ReportOutcome>>> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            let mut outcomes = Vec::with_capacity(reports.len());
+            for (hour, events) in reports {
+                match report_activity_tx(&mut tx, device_id, ruleset_id, hour, events, mode) {
+                    Err(DatastoreError::BadRequest(msg)) => outcomes.push(Err(DatastoreError::BadRequest(msg))),
+                    result => outcomes.push(Ok(result?)),
+                }
+            }
+            tx.commit()?;
+            Ok(outcomes)
+        })
+    }
+
+    fn create_api_key(&self, user_id: i64, device_id: Option<&Uuid>, name: &str) -> Result<(ApiKey, String)> {
+        let secret = generate_api_key_secret();
+        let created = Utc::now().with_nanosecond(0).unwrap();
+
+        let id: i64 = self.with_conn(|conn| {
+            let row = conn.query_one(
+                "INSERT INTO api_key (user_id, device_id, name, key_hash, created) VALUES ($1, $2, $3, $4, $5)
+                 RETURNING id",
+                &[&user_id, &device_id, &name, &hash_api_key(&secret), &created.timestamp()],
+            )?;
+            Ok(row.get(0))
+        })?;
+        let key = ApiKey {
+            id,
+            user_id,
+            device_id: device_id.copied(),
+            name: name.to_string(),
+            created,
+            last_used: None,
+        };
+        Ok((key, secret))
+    }
+
+    fn get_api_keys(&self, user_id: i64) -> Result<Vec<ApiKey>> {
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query(
+                "SELECT id, user_id, device_id, name, created, last_used FROM api_key WHERE user_id = $1 ORDER BY id",
+                &[&user_id],
+            )?)
+        })?;
+        Ok(rows.iter().map(api_key_from_row).collect())
+    }
+
+    fn use_api_key(&self, secret: &str) -> Result<ApiKey> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "SELECT id, user_id, device_id, name, created, last_used FROM api_key WHERE key_hash = $1",
+                &[&hash_api_key(secret)],
+            )?)
+        })?;
+        let key = match row {
+            Some(row) => api_key_from_row(&row),
+            None => return Err(DatastoreError::Unauthorized("invalid API key".to_string())),
+        };
+        self.with_conn(|conn| {
+            conn.execute("UPDATE api_key SET last_used = $1 WHERE id = $2", &[&Utc::now().timestamp(), &key.id])?;
+            Ok(())
+        })?;
+        Ok(key)
+    }
+
+    fn revoke_api_key(&self, user_id: i64, key_id: i64) -> Result<()> {
+        let deleted = self.with_conn(|conn| {
+            Ok(conn.execute("DELETE FROM api_key WHERE id = $1 AND user_id = $2", &[&key_id, &user_id])?)
+        })?;
+        if deleted == 0 {
+            return Err(DatastoreError::NotFound(format!("api key `{}`", key_id)));
+        }
+        Ok(())
+    }
+
+    fn create_session(&self, user_id: i64, expires: DateTime<Utc>, user_agent: Option<&str>, ip: Option<&str>) -> Result<Session> {
+        let now = Utc::now().with_nanosecond(0).unwrap();
+        let session = Session {
+            id: Uuid::new_v4(),
+            user_id,
+            created: now,
+            last_used: now,
+            expires: expires.with_nanosecond(0).unwrap(),
+            user_agent: user_agent.map(String::from),
+            ip: ip.map(String::from),
+        };
+        self.with_conn(|conn| {
+            // Expired sessions of the user are cleaned up on every login
+            conn.execute(
+                "DELETE FROM session WHERE user_id = $1 AND expires <= $2",
+                &[&user_id, &now.timestamp()],
+            )?;
+            conn.execute(
+                "INSERT INTO session (id, user_id, created, last_used, expires, user_agent, ip)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    &session.id,
+                    &user_id,
+                    &session.created.timestamp(),
+                    &session.last_used.timestamp(),
+                    &session.expires.timestamp(),
+                    &session.user_agent,
+                    &session.ip,
+                ],
+            )?;
+            Ok(())
+        })?;
+        Ok(session)
+    }
+
+    fn use_session(&self, session_id: &Uuid) -> Result<Session> {
+        let now = Utc::now().timestamp();
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "SELECT id, user_id, created, last_used, expires, user_agent, ip FROM session
+                 WHERE id = $1 AND expires > $2",
+                &[session_id, &now],
+            )?)
+        })?;
+        let session = match row {
+            Some(row) => session_from_row(&row),
+            None => return Err(DatastoreError::Unauthorized("session has ended".to_string())),
+        };
+        self.with_conn(|conn| {
+            conn.execute("UPDATE session SET last_used = $1 WHERE id = $2", &[&now, session_id])?;
+            Ok(())
+        })?;
+        Ok(session)
+    }
+
+    fn get_sessions(&self, user_id: i64) -> Result<Vec<Session>> {
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query(
+                "SELECT id, user_id, created, last_used, expires, user_agent, ip FROM session
+                 WHERE user_id = $1 AND expires > $2
+                 ORDER BY last_used DESC",
+                &[&user_id, &Utc::now().timestamp()],
+            )?)
+        })?;
+        Ok(rows.iter().map(session_from_row).collect())
+    }
+
+    fn revoke_session(&self, user_id: i64, session_id: &Uuid) -> Result<()> {
+        let deleted = self.with_conn(|conn| {
+            Ok(conn.execute("DELETE FROM session WHERE id = $1 AND user_id = $2", &[session_id, &user_id])?)
+        })?;
+        if deleted == 0 {
+            return Err(DatastoreError::NotFound(format!("session `{}`", session_id)));
+        }
+        Ok(())
+    }
+
+    fn revoke_other_sessions(&self, user_id: i64, keep: Option<&Uuid>) -> Result<usize> {
+        let deleted = self.with_conn(|conn| {
+            Ok(conn.execute(
+                "DELETE FROM session WHERE user_id = $1 AND ($2::UUID IS NULL OR id != $2)",
+                &[&user_id, &keep],
+            )?)
+        })?;
+        Ok(deleted as usize)
+    }
+
+    fn get_oauth_user(&self, provider: &str, subject: &str) -> Result<User> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "SELECT u.id, username, email, password, created_at, email_verified, deletion_due FROM oauth_identity
+                 JOIN \"user\" u ON u.id = oauth_identity.user_id
+                 WHERE provider = $1 AND subject = $2",
+                &[&provider, &subject],
+            )?)
+        })?;
+        match row {
+            Some(row) => Ok(user_from_row(&row)),
+            None => Err(DatastoreError::NotFound(format!("{} account `{}`", provider, subject))),
+        }
+    }
+
+    fn link_oauth_identity(&self, user_id: i64, provider: &str, subject: &str) -> Result<()> {
+        let inserted = self.with_conn(|conn| {
+            Ok(conn.execute(
+                "INSERT INTO oauth_identity (provider, subject, user_id, created) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT DO NOTHING",
+                &[&provider, &subject, &user_id, &Utc::now().timestamp()],
+            )?)
+        })?;
+        if inserted == 0 {
+            return Err(DatastoreError::Conflict(format!("{} account is already linked", provider)));
+        }
+        Ok(())
+    }
+
+    fn get_oauth_identities(&self, user_id: i64) -> Result<Vec<OauthIdentity>> {
+        let rows = self.with_conn(|conn| {
+            Ok(conn.query(
+                "SELECT provider, subject, created FROM oauth_identity WHERE user_id = $1 ORDER BY created",
+                &[&user_id],
+            )?)
+        })?;
+        Ok(rows
+            .iter()
+            .map(|row| OauthIdentity {
+                provider: row.get(0),
+                subject: row.get(1),
+                created: timestamp_to_datetime(row.get(2)),
+            })
+            .collect())
+    }
+
+    fn get_totp(&self, user_id: i64) -> Result<Option<Totp>> {
+        let row = self.with_conn(|conn| {
+            Ok(conn.query_opt(
+                "SELECT secret, enabled, (SELECT COUNT(*) FROM recovery_code WHERE user_id = $1)
+                 FROM totp WHERE user_id = $1",
+                &[&user_id],
+            )?)
+        })?;
+        Ok(row.map(|row| Totp { secret: row.get(0), enabled: row.get(1), recovery_codes: row.get(2) }))
+    }
+
+    fn set_totp_secret(&self, user_id: i64, secret: &str) -> Result<()> {
+        let updated = self.with_conn(|conn| {
+            Ok(conn.execute(
+                "INSERT INTO totp (user_id, secret, enabled) VALUES ($1, $2, FALSE)
+                 ON CONFLICT (user_id) DO UPDATE SET secret = excluded.secret WHERE NOT totp.enabled",
+                &[&user_id, &secret],
+            )?)
+        })?;
+        if updated == 0 {
+            return Err(DatastoreError::Conflict("two-factor authentication is already enabled".to_string()));
+        }
+        Ok(())
+    }
+
+    fn enable_totp(&self, user_id: i64, step: i64) -> Result<Vec<String>> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            let updated = tx.execute(
+                "UPDATE totp SET enabled = TRUE, last_step = $1 WHERE user_id = $2 AND NOT enabled",
+                &[&step, &user_id],
+            )?;
+            if updated == 0 {
+                return Err(DatastoreError::NotFound("pending two-factor setup".to_string()));
+            }
+            let codes = replace_recovery_codes_tx(&mut tx, user_id)?;
+            tx.commit()?;
+            Ok(codes)
+        })
+    }
+
+    fn use_totp_step(&self, user_id: i64, step: i64) -> Result<bool> {
+        let updated = self.with_conn(|conn| {
+            Ok(conn.execute(
+                "UPDATE totp SET last_step = $1
+                 WHERE user_id = $2 AND enabled AND (last_step IS NULL OR last_step < $1)",
+                &[&step, &user_id],
+            )?)
+        })?;
+        Ok(updated > 0)
+    }
+
+    fn use_recovery_code(&self, user_id: i64, code: &str) -> Result<bool> {
+        let deleted = self.with_conn(|conn| {
+            Ok(conn.execute(
+                "DELETE FROM recovery_code WHERE user_id = $1 AND code_hash = $2",
+                &[&user_id, &hash_recovery_code(code)],
+            )?)
+        })?;
+        Ok(deleted > 0)
+    }
+
+    fn reset_recovery_codes(&self, user_id: i64) -> Result<Vec<String>> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            let codes = replace_recovery_codes_tx(&mut tx, user_id)?;
+            tx.commit()?;
+            Ok(codes)
+        })
+    }
+
+    fn disable_totp(&self, user_id: i64) -> Result<()> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            tx.execute("DELETE FROM recovery_code WHERE user_id = $1", &[&user_id])?;
+            tx.execute("DELETE FROM totp WHERE user_id = $1", &[&user_id])?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn get_privacy(&self, user_id: i64) -> Result<Privacy> {
+        self.with_conn(|conn| {
+            let row = conn
+                .query_opt("SELECT visibility, share_devices, share_categories FROM \"user\" WHERE id = $1", &[&user_id])?
+                .ok_or_else(|| DatastoreError::NotFound(format!("user `{}`", user_id)))?;
+            let categories = conn
+                .query("SELECT category, shared FROM category_sharing WHERE user_id = $1 ORDER BY category", &[&user_id])?
+                .iter()
+                .map(|row| CategorySharing { category: row.get(0), shared: row.get(1) })
+                .collect();
+            Ok(Privacy {
+                visibility: Visibility::parse(row.get(0)),
+                share_devices: row.get(1),
+                share_categories: row.get(2),
+                categories,
+            })
+        })
+    }
+
+    fn set_privacy(&self, user_id: i64, privacy: &Privacy) -> Result<()> {
+        self.with_conn(|conn| {
+            let mut tx = conn.transaction()?;
+            let updated = tx.execute(
+                "UPDATE \"user\" SET visibility = $1, share_devices = $2, share_categories = $3 WHERE id = $4",
+                &[&privacy.visibility.as_str(), &privacy.share_devices, &privacy.share_categories, &user_id],
+            )?;
+            if updated == 0 {
+                return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+            }
+            tx.execute("DELETE FROM category_sharing WHERE user_id = $1", &[&user_id])?;
+            for setting in &privacy.categories {
+                tx.execute(
+                    "INSERT INTO category_sharing (user_id, category, shared) VALUES ($1, $2, $3)
+                     ON CONFLICT (user_id, category) DO UPDATE SET shared = excluded.shared",
+                    &[&user_id, &setting.category, &setting.shared],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    fn get_listed_privacy(&self) -> Result<HashMap<String, Privacy>> {
+        self.with_conn(|conn| {
+            let mut listed: HashMap<i64, (String, Privacy)> = HashMap::new();
+            let users = conn.query(
+                "SELECT id, username, share_devices, share_categories FROM \"user\"
+                 WHERE email_verified AND visibility = 'public' AND deletion_due IS NULL",
+                &[],
+            )?;
+            for row in users {
+                let privacy = Privacy {
+                    visibility: Visibility::Public,
+                    share_devices: row.get(2),
+                    share_categories: row.get(3),
+                    categories: Vec::new(),
+                };
+                listed.insert(row.get(0), (row.get(1), privacy));
+            }
+            for row in conn.query("SELECT user_id, category, shared FROM category_sharing ORDER BY category", &[])? {
+                if let Some((_, privacy)) = listed.get_mut(&row.get::<_, i64>(0)) {
+                    privacy.categories.push(CategorySharing { category: row.get(1), shared: row.get(2) });
+                }
+            }
+            Ok(listed.into_values().collect())
+        })
+    }
+}
+
+fn replace_recovery_codes_tx(tx: &mut impl GenericClient, user_id: i64) -> Result<Vec<String>> {
+    tx.execute("DELETE FROM recovery_code WHERE user_id = $1", &[&user_id])?;
+    let codes = generate_recovery_codes();
+    for code in &codes {
+        tx.execute(
+            "INSERT INTO recovery_code (user_id, code_hash) VALUES ($1, $2)",
+            &[&user_id, &hash_recovery_code(code)],
+        )?;
+    }
+    Ok(codes)
+}
+
+fn report_activity_tx(tx: &mut impl GenericClient, device_id: &Uuid, ruleset_id: i64, hour: DateTime<Utc>, events: Vec<Event>, mode: ReportMode) -> Result<ReportOutcome> {
+    let compacted_until = tx
+        .query_opt(
+            "SELECT \"user\".compacted_until FROM device JOIN \"user\" ON device.user_id = \"user\".id WHERE device.id = $1",
+            &[device_id],
+        )?
+        .and_then(|row| row.get::<_, Option<i64>>(0));
+    check_report_hour(hour, compacted_until.map(timestamp_to_datetime))?;
+    let existing = tx.query_opt(
+        "SELECT id, events FROM activity WHERE device_id = $1 AND timestamp = $2 AND ruleset_id = $3",
+        &[device_id, &hour.timestamp(), &ruleset_id],
+    )?;
+    // The report of the hour counted in the rollups so far, the one added last
+    let counted = tx.query_opt(
+        "SELECT id, ruleset_id, events FROM activity WHERE device_id = $1 AND timestamp = $2 ORDER BY id DESC LIMIT 1",
+        &[device_id, &hour.timestamp()],
+    )?;
+
+    let outcome = match existing {
+        None => {
+            let events_json = serde_json::to_string(&events).unwrap();
+            tx.execute(
+                "INSERT INTO activity (timestamp, device_id, events, ruleset_id) VALUES ($1, $2, $3, $4)",
+                &[&hour.timestamp(), device_id, &events_json, &ruleset_id],
+            )?;
+            // The new report takes the place of the one counted so far
+            if let Some(row) = &counted {
+                let counted_events: Vec<Event> = serde_json::from_str(row.get(2)).unwrap();
+                update_rollups(tx, device_id, row.get(1), hour, &counted_events, &[])?;
+            }
+            update_rollups(tx, device_id, ruleset_id, hour, &[], &events)?;
+            ReportOutcome::Created
+        }
+        Some(row) => {
+            let id: i64 = row.get(0);
+            let stored: Vec<Event> = serde_json::from_str(row.get(1)).unwrap();
+            match reported_events(&stored, events, mode) {
+                None => ReportOutcome::Unchanged,
+                Some(events) => {
+                    let events_json = serde_json::to_string(&events).unwrap();
+                    tx.execute("UPDATE activity SET events = $1 WHERE id = $2", &[&events_json, &id])?;
+                    if counted.map(|row| row.get::<_, i64>(0)) == Some(id) {
+                        update_rollups(tx, device_id, ruleset_id, hour, &stored, &events)?;
+                    }
+                    ReportOutcome::Replaced
+                }
+            }
+        }
+    };
+    Ok(outcome)
+}
+
+// Recomputes the daily rollups of a user, or of everyone, from the stored activity, but
+// those of days whose activity was compacted
+fn rebuild_rollups_tx(tx: &mut impl GenericClient, user_id: Option<i64>) -> Result<()> {
+    let activity = tx
+        .query(
+            &format!(
+                "SELECT device.user_id, activity.device_id, activity.timestamp, activity.events, activity.ruleset_id FROM activity
+                 JOIN device ON activity.device_id = device.id
+                 JOIN \"user\" ON device.user_id = \"user\".id
+                 WHERE (\"user\".compacted_until IS NULL OR activity.timestamp >= \"user\".compacted_until) AND {}
+                   AND ($1::BIGINT IS NULL OR \"user\".id = $1)",
+                COUNTED_ACTIVITY
+            ),
+            &[&user_id],
+        )?
+        .iter()
+        .map(|row| {
+            let events: Vec<Event> = serde_json::from_str(row.get(3)).unwrap();
+            (row.get(0), row.get(1), timestamp_to_datetime(row.get(2)), events, row.get(4))
+        })
+        .collect();
+    let totals = rollup_totals(activity, |ruleset_id| get_rules(tx, ruleset_id))?;
+    tx.execute(
+        "DELETE FROM activity_daily WHERE ($1::BIGINT IS NULL OR user_id = $1) AND NOT EXISTS (
+             SELECT 1 FROM \"user\" WHERE \"user\".id = activity_daily.user_id AND activity_daily.day < \"user\".compacted_until
+         )",
+        &[&user_id],
+    )?;
+    for ((user_id, device_id, day, category), duration) in totals {
+        tx.execute(
+            "INSERT INTO activity_daily (user_id, device_id, day, category, duration) VALUES ($1, $2, $3, $4, $5)",
+            &[&user_id, &device_id, &day, &category, &(duration as i64)],
+        )?;
+    }
+    Ok(())
+}
+
+// Rules of a ruleset, empty if it no longer exists
+fn get_rules(tx: &mut impl GenericClient, ruleset_id: i64) -> Result<Vec<Rule>> {
+    let row = tx.query_opt("SELECT rules FROM ruleset WHERE id = $1", &[&ruleset_id])?;
+    Ok(row.map(|row| serde_json::from_str(row.get(0)).unwrap()).unwrap_or_default())
+}
+
+// Applies the difference between the old and new events of an hour to the daily rollups
+fn update_rollups(tx: &mut impl GenericClient, device_id: &Uuid, ruleset_id: i64, hour: DateTime<Utc>, old: &[Event], new: &[Event]) -> Result<()> {
+    let user_id: i64 = tx.query_one("SELECT user_id FROM device WHERE id = $1", &[device_id])?.get(0);
+    let day = start_of_day(hour).timestamp();
+    let rules = get_rules(tx, ruleset_id)?;
+
+    for (category, delta) in rollup_deltas(&rules, old, new) {
+        tx.execute(
+            "INSERT INTO activity_daily (user_id, device_id, day, category, duration) VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT(user_id, device_id, day, category) DO UPDATE SET duration = activity_daily.duration + excluded.duration",
+            &[&user_id, device_id, &day, &category, &delta],
+        )?;
+    }
+    tx.execute(
+        "DELETE FROM activity_daily WHERE device_id = $1 AND day = $2 AND duration <= 0",
+        &[device_id, &day],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,1027 @@
+use std::collections::HashMap;
+
+use bcrypt::{hash, DEFAULT_COST};
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, OptionalExtension};
+use chrono::{prelude::*};
+use uuid::Uuid;
+
+use super::{
+    check_report_hour, generate_api_key_secret, generate_recovery_codes, hash_api_key, hash_recovery_code,
+    reported_events, rollup_deltas, rollup_totals, start_of_day, timestamp_to_datetime, Activity, ApiKey, DailyRollup,
+    Datastore, Device, Event, ReportMode, ReportOutcome, Result, Rule, Ruleset, OauthIdentity, Session, Totp, User, COUNTED_ACTIVITY, USER_TABLES,
+};
+use crate::error::DatastoreError;
+use crate::migrations::sqlite as migrations;
+use crate::privacy::{CategorySharing, Privacy, Visibility};
+use crate::retention::RetentionAction;
+
+pub struct SqliteDb {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+}
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        email: row.get(2)?,
+        password: row.get(3)?,
+        created_at: row.get::<_, Option<i64>>(4)?.map(timestamp_to_datetime),
+        email_verified: row.get(5)?,
+        deletion_due: row.get::<_, Option<i64>>(6)?.map(timestamp_to_datetime),
+    })
+}
+
+fn api_key_from_row(row: &rusqlite::Row) -> rusqlite::Result<ApiKey> {
+    let device_id: Option<String> = row.get(2)?;
+    let created: i64 = row.get(4)?;
+    let last_used: Option<i64> = row.get(5)?;
+    Ok(ApiKey {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        device_id: device_id.map(|id| Uuid::parse_str(&id).unwrap()),
+        name: row.get(3)?,
+        created: timestamp_to_datetime(created),
+        last_used: last_used.map(timestamp_to_datetime),
+    })
+}
+
+fn session_from_row(row: &rusqlite::Row) -> rusqlite::Result<Session> {
+    let id: String = row.get(0)?;
+    Ok(Session {
+        id: Uuid::parse_str(&id).unwrap(),
+        user_id: row.get(1)?,
+        created: timestamp_to_datetime(row.get(2)?),
+        last_used: timestamp_to_datetime(row.get(3)?),
+        expires: timestamp_to_datetime(row.get(4)?),
+        user_agent: row.get(5)?,
+        ip: row.get(6)?,
+    })
+}
+
+impl SqliteDb {
+    pub fn new(manager: SqliteConnectionManager) -> Result<SqliteDb> {
+        // SQLite only enforces foreign keys when asked to, on every connection
+        let manager = manager.with_init(|conn| conn.execute_batch("PRAGMA foreign_keys = ON;"));
+        let pool = r2d2::Pool::new(manager).expect("Failed to create pool.");
+        let db = SqliteDb { pool };
+        db.init()?;
+        Ok(db)
+    }
+
+    fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        Ok(self.pool.get()?)
+    }
+
+    fn init(&self) -> Result<()> {
+        migrations::migrate(&mut *self.conn()?)?;
+        // Backfill rollups for databases created before they existed, or before they were
+        // kept per device
+        let (rollups, activities): (i64, i64) = self.conn()?.query_row(
+            "SELECT (SELECT COUNT(*) FROM activity_daily WHERE device_id IS NOT NULL), (SELECT COUNT(*) FROM activity)",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        if rollups == 0 && activities > 0 {
+            self.rebuild_rollups()?;
+        }
+        Ok(())
+    }
+}
+
+impl Datastore for SqliteDb {
+    fn add_user(&self, username: &str, email: &str, password: &str) -> Result<()> {
+        let hashed_password = hash(password, DEFAULT_COST).unwrap();
+
+        let inserted = self.conn()?.execute(
+            "INSERT INTO user (username, email, password, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![username, email, hashed_password, Utc::now().timestamp()],
+        );
+        match inserted {
+            Ok(_) => Ok(()),
+            // The unique column that was violated tells which one is taken
+            Err(rusqlite::Error::SqliteFailure(err, Some(msg))) if err.code == rusqlite::ErrorCode::ConstraintViolation => {
+                if msg.contains("user.username") {
+                    Err(DatastoreError::UserAlreadyExists { username: username.to_string() })
+                } else if msg.contains("user.email") {
+                    Err(DatastoreError::EmailAlreadyExists { email: email.to_string() })
+                } else {
+                    Err(rusqlite::Error::SqliteFailure(err, Some(msg)).into())
+                }
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn get_user(&self, username: &str) -> Result<User> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT id, username, email, password, created_at, email_verified, deletion_due FROM user WHERE username = ?1")?;
+        let mut user_iter = stmt.query_map(params![username], user_from_row)?;
+
+        match user_iter.next() {
+            Some(user) => Ok(user?),
+            None => Err(DatastoreError::NotFound(format!("user `{}`", username))),
+        }
+    }
+
+    fn get_user_by_id(&self, user_id: i64) -> Result<User> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT id, username, email, password, created_at, email_verified, deletion_due FROM user WHERE id = ?1")?;
+        let mut user_iter = stmt.query_map(params![user_id], user_from_row)?;
+
+        match user_iter.next() {
+            Some(user) => Ok(user?),
+            None => Err(DatastoreError::NotFound(format!("user `{}`", user_id))),
+        }
+    }
+
+    fn get_user_by_email(&self, email: &str) -> Result<User> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT id, username, email, password, created_at, email_verified, deletion_due FROM user WHERE email = ?1")?;
+        let mut user_iter = stmt.query_map(params![email], user_from_row)?;
+
+        match user_iter.next() {
+            Some(user) => Ok(user?),
+            None => Err(DatastoreError::NotFound(format!("user with email `{}`", email))),
+        }
+    }
+
+    fn set_email_verified(&self, user_id: i64) -> Result<()> {
+        let updated = self.conn()?.execute("UPDATE user SET email_verified = 1 WHERE id = ?1", params![user_id])?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        Ok(())
+    }
+
+    fn set_password(&self, user_id: i64, password: &str) -> Result<()> {
+        let hashed_password = hash(password, DEFAULT_COST).unwrap();
+        let updated = self.conn()?.execute(
+            "UPDATE user SET password = ?1 WHERE id = ?2",
+            params![hashed_password, user_id],
+        )?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        Ok(())
+    }
+
+    fn delete_user(&self, user_id: i64) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM activity WHERE device_id IN (SELECT id FROM device WHERE user_id = ?1)",
+            params![user_id],
+        )?;
+        for table in USER_TABLES {
+            tx.execute(&format!("DELETE FROM {} WHERE user_id = ?1", table), params![user_id])?;
+        }
+        let deleted = tx.execute("DELETE FROM user WHERE id = ?1", params![user_id])?;
+        if deleted == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn schedule_user_deletion(&self, user_id: i64, due: Option<DateTime<Utc>>) -> Result<()> {
+        let updated = self.conn()?.execute(
+            "UPDATE user SET deletion_due = ?1 WHERE id = ?2",
+            params![due.map(|due| due.timestamp()), user_id],
+        )?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        Ok(())
+    }
+
+    fn get_users_due_for_deletion(&self, now: DateTime<Utc>) -> Result<Vec<i64>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT id FROM user WHERE deletion_due <= ?1")?;
+        let users = stmt.query_map(params![now.timestamp()], |row| row.get(0))?;
+        Ok(users.collect::<rusqlite::Result<Vec<i64>>>()?)
+    }
+
+    fn add_device(&self, user_id: i64, device_id: Uuid, name: &str) -> Result<()> {
+        let inserted = self.conn()?.execute(
+            "INSERT INTO device (id, user_id, name) VALUES (?1, ?2, ?3)",
+            params![device_id.to_string(), user_id, name],
+        );
+        match inserted {
+            Ok(_) => Ok(()),
+            Err(rusqlite::Error::SqliteFailure(err, _)) if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY => {
+                Err(DatastoreError::Conflict(format!("device `{}` already exists", device_id)))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn get_devices(&self, user_id: i64) -> Result<Vec<Device>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT device.id, device.user_id, device.name, MAX(activity.timestamp)
+             FROM device LEFT JOIN activity ON activity.device_id = device.id
+             WHERE device.user_id = ?1
+             GROUP BY device.id",
+        )?;
+        let mut device_iter = stmt.query_map(params![user_id], |row| {
+            let device_id: String = row.get(0)?;
+            let last_seen: Option<i64> = row.get(3)?;
+            Ok(Device {
+                id: Uuid::parse_str(&device_id).unwrap(),
+                user_id: row.get(1)?,
+                name: row.get(2)?,
+                last_seen: last_seen.map(timestamp_to_datetime),
+            })
+        })?;
+
+        let mut devices = Vec::new();
+        while let Some(device) = device_iter.next() {
+            devices.push(device?);
+        }
+        Ok(devices)
+    }
+
+    fn get_device(&self, device_id: &Uuid) -> Result<Device> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT device.id, device.user_id, device.name, MAX(activity.timestamp)
+             FROM device LEFT JOIN activity ON activity.device_id = device.id
+             WHERE device.id = ?1
+             GROUP BY device.id",
+        )?;
+        let mut device_iter = stmt.query_map(params![device_id.to_string()], |row| {
+            let last_seen: Option<i64> = row.get(3)?;
+            Ok(Device {
+                id: *device_id,
+                user_id: row.get(1)?,
+                name: row.get(2)?,
+                last_seen: last_seen.map(timestamp_to_datetime),
+            })
+        })?;
+
+        match device_iter.next() {
+            Some(device) => Ok(device?),
+            None => Err(DatastoreError::NotFound(format!("device `{}`", device_id))),
+        }
+    }
+
+    fn delete_device(&self, device_id: &Uuid) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM activity_daily WHERE device_id = ?1", params![device_id.to_string()])?;
+        tx.execute("DELETE FROM activity WHERE device_id = ?1", params![device_id.to_string()])?;
+        tx.execute("DELETE FROM api_key WHERE device_id = ?1", params![device_id.to_string()])?;
+        let deleted = tx.execute("DELETE FROM device WHERE id = ?1", params![device_id.to_string()])?;
+        if deleted == 0 {
+            return Err(DatastoreError::NotFound(format!("device `{}`", device_id)));
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn for_each_activity(&self, device_id: &Uuid, f: &mut (dyn FnMut(Activity) -> Result<()> + Send)) -> Result<()> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, timestamp, events, ruleset_id FROM activity WHERE device_id = ?1 ORDER BY timestamp",
+        )?;
+        let mut rows = stmt.query(params![device_id.to_string()])?;
+        while let Some(row) = rows.next()? {
+            let events: String = row.get(2)?;
+            f(Activity {
+                id: row.get(0)?,
+                timestamp: timestamp_to_datetime(row.get(1)?),
+                device_id: *device_id,
+                events: serde_json::from_str(&events).unwrap(),
+                ruleset_id: row.get(3)?,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn get_daily_totals(&self, since: Option<DateTime<Utc>>) -> Result<Vec<(String, String, u64)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT user.username, activity_daily.category, SUM(activity_daily.duration) FROM activity_daily
+             JOIN user ON activity_daily.user_id = user.id
+             WHERE activity_daily.day >= ?1 AND user.email_verified
+             GROUP BY user.id, activity_daily.category",
+        )?;
+        let since = since.map(|t| t.timestamp()).unwrap_or(i64::MIN);
+        let total_iter = stmt.query_map(params![since], |row| {
+            let duration: i64 = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, duration.max(0) as u64))
+        })?;
+
+        let mut totals = Vec::new();
+        for total in total_iter {
+            totals.push(total?);
+        }
+        Ok(totals)
+    }
+
+    fn get_category_totals(&self, user_id: i64, since: Option<DateTime<Utc>>) -> Result<Vec<(String, u64)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT category, SUM(duration) FROM activity_daily
+             WHERE user_id = ?1 AND day >= ?2
+             GROUP BY category",
+        )?;
+        let since = since.map(|t| t.timestamp()).unwrap_or(i64::MIN);
+        let total_iter = stmt.query_map(params![user_id, since], |row| {
+            let duration: i64 = row.get(1)?;
+            Ok((row.get(0)?, duration.max(0) as u64))
+        })?;
+
+        let mut totals = Vec::new();
+        for total in total_iter {
+            totals.push(total?);
+        }
+        Ok(totals)
+    }
+
+    fn get_daily_rollups(&self, user_id: i64) -> Result<Vec<DailyRollup>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT day, device_id, category, duration FROM activity_daily
+             WHERE user_id = ?1
+             ORDER BY day, device_id, category",
+        )?;
+        let rollups = stmt.query_map(params![user_id], |row| {
+            let device_id: Option<String> = row.get(1)?;
+            let duration: i64 = row.get(3)?;
+            Ok(DailyRollup {
+                day: timestamp_to_datetime(row.get(0)?),
+                device_id: device_id.map(|id| Uuid::parse_str(&id).unwrap()),
+                category: row.get(2)?,
+                duration: duration.max(0) as u64,
+            })
+        })?;
+        Ok(rollups.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn rebuild_rollups(&self) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        rebuild_rollups_tx(&tx, None)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_retention(&self, user_id: i64) -> Result<Option<u32>> {
+        let days: Option<i64> = self
+            .conn()?
+            .query_row("SELECT retention_days FROM user WHERE id = ?1", params![user_id], |row| row.get(0))
+            .optional()?
+            .ok_or_else(|| DatastoreError::NotFound(format!("user `{}`", user_id)))?;
+        Ok(days.map(|days| days as u32))
+    }
+
+    fn set_retention(&self, user_id: i64, days: Option<u32>) -> Result<()> {
+        let updated = self.conn()?.execute(
+            "UPDATE user SET retention_days = ?1 WHERE id = ?2",
+            params![days, user_id],
+        )?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        Ok(())
+    }
+
+    fn get_retentions(&self) -> Result<Vec<(i64, Option<u32>)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT id, retention_days FROM user ORDER BY id")?;
+        let retentions = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, Option<i64>>(1)?.map(|days| days as u32))))?;
+        Ok(retentions.collect::<rusqlite::Result<_>>()?)
+    }
+
+    fn count_activity_before(&self, user_id: i64, before: DateTime<Utc>) -> Result<usize> {
+        let count: i64 = self.conn()?.query_row(
+            "SELECT COUNT(*) FROM activity JOIN device ON activity.device_id = device.id
+             WHERE device.user_id = ?1 AND activity.timestamp < ?2",
+            params![user_id, start_of_day(before).timestamp()],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn prune_activity(&self, user_id: i64, before: DateTime<Utc>, action: RetentionAction) -> Result<usize> {
+        let before = start_of_day(before).timestamp();
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        match action {
+            RetentionAction::Delete => {
+                let activity: Vec<(String, i64, i64, String)> = tx
+                    .prepare(&format!(
+                        "SELECT activity.device_id, activity.ruleset_id, activity.timestamp, activity.events FROM activity
+                         JOIN device ON activity.device_id = device.id
+                         WHERE device.user_id = ?1 AND activity.timestamp < ?2 AND {}",
+                        COUNTED_ACTIVITY
+                    ))?
+                    .query_map(params![user_id, before], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+                    .collect::<rusqlite::Result<_>>()?;
+                for (device_id, ruleset_id, hour, events) in activity {
+                    let events: Vec<Event> = serde_json::from_str(&events).unwrap();
+                    let device_id = Uuid::parse_str(&device_id).unwrap();
+                    update_rollups_tx(&tx, &device_id, ruleset_id, timestamp_to_datetime(hour), &events, &[])?;
+                }
+                // Days compacted before have no activity left to take off, only their rollups
+                tx.execute(
+                    "DELETE FROM activity_daily WHERE user_id = ?1 AND day < ?2
+                     AND day < (SELECT compacted_until FROM user WHERE id = ?1)",
+                    params![user_id, before],
+                )?;
+            }
+            // The rollups of these days are all that will be left of them
+            RetentionAction::Compact => {
+                tx.execute(
+                    "UPDATE user SET compacted_until = MAX(COALESCE(compacted_until, ?2), ?2) WHERE id = ?1",
+                    params![user_id, before],
+                )?;
+            }
+        }
+        let pruned = tx.execute(
+            "DELETE FROM activity WHERE timestamp < ?2 AND device_id IN (SELECT id FROM device WHERE user_id = ?1)",
+            params![user_id, before],
+        )?;
+        tx.commit()?;
+        Ok(pruned)
+    }
+
+    fn create_ruleset(&self, user_id: i64, name: &str, rules: Vec<Rule>) -> Result<i64> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "INSERT INTO ruleset (user_id, name, rules) VALUES (?1, ?2, ?3)",
+        )?;
+        let rules_json = serde_json::to_string(&rules).unwrap();
+        stmt.execute(params![user_id, name, rules_json])?;
+        Ok(conn.last_insert_rowid() as i64)
+    }
+
+    fn get_ruleset(&self, ruleset_id: i64) -> Result<Ruleset> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT id, user_id, name, rules FROM ruleset WHERE id = ?1")?;
+        let mut ruleset_iter = stmt.query_map(params![ruleset_id], |row| {
+            let rules: String = row.get(3)?;
+            Ok(Ruleset {
+                id: row.get(0)?,
+                user_id: row.get(1)?,
+                name: row.get(2)?,
+                rules: serde_json::from_str(&rules).unwrap(),
+            })
+        })?;
+
+        match ruleset_iter.next() {
+            Some(ruleset) => Ok(ruleset?),
+            None => Err(DatastoreError::NotFound(format!("ruleset `{}`", ruleset_id))),
+        }
+    }
+
+    fn get_rulesets(&self, user_id: i64) -> Result<Vec<Ruleset>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT id, user_id, name, rules FROM ruleset WHERE user_id = ?1")?;
+        let ruleset_iter = stmt.query_map(params![user_id], |row| {
+            let rules: String = row.get(3)?;
+            Ok(Ruleset {
+                id: row.get(0)?,
+                user_id: row.get(1)?,
+                name: row.get(2)?,
+                rules: serde_json::from_str(&rules).unwrap(),
+            })
+        })?;
+
+        let mut rulesets = Vec::new();
+        for ruleset in ruleset_iter {
+            rulesets.push(ruleset?);
+        }
+        Ok(rulesets)
+    }
+
+    fn update_ruleset(&self, ruleset_id: i64, name: &str, rules: Vec<Rule>) -> Result<()> {
+        let rules_json = serde_json::to_string(&rules).unwrap();
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let user_id: i64 = tx
+            .query_row("SELECT user_id FROM ruleset WHERE id = ?1", params![ruleset_id], |row| row.get(0))
+            .optional()?
+            .ok_or_else(|| DatastoreError::NotFound(format!("ruleset `{}`", ruleset_id)))?;
+        tx.execute(
+            "UPDATE ruleset SET name = ?1, rules = ?2 WHERE id = ?3",
+            params![name, rules_json, ruleset_id],
+        )?;
+        // The rollups hold the categories of the old rules
+        rebuild_rollups_tx(&tx, Some(user_id))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_ruleset(&self, ruleset_id: i64) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let references: i64 = tx.query_row(
+            "SELECT COUNT(*) FROM activity WHERE ruleset_id = ?1",
+            params![ruleset_id],
+            |row| row.get(0),
+        )?;
+        if references > 0 {
+            return Err(DatastoreError::Conflict(format!(
+                "ruleset `{}` is used by {} hours of activity",
+                ruleset_id, references
+            )));
+        }
+        let deleted = tx.execute("DELETE FROM ruleset WHERE id = ?1", params![ruleset_id])?;
+        if deleted == 0 {
+            return Err(DatastoreError::NotFound(format!("ruleset `{}`", ruleset_id)));
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn report_activity_batch(&self, device_id: &Uuid, ruleset_id: i64, reports: Vec<(DateTime<Utc>, Vec<Event>)>, mode: ReportMode) -> Result<Vec<Result<ReportOutcome>>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let mut outcomes = Vec::with_capacity(reports.len());
+        for (hour, events) in reports {
+            match report_activity_tx(&tx, device_id, ruleset_id, hour, events, mode) {
+                Err(DatastoreError::BadRequest(msg)) => outcomes.push(Err(DatastoreError::BadRequest(msg))),
+                result => outcomes.push(Ok(result?)),
+            }
+        }
+        tx.commit()?;
+        Ok(outcomes)
+    }
+
+    fn create_api_key(&self, user_id: i64, device_id: Option<&Uuid>, name: &str) -> Result<(ApiKey, String)> {
+        let secret = generate_api_key_secret();
+        let created = Utc::now().with_nanosecond(0).unwrap();
+
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO api_key (user_id, device_id, name, key_hash, created) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![user_id, device_id.map(|id| id.to_string()), name, hash_api_key(&secret), created.timestamp()],
+        )?;
+        let key = ApiKey {
+            id: conn.last_insert_rowid(),
+            user_id,
+            device_id: device_id.copied(),
+            name: name.to_string(),
+            created,
+            last_used: None,
+        };
+        Ok((key, secret))
+    }
+
+    fn get_api_keys(&self, user_id: i64) -> Result<Vec<ApiKey>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, device_id, name, created, last_used FROM api_key WHERE user_id = ?1",
+        )?;
+        let key_iter = stmt.query_map(params![user_id], api_key_from_row)?;
+
+        let mut keys = Vec::new();
+        for key in key_iter {
+            keys.push(key?);
+        }
+        Ok(keys)
+    }
+
+    fn use_api_key(&self, secret: &str) -> Result<ApiKey> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, device_id, name, created, last_used FROM api_key WHERE key_hash = ?1",
+        )?;
+        let mut key_iter = stmt.query_map(params![hash_api_key(secret)], api_key_from_row)?;
+        let key = match key_iter.next() {
+            Some(key) => key?,
+            None => return Err(DatastoreError::Unauthorized("invalid API key".to_string())),
+        };
+        conn.execute(
+            "UPDATE api_key SET last_used = ?1 WHERE id = ?2",
+            params![Utc::now().timestamp(), key.id],
+        )?;
+        Ok(key)
+    }
+
+    fn revoke_api_key(&self, user_id: i64, key_id: i64) -> Result<()> {
+        let deleted = self.conn()?.execute(
+            "DELETE FROM api_key WHERE id = ?1 AND user_id = ?2",
+            params![key_id, user_id],
+        )?;
+        if deleted == 0 {
+            return Err(DatastoreError::NotFound(format!("api key `{}`", key_id)));
+        }
+        Ok(())
+    }
+
+    fn create_session(&self, user_id: i64, expires: DateTime<Utc>, user_agent: Option<&str>, ip: Option<&str>) -> Result<Session> {
+        let now = Utc::now().with_nanosecond(0).unwrap();
+        let session = Session {
+            id: Uuid::new_v4(),
+            user_id,
+            created: now,
+            last_used: now,
+            expires: expires.with_nanosecond(0).unwrap(),
+            user_agent: user_agent.map(String::from),
+            ip: ip.map(String::from),
+        };
+        let conn = self.conn()?;
+        // Expired sessions of the user are cleaned up on every login
+        conn.execute(
+            "DELETE FROM session WHERE user_id = ?1 AND expires <= ?2",
+            params![user_id, now.timestamp()],
+        )?;
+        conn.execute(
+            "INSERT INTO session (id, user_id, created, last_used, expires, user_agent, ip)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                session.id.to_string(),
+                user_id,
+                session.created.timestamp(),
+                session.last_used.timestamp(),
+                session.expires.timestamp(),
+                session.user_agent,
+                session.ip,
+            ],
+        )?;
+        Ok(session)
+    }
+
+    fn use_session(&self, session_id: &Uuid) -> Result<Session> {
+        let now = Utc::now().timestamp();
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, created, last_used, expires, user_agent, ip FROM session
+             WHERE id = ?1 AND expires > ?2",
+        )?;
+        let mut session_iter = stmt.query_map(params![session_id.to_string(), now], session_from_row)?;
+        let session = match session_iter.next() {
+            Some(session) => session?,
+            None => return Err(DatastoreError::Unauthorized("session has ended".to_string())),
+        };
+        conn.execute("UPDATE session SET last_used = ?1 WHERE id = ?2", params![now, session_id.to_string()])?;
+        Ok(session)
+    }
+
+    fn get_sessions(&self, user_id: i64) -> Result<Vec<Session>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, created, last_used, expires, user_agent, ip FROM session
+             WHERE user_id = ?1 AND expires > ?2
+             ORDER BY last_used DESC",
+        )?;
+        let session_iter = stmt.query_map(params![user_id, Utc::now().timestamp()], session_from_row)?;
+
+        let mut sessions = Vec::new();
+        for session in session_iter {
+            sessions.push(session?);
+        }
+        Ok(sessions)
+    }
+
+    fn revoke_session(&self, user_id: i64, session_id: &Uuid) -> Result<()> {
+        let deleted = self.conn()?.execute(
+            "DELETE FROM session WHERE id = ?1 AND user_id = ?2",
+            params![session_id.to_string(), user_id],
+        )?;
+        if deleted == 0 {
+            return Err(DatastoreError::NotFound(format!("session `{}`", session_id)));
+        }
+        Ok(())
+    }
+
+    fn revoke_other_sessions(&self, user_id: i64, keep: Option<&Uuid>) -> Result<usize> {
+        Ok(self.conn()?.execute(
+            "DELETE FROM session WHERE user_id = ?1 AND (?2 IS NULL OR id != ?2)",
+            params![user_id, keep.map(|id| id.to_string())],
+        )?)
+    }
+
+    fn get_oauth_user(&self, provider: &str, subject: &str) -> Result<User> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT user.id, username, email, password, created_at, email_verified, deletion_due FROM oauth_identity
+             JOIN user ON user.id = oauth_identity.user_id
+             WHERE provider = ?1 AND subject = ?2",
+        )?;
+        let mut user_iter = stmt.query_map(params![provider, subject], user_from_row)?;
+
+        match user_iter.next() {
+            Some(user) => Ok(user?),
+            None => Err(DatastoreError::NotFound(format!("{} account `{}`", provider, subject))),
+        }
+    }
+
+    fn link_oauth_identity(&self, user_id: i64, provider: &str, subject: &str) -> Result<()> {
+        let inserted = self.conn()?.execute(
+            "INSERT OR IGNORE INTO oauth_identity (provider, subject, user_id, created) VALUES (?1, ?2, ?3, ?4)",
+            params![provider, subject, user_id, Utc::now().timestamp()],
+        )?;
+        if inserted == 0 {
+            return Err(DatastoreError::Conflict(format!("{} account is already linked", provider)));
+        }
+        Ok(())
+    }
+
+    fn get_oauth_identities(&self, user_id: i64) -> Result<Vec<OauthIdentity>> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT provider, subject, created FROM oauth_identity WHERE user_id = ?1 ORDER BY created")?;
+        let identity_iter = stmt.query_map(params![user_id], |row| {
+            Ok(OauthIdentity {
+                provider: row.get(0)?,
+                subject: row.get(1)?,
+                created: timestamp_to_datetime(row.get(2)?),
+            })
+        })?;
+
+        let mut identities = Vec::new();
+        for identity in identity_iter {
+            identities.push(identity?);
+        }
+        Ok(identities)
+    }
+
+    fn get_totp(&self, user_id: i64) -> Result<Option<Totp>> {
+        Ok(self
+            .conn()?
+            .query_row(
+                "SELECT secret, enabled, (SELECT COUNT(*) FROM recovery_code WHERE user_id = ?1)
+                 FROM totp WHERE user_id = ?1",
+                params![user_id],
+                |row| Ok(Totp { secret: row.get(0)?, enabled: row.get(1)?, recovery_codes: row.get(2)? }),
+            )
+            .optional()?)
+    }
+
+    fn set_totp_secret(&self, user_id: i64, secret: &str) -> Result<()> {
+        let updated = self.conn()?.execute(
+            "INSERT INTO totp (user_id, secret, enabled) VALUES (?1, ?2, 0)
+             ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret WHERE NOT totp.enabled",
+            params![user_id, secret],
+        )?;
+        if updated == 0 {
+            return Err(DatastoreError::Conflict("two-factor authentication is already enabled".to_string()));
+        }
+        Ok(())
+    }
+
+    fn enable_totp(&self, user_id: i64, step: i64) -> Result<Vec<String>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let updated = tx.execute(
+            "UPDATE totp SET enabled = 1, last_step = ?1 WHERE user_id = ?2 AND NOT enabled",
+            params![step, user_id],
+        )?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound("pending two-factor setup".to_string()));
+        }
+        let codes = replace_recovery_codes_tx(&tx, user_id)?;
+        tx.commit()?;
+        Ok(codes)
+    }
+
+    fn use_totp_step(&self, user_id: i64, step: i64) -> Result<bool> {
+        let updated = self.conn()?.execute(
+            "UPDATE totp SET last_step = ?1 WHERE user_id = ?2 AND enabled AND (last_step IS NULL OR last_step < ?1)",
+            params![step, user_id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn use_recovery_code(&self, user_id: i64, code: &str) -> Result<bool> {
+        let deleted = self.conn()?.execute(
+            "DELETE FROM recovery_code WHERE user_id = ?1 AND code_hash = ?2",
+            params![user_id, hash_recovery_code(code)],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    fn reset_recovery_codes(&self, user_id: i64) -> Result<Vec<String>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let codes = replace_recovery_codes_tx(&tx, user_id)?;
+        tx.commit()?;
+        Ok(codes)
+    }
+
+    fn disable_totp(&self, user_id: i64) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM recovery_code WHERE user_id = ?1", params![user_id])?;
+        tx.execute("DELETE FROM totp WHERE user_id = ?1", params![user_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_privacy(&self, user_id: i64) -> Result<Privacy> {
+        let conn = self.conn()?;
+        let (visibility, share_devices, share_categories): (String, bool, bool) = conn
+            .query_row(
+                "SELECT visibility, share_devices, share_categories FROM user WHERE id = ?1",
+                params![user_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?
+            .ok_or_else(|| DatastoreError::NotFound(format!("user `{}`", user_id)))?;
+        let mut stmt = conn.prepare("SELECT category, shared FROM category_sharing WHERE user_id = ?1 ORDER BY category")?;
+        let categories = stmt
+            .query_map(params![user_id], |row| Ok(CategorySharing { category: row.get(0)?, shared: row.get(1)? }))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(Privacy { visibility: Visibility::parse(&visibility), share_devices, share_categories, categories })
+    }
+
+    fn set_privacy(&self, user_id: i64, privacy: &Privacy) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let updated = tx.execute(
+            "UPDATE user SET visibility = ?1, share_devices = ?2, share_categories = ?3 WHERE id = ?4",
+            params![privacy.visibility.as_str(), privacy.share_devices, privacy.share_categories, user_id],
+        )?;
+        if updated == 0 {
+            return Err(DatastoreError::NotFound(format!("user `{}`", user_id)));
+        }
+        tx.execute("DELETE FROM category_sharing WHERE user_id = ?1", params![user_id])?;
+        for setting in &privacy.categories {
+            tx.execute(
+                "INSERT OR REPLACE INTO category_sharing (user_id, category, shared) VALUES (?1, ?2, ?3)",
+                params![user_id, setting.category, setting.shared],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_listed_privacy(&self) -> Result<HashMap<String, Privacy>> {
+        let conn = self.conn()?;
+        let mut listed: HashMap<i64, (String, Privacy)> = HashMap::new();
+        let mut stmt = conn.prepare(
+            "SELECT id, username, share_devices, share_categories FROM user
+             WHERE email_verified AND visibility = 'public' AND deletion_due IS NULL",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let privacy = Privacy {
+                visibility: Visibility::Public,
+                share_devices: row.get(2)?,
+                share_categories: row.get(3)?,
+                categories: Vec::new(),
+            };
+            listed.insert(row.get(0)?, (row.get(1)?, privacy));
+        }
+        let mut stmt = conn.prepare("SELECT user_id, category, shared FROM category_sharing ORDER BY category")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            if let Some((_, privacy)) = listed.get_mut(&row.get::<_, i64>(0)?) {
+                privacy.categories.push(CategorySharing { category: row.get(1)?, shared: row.get(2)? });
+            }
+        }
+        Ok(listed.into_values().collect())
+    }
+}
+
+fn replace_recovery_codes_tx(tx: &rusqlite::Transaction, user_id: i64) -> Result<Vec<String>> {
+    tx.execute("DELETE FROM recovery_code WHERE user_id = ?1", params![user_id])?;
+    let codes = generate_recovery_codes();
+    for code in &codes {
+        tx.execute(
+            "INSERT INTO recovery_code (user_id, code_hash) VALUES (?1, ?2)",
+            params![user_id, hash_recovery_code(code)],
+        )?;
+    }
+    Ok(codes)
+}
+
+fn report_activity_tx(tx: &rusqlite::Transaction, device_id: &Uuid, ruleset_id: i64, hour: DateTime<Utc>, events: Vec<Event>, mode: ReportMode) -> Result<ReportOutcome> {
+    let compacted_until: Option<i64> = tx
+        .query_row(
+            "SELECT user.compacted_until FROM device JOIN user ON device.user_id = user.id WHERE device.id = ?1",
+            params![device_id.to_string()],
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+    check_report_hour(hour, compacted_until.map(timestamp_to_datetime))?;
+    let existing: Option<(i64, String)> = tx
+        .query_row(
+            "SELECT id, events FROM activity WHERE device_id = ?1 AND timestamp = ?2 AND ruleset_id = ?3",
+            params![device_id.to_string(), hour.timestamp(), ruleset_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    // The report of the hour counted in the rollups so far, the one added last
+    let counted: Option<(i64, i64, String)> = tx
+        .query_row(
+            "SELECT id, ruleset_id, events FROM activity WHERE device_id = ?1 AND timestamp = ?2 ORDER BY id DESC LIMIT 1",
+            params![device_id.to_string(), hour.timestamp()],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+
+    let outcome = match existing {
+        None => {
+            let events_json = serde_json::to_string(&events).unwrap();
+            tx.execute(
+                "INSERT INTO activity (timestamp, device_id, events, ruleset_id) VALUES (?1, ?2, ?3, ?4)",
+                params![hour.timestamp(), device_id.to_string(), events_json, ruleset_id],
+            )?;
+            // The new report takes the place of the one counted so far
+            if let Some((_, counted_ruleset_id, counted_json)) = &counted {
+                let counted_events: Vec<Event> = serde_json::from_str(counted_json).unwrap();
+                update_rollups_tx(tx, device_id, *counted_ruleset_id, hour, &counted_events, &[])?;
+            }
+            update_rollups_tx(tx, device_id, ruleset_id, hour, &[], &events)?;
+            ReportOutcome::Created
+        }
+        Some((id, stored_json)) => {
+            let stored: Vec<Event> = serde_json::from_str(&stored_json).unwrap();
+            match reported_events(&stored, events, mode) {
+                None => ReportOutcome::Unchanged,
+                Some(events) => {
+                    let events_json = serde_json::to_string(&events).unwrap();
+                    tx.execute(
+                        "UPDATE activity SET events = ?1 WHERE id = ?2",
+                        params![events_json, id],
+                    )?;
+                    if counted.map(|(counted_id, _, _)| counted_id) == Some(id) {
+                        update_rollups_tx(tx, device_id, ruleset_id, hour, &stored, &events)?;
+                    }
+                    ReportOutcome::Replaced
+                }
+            }
+        }
+    };
+    Ok(outcome)
+}
+
+// Recomputes the daily rollups of a user, or of everyone, from the stored activity, but
+// those of days whose activity was compacted
+fn rebuild_rollups_tx(tx: &rusqlite::Transaction, user_id: Option<i64>) -> Result<()> {
+    let mut activity = Vec::new();
+    {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT device.user_id, activity.device_id, activity.timestamp, activity.events, activity.ruleset_id FROM activity
+             JOIN device ON activity.device_id = device.id
+             JOIN user ON device.user_id = user.id
+             WHERE (user.compacted_until IS NULL OR activity.timestamp >= user.compacted_until) AND {}
+               AND (?1 IS NULL OR user.id = ?1)",
+            COUNTED_ACTIVITY
+        ))?;
+        let mut rows = stmt.query(params![user_id])?;
+        while let Some(row) = rows.next()? {
+            let device_id: String = row.get(1)?;
+            let events: String = row.get(3)?;
+            let events: Vec<Event> = serde_json::from_str(&events).unwrap();
+            activity.push((row.get(0)?, Uuid::parse_str(&device_id).unwrap(), timestamp_to_datetime(row.get(2)?), events, row.get(4)?));
+        }
+    }
+    let totals = rollup_totals(activity, |ruleset_id| get_rules_tx(tx, ruleset_id))?;
+    tx.execute(
+        "DELETE FROM activity_daily WHERE (?1 IS NULL OR user_id = ?1) AND NOT EXISTS (
+             SELECT 1 FROM user WHERE user.id = activity_daily.user_id AND activity_daily.day < user.compacted_until
+         )",
+        params![user_id],
+    )?;
+    for ((user_id, device_id, day, category), duration) in totals {
+        tx.execute(
+            "INSERT INTO activity_daily (user_id, device_id, day, category, duration) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![user_id, device_id.to_string(), day, category, duration as i64],
+        )?;
+    }
+    Ok(())
+}
+
+// Rules of a ruleset, empty if it no longer exists
+fn get_rules_tx(tx: &rusqlite::Transaction, ruleset_id: i64) -> Result<Vec<Rule>> {
+    let rules: Option<String> = tx
+        .query_row("SELECT rules FROM ruleset WHERE id = ?1", params![ruleset_id], |row| row.get(0))
+        .optional()?;
+    Ok(rules.map(|r| serde_json::from_str(&r).unwrap()).unwrap_or_default())
+}
+
+// Applies the difference between the old and new events of an hour to the daily rollups
+fn update_rollups_tx(tx: &rusqlite::Transaction, device_id: &Uuid, ruleset_id: i64, hour: DateTime<Utc>, old: &[Event], new: &[Event]) -> Result<()> {
+    let user_id: i64 = tx.query_row(
+        "SELECT user_id FROM device WHERE id = ?1",
+        params![device_id.to_string()],
+        |row| row.get(0),
+    )?;
+    let day = start_of_day(hour).timestamp();
+    let rules = get_rules_tx(tx, ruleset_id)?;
+
+    for (category, delta) in rollup_deltas(&rules, old, new) {
+        tx.execute(
+            "INSERT INTO activity_daily (user_id, device_id, day, category, duration) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(user_id, device_id, day, category) DO UPDATE SET duration = duration + excluded.duration",
+            params![user_id, device_id.to_string(), day, category, delta],
+        )?;
+    }
+    tx.execute(
+        "DELETE FROM activity_daily WHERE device_id = ?1 AND day = ?2 AND duration <= 0",
+        params![device_id.to_string(), day],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,197 @@
+use chrono::{DateTime, Utc};
+use rocket::{form::Form, http::{Cookie, CookieJar}, response::Redirect, serde::json::Json, State};
+use rocket_dyn_templates::Template;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::db::{Db, self};
+use crate::endpoints::{util::{error, message, ApiUser, Context, HttpErrorJson}, Respondable};
+use crate::error::DatastoreError;
+use crate::mailer::Mailer;
+use crate::ratelimit::{RateLimiter, RouteGroup};
+use crate::tokens::{Purpose, Tokens};
+use crate::validation::check_password;
+
+#[derive(FromForm)]
+pub struct ResetRequest {
+    email: String,
+}
+
+#[derive(FromForm)]
+pub struct NewPassword {
+    token: String,
+    password: String,
+}
+
+// Users type their username to confirm they mean to delete their account
+#[derive(FromForm)]
+pub struct DeleteConfirmation {
+    username: String,
+}
+
+#[derive(Serialize)]
+pub struct DeletionResponse {
+    // When the account is removed for good, None if it already was
+    deletion_due: Option<DateTime<Utc>>,
+}
+
+// Emails a user the link to confirm their email address with
+pub fn send_verification(mailer: &Mailer, tokens: &Tokens, config: &Config, user: &db::User) -> Result<(), DatastoreError> {
+    let token = tokens.issue_link(Purpose::VerifyEmail, user);
+    let body = format!(
+        "Hi {},\n\nPlease confirm your email address by opening this link:\n\n{}\n\n\
+         The link expires in {} hours. Until then you are not listed on the leaderboards.",
+        user.username,
+        config.link(&format!("/verify-email?token={}", token)),
+        Purpose::VerifyEmail.lifetime().num_hours(),
+    );
+    mailer.send(&user.email, "Confirm your email address", &body)
+}
+
+fn send_reset(mailer: &Mailer, tokens: &Tokens, config: &Config, user: &db::User) -> Result<(), DatastoreError> {
+    let token = tokens.issue_link(Purpose::ResetPassword, user);
+    let body = format!(
+        "Hi {},\n\nSomeone asked to reset the password of your account. To choose a new one, open this link:\n\n{}\n\n\
+         The link expires in {} minutes. If it wasn't you, you can ignore this email.",
+        user.username,
+        config.link(&format!("/reset-password/confirm?token={}", token)),
+        Purpose::ResetPassword.lifetime().num_minutes(),
+    );
+    mailer.send(&user.email, "Reset your password", &body)
+}
+
+#[get("/verify-email?<token>")]
+pub fn verify_email(db: &State<Db>, tokens: &State<Tokens>, token: &str, context: Context) -> Template {
+    let verified = tokens
+        .verify_link(token, Purpose::VerifyEmail, |id| db.get_user_by_id(id))
+        .and_then(|user| db.set_email_verified(user.id));
+    match verified {
+        Ok(()) => message(context, "Email confirmed", "Thanks for confirming your email address, you are now listed on the leaderboards."),
+        Err(err) => error(context, err),
+    }
+}
+
+// Sends the logged in user a new verification link
+#[post("/verify-email")]
+pub fn verify_email_resend(mailer: &State<Mailer>, tokens: &State<Tokens>, config: &State<Config>, limiter: &State<RateLimiter>, context: Context) -> Respondable {
+    let user = match &context.user {
+        Some(user) if user.email_verified => return Redirect::to(uri!(super::user::user_self)).into(),
+        Some(user) => user.clone(),
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    let sent = limiter
+        .check_account(RouteGroup::Auth, &user.username)
+        .and_then(|()| send_verification(mailer, tokens, config, &user));
+    match sent {
+        Ok(()) => message(context, "Check your email", "We sent a new link to your email address.").into(),
+        Err(err) => error(context, err).into(),
+    }
+}
+
+#[get("/reset-password")]
+pub fn reset_password(context: Context) -> Template {
+    Template::render("reset_password", &context)
+}
+
+#[post("/reset-password", data = "<form>")]
+pub fn reset_password_post(db: &State<Db>, mailer: &State<Mailer>, tokens: &State<Tokens>, config: &State<Config>, limiter: &State<RateLimiter>, form: Form<ResetRequest>, context: Context) -> Result<Template, DatastoreError> {
+    // Limited by address rather than account, so it doesn't tell whether the address is known
+    limiter.check_account(RouteGroup::Auth, &form.email.trim().to_lowercase())?;
+    // The answer is the same whether the email is known or not, so it can't be used
+    // to find out who has an account
+    if let Ok(user) = db.get_user_by_email(form.email.trim()) {
+        if let Err(err) = send_reset(mailer, tokens, config, &user) {
+            log::error!("Failed to send password reset email: {}", err);
+        }
+    }
+    Ok(message(context, "Check your email", "If an account uses that address, we sent it a link to reset its password."))
+}
+
+#[get("/reset-password/confirm?<token>")]
+pub fn reset_password_confirm(db: &State<Db>, tokens: &State<Tokens>, token: &str, mut context: Context) -> Template {
+    match tokens.verify_link(token, Purpose::ResetPassword, |id| db.get_user_by_id(id)) {
+        Ok(_) => {
+            context.requested = Some(serde_json::json!({ "token": token }));
+            Template::render("reset_password", &context)
+        }
+        Err(err) => error(context, err),
+    }
+}
+
+#[post("/reset-password/confirm", data = "<form>")]
+pub fn reset_password_confirm_post(db: &State<Db>, tokens: &State<Tokens>, form: Form<NewPassword>, context: Context) -> Respondable {
+    let reset = tokens
+        .verify_link(&form.token, Purpose::ResetPassword, |id| db.get_user_by_id(id))
+        .and_then(|user| {
+            check_password(&form.password, &user.username, &user.email)
+                .map_err(|err| DatastoreError::BadRequest(format!("password {}", err)))?;
+            db.set_password(user.id, &form.password)?;
+            // Whoever knew the old password is logged out, and the email was just
+            // shown to belong to the user
+            db.revoke_other_sessions(user.id, None)?;
+            db.set_email_verified(user.id)
+        });
+    match reset {
+        Ok(()) => Redirect::to(uri!(super::auth::login)).into(),
+        Err(err) => error(context, err).into(),
+    }
+}
+
+// Deletes the account of a user and logs them out everywhere. With a grace period the
+// account is only removed once it's over, returning when that is.
+fn delete_account(db: &Db, config: &Config, user: &db::User) -> Result<Option<DateTime<Utc>>, DatastoreError> {
+    if config.account_deletion_grace_days == 0 {
+        db.delete_user(user.id)?;
+        return Ok(None);
+    }
+    let due = Utc::now() + chrono::Duration::days(config.account_deletion_grace_days.into());
+    db.schedule_user_deletion(user.id, Some(due))?;
+    db.revoke_other_sessions(user.id, None)?;
+    Ok(Some(due))
+}
+
+#[get("/account/delete")]
+pub fn delete_account_page(config: &State<Config>, mut context: Context) -> Respondable {
+    if context.user.is_none() {
+        return Redirect::to(uri!(super::auth::login)).into();
+    }
+    context.requested = Some(serde_json::json!({ "grace_days": config.account_deletion_grace_days }));
+    Template::render("delete_account", &context).into()
+}
+
+#[post("/account/delete", data = "<form>")]
+pub fn delete_account_post(db: &State<Db>, config: &State<Config>, form: Form<DeleteConfirmation>, context: Context, cookies: &CookieJar) -> Respondable {
+    let user = match &context.user {
+        Some(user) => user.clone(),
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    if form.username.trim() != user.username {
+        return error(context, DatastoreError::BadRequest("type your username to confirm".to_string())).into();
+    }
+    let due = match delete_account(db, config, &user) {
+        Ok(due) => due,
+        Err(err) => return error(context, err).into(),
+    };
+    cookies.remove_private(Cookie::named("jwt"));
+    let context = Context::default();
+    match due {
+        Some(due) => message(
+            context,
+            "Account deleted",
+            &format!("Your account will be removed for good on {}. Log in before then if you change your mind.", due.format("%Y-%m-%d")),
+        )
+        .into(),
+        None => message(context, "Account deleted", "Your account and all its data were removed.").into(),
+    }
+}
+
+// Deleting an account takes a login, API keys can't do it
+#[delete("/account")]
+pub fn account_delete(db: &State<Db>, config: &State<Config>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<DeletionResponse>, HttpErrorJson> {
+    let auth = auth?;
+    if auth.session_id.is_none() {
+        return Err(DatastoreError::Forbidden("API keys can't delete accounts".to_string()).into());
+    }
+    let deletion_due = delete_account(db, config, &auth.user)?;
+    Ok(Json(DeletionResponse { deletion_due }))
+}
@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use rocket::{State, serde::json::Json, http::Status};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::{Db, self};
+use crate::endpoints::util::{ApiUser, HttpErrorJson};
+use crate::error::DatastoreError;
+use crate::ratelimit::{RateLimiter, RouteGroup};
+use crate::validation::sanitize_events;
+
+// Upper bound on hours per batch, a month of hourly reports fits comfortably
+const MAX_BATCH_SIZE: usize = 1000;
+
+// An hour of activity as reported by a device
+#[derive(Deserialize)]
+pub struct ActivityReport {
+    device_id: Uuid,
+    ruleset_id: i64,
+    hour: DateTime<Utc>,
+    events: Vec<db::Event>,
+    #[serde(default)]
+    mode: db::ReportMode,
+}
+
+#[derive(Serialize)]
+pub struct ReportResponse {
+    outcome: db::ReportOutcome,
+}
+
+// Many hours of activity for a single device
+#[derive(Deserialize)]
+pub struct BatchReport {
+    device_id: Uuid,
+    ruleset_id: i64,
+    reports: Vec<HourReport>,
+    #[serde(default)]
+    mode: db::ReportMode,
+}
+
+#[derive(Deserialize)]
+pub struct HourReport {
+    hour: DateTime<Utc>,
+    events: Vec<db::Event>,
+}
+
+// Result for a single hour of a batch, either an outcome or the reason it was rejected
+#[derive(Serialize)]
+pub struct BatchItemResult {
+    hour: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<db::ReportOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    results: Vec<BatchItemResult>,
+}
+
+// Checks that the caller may report activity for the device using the ruleset
+fn check_report_target(db: &Db, auth: &ApiUser, device_id: &Uuid, ruleset_id: i64) -> Result<(), DatastoreError> {
+    let device = db.get_device(device_id)?;
+    auth.check_device(&device)?;
+    let ruleset = db.get_ruleset(ruleset_id)?;
+    if ruleset.user_id != auth.user.id {
+        return Err(DatastoreError::Forbidden("ruleset belongs to another user".to_string()));
+    }
+    Ok(())
+}
+
+#[post("/activity", format = "json", data = "<report>")]
+pub fn activity_post(db: &State<Db>, config: &State<Config>, limiter: &State<RateLimiter>, report: Json<ActivityReport>, auth: Result<ApiUser, DatastoreError>) -> Result<(Status, Json<ReportResponse>), HttpErrorJson> {
+    let auth = auth?;
+    limiter.check_account(RouteGroup::Ingest, &auth.user.id.to_string())?;
+    let report = report.into_inner();
+
+    check_report_target(db, &auth, &report.device_id, report.ruleset_id)?;
+    let events = sanitize_events(report.hour, report.events, config.event_policy)?;
+
+    let outcome = db.report_activity(&report.device_id, report.ruleset_id, report.hour, events, report.mode)?;
+    let status = match outcome {
+        db::ReportOutcome::Created => Status::Created,
+        _ => Status::Ok,
+    };
+    Ok((status, Json(ReportResponse { outcome })))
+}
+
+#[post("/activity/batch", format = "json", data = "<batch>")]
+pub fn activity_batch_post(db: &State<Db>, config: &State<Config>, limiter: &State<RateLimiter>, batch: Json<BatchReport>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<BatchResponse>, HttpErrorJson> {
+    let auth = auth?;
+    limiter.check_account(RouteGroup::Ingest, &auth.user.id.to_string())?;
+    let batch = batch.into_inner();
+    if batch.reports.len() > MAX_BATCH_SIZE {
+        return Err(DatastoreError::BadRequest(format!("at most {} hours per batch", MAX_BATCH_SIZE)).into());
+    }
+
+    check_report_target(db, &auth, &batch.device_id, batch.ruleset_id)?;
+
+    // Reject invalid hours up front, the rest are written together
+    let mut results = Vec::with_capacity(batch.reports.len());
+    let mut accepted = Vec::new();
+    for (i, report) in batch.reports.into_iter().enumerate() {
+        results.push(BatchItemResult { hour: report.hour, outcome: None, error: None });
+        match sanitize_events(report.hour, report.events, config.event_policy) {
+            Ok(events) => accepted.push((i, (report.hour, events))),
+            Err(err) => results[i].error = Some(err.to_string()),
+        }
+    }
+
+    let (indices, reports): (Vec<usize>, Vec<_>) = accepted.into_iter().unzip();
+    let outcomes = db.report_activity_batch(&batch.device_id, batch.ruleset_id, reports, batch.mode)?;
+    for (i, outcome) in indices.into_iter().zip(outcomes) {
+        match outcome {
+            Ok(outcome) => results[i].outcome = Some(outcome),
+            Err(err) => results[i].error = Some(err.to_string()),
+        }
+    }
+    Ok(Json(BatchResponse { results }))
+}
@@ -1,12 +1,24 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
-use rocket::{response::Redirect, form::Form, State, http::{Cookie, CookieJar}};
+use rocket::{response::{status::Custom, Redirect}, form::Form, State, http::{Cookie, CookieJar, SameSite, Status}, time};
 use rocket_dyn_templates::Template;
 use serde::{Serialize, Deserialize};
-use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 
-use crate::db::Db;
+use chrono::Utc;
+
+use crate::config::Config;
+use crate::db::{Db, self};
+use crate::endpoints::account::send_verification;
+use crate::endpoints::two_factor::{check_second_factor, Code};
+use crate::endpoints::util::{ClientInfo, Context};
+use crate::endpoints::Respondable;
 use crate::error::DatastoreError;
+use crate::leaderboard::Window;
+use crate::mailer::Mailer;
+use crate::oauth::OAuth;
+use crate::ratelimit::{RateLimiter, RouteGroup};
+use crate::tokens::Tokens;
+use crate::validation::check_signup;
 
 #[derive(FromForm)]
 pub struct Login {
@@ -14,6 +26,17 @@ pub struct Login {
     password: String,
 }
 
+// A login waiting for the second factor, kept in a private cookie
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    user_id: i64,
+    expires: i64,
+}
+
+const PENDING_LOGIN_COOKIE: &str = "login_2fa";
+// How long users have to enter the code from their authenticator app
+const PENDING_LOGIN_MINUTES: i64 = 5;
+
 #[derive(FromForm)]
 pub struct Signup {
     username: String,
@@ -21,63 +44,156 @@ pub struct Signup {
     password: String,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Claims {
-    sub: String,
-    company: String,
+// The signup form, filled in again with what was entered (but the password) and what
+// was wrong with it
+fn signup_page(form: Option<&Signup>, errors: BTreeMap<&str, String>, error: Option<&DatastoreError>) -> Template {
+    let context = serde_json::json!({
+        "title": "Signup",
+        "username": form.map(|form| form.username.as_str()).unwrap_or_default(),
+        "email": form.map(|form| form.email.as_str()).unwrap_or_default(),
+        "errors": errors,
+        "error": error.map(|err| err.to_string()),
+    });
+    Template::render("signup", &context)
 }
 
 #[get("/signup")]
 pub fn signup() -> Template {
-    let mut context = HashMap::new();
-    context.insert("title", "Signup");
-    Template::render("signup", &context)
+    signup_page(None, BTreeMap::new(), None)
 }
 
 #[post("/signup", data = "<user_form>")]
-pub fn signup_post(db: &State<Db>, user_form: Form<Signup>) -> Result<Redirect, DatastoreError> {
-    match db.add_user(&user_form.username, &user_form.email, &user_form.password) {
-        Ok(_) => Ok(Redirect::to(uri!(super::user::user(user_form.username.to_string())))),
-        Err(_) => Err(DatastoreError::UserAlreadyExists { username: user_form.username.to_string() }),
+pub fn signup_post(db: &State<Db>, mailer: &State<Mailer>, tokens: &State<Tokens>, config: &State<Config>, user_form: Form<Signup>) -> Result<Redirect, Box<Custom<Template>>> {
+    let username = user_form.username.trim();
+    let email = user_form.email.trim();
+    let errors = check_signup(username, email, &user_form.password);
+    if !errors.is_empty() {
+        return Err(Box::new(Custom(Status::UnprocessableEntity, signup_page(Some(&user_form), errors, None))));
+    }
+    let failed = |err: DatastoreError| {
+        let mut errors = BTreeMap::new();
+        match err {
+            DatastoreError::UserAlreadyExists { .. } => errors.insert("username", "is already taken".to_string()),
+            DatastoreError::EmailAlreadyExists { .. } => errors.insert("email", "already has an account, log in or reset its password".to_string()),
+            _ => None,
+        };
+        let error = if errors.is_empty() { Some(&err) } else { None };
+        Box::new(Custom(err.status(), signup_page(Some(&user_form), errors, error)))
+    };
+    db.add_user(username, email, &user_form.password).map_err(failed)?;
+    let user = db.get_user(username).map_err(failed)?;
+    if let Err(err) = send_verification(mailer, tokens, config, &user) {
+        log::error!("Failed to send verification email: {}", err);
+    }
+    Ok(Redirect::to(uri!(super::user::user(user.username, _))))
+}
+
+// Logs in a user with a new session, whose token is kept in the login cookie. Logging
+// in to an account pending deletion restores it.
+pub fn start_session(db: &Db, tokens: &Tokens, user: &db::User, client: &ClientInfo, cookies: &CookieJar) -> Result<(), DatastoreError> {
+    if user.deletion_due.is_some() {
+        db.schedule_user_deletion(user.id, None)?;
+        log::info!("Restored user {} pending deletion", user.id);
     }
+    let session = db.create_session(user.id, Utc::now() + tokens.expiry, client.user_agent.as_deref(), client.ip.as_deref())?;
+    let token = tokens.issue(user.id, &session.id);
+    let max_age = time::Duration::seconds(tokens.expiry.num_seconds());
+    cookies.add_private(Cookie::build("jwt", token).max_age(max_age).finish());
+    Ok(())
+}
+
+// Logs in a user who passed the first factor, sending users with two-factor
+// authentication on to enter their code first
+pub fn finish_login(db: &Db, tokens: &Tokens, user: &db::User, client: &ClientInfo, cookies: &CookieJar) -> Result<Redirect, DatastoreError> {
+    if db.get_totp(user.id)?.is_some_and(|totp| totp.enabled) {
+        let pending = PendingLogin {
+            user_id: user.id,
+            expires: (Utc::now() + chrono::Duration::minutes(PENDING_LOGIN_MINUTES)).timestamp(),
+        };
+        // Lax, for logins coming back from an OAuth provider
+        let cookie = Cookie::build(PENDING_LOGIN_COOKIE, serde_json::to_string(&pending).unwrap())
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::minutes(PENDING_LOGIN_MINUTES))
+            .finish();
+        cookies.add_private(cookie);
+        return Ok(Redirect::to(uri!(login_2fa)));
+    }
+    start_session(db, tokens, user, client, cookies)?;
+    Ok(Redirect::to(uri!(super::user::user(user.username.clone(), _))))
+}
+
+fn pending_login(cookies: &CookieJar) -> Option<PendingLogin> {
+    cookies
+        .get_private(PENDING_LOGIN_COOKIE)
+        .and_then(|cookie| serde_json::from_str::<PendingLogin>(cookie.value()).ok())
+        .filter(|pending| pending.expires > Utc::now().timestamp())
 }
 
 #[get("/login")]
-pub fn login() -> Template {
-    let mut context = HashMap::new();
-    context.insert("title", "Login");
+pub fn login(oauth: &State<OAuth>) -> Template {
+    let context = serde_json::json!({
+        "title": "Login",
+        "providers": oauth.names(),
+    });
     Template::render("login", &context)
 }
 
 #[post("/login", data = "<login_form>")]
-pub fn login_post(db: &State<Db>, login_form: Form<Login>, cookies: &CookieJar) -> Result<Redirect, String> {
-    match db.check_password(&login_form.username, &login_form.password) {
-        Ok(true) => {
-            let my_claims = Claims {
-                sub: login_form.username.to_owned(),
-                company: "ACME".to_owned(),
-            };
-            // TODO: set to something actually secret
-            let key = b"secret";
-            let token = encode(
-                &Header::new(Algorithm::HS256),
-                &my_claims,
-                &EncodingKey::from_secret(key),
-            )
-            .unwrap();
-
-            cookies.add_private(Cookie::new("user_id", login_form.username.to_string()));
-            cookies.add_private(Cookie::new("jwt", token));
-
-            Ok(Redirect::to(uri!(super::user::user(login_form.username.to_string()))))
+pub fn login_post(db: &State<Db>, tokens: &State<Tokens>, limiter: &State<RateLimiter>, login_form: Form<Login>, client: ClientInfo, cookies: &CookieJar) -> Result<Redirect, DatastoreError> {
+    let username = &login_form.username;
+    limiter.check_account(RouteGroup::Auth, username)?;
+    limiter.check_lockout(username)?;
+    match db.check_password(username, &login_form.password)? {
+        true => {
+            let user = db.get_user(username)?;
+            // With two-factor authentication, failures are only forgiven once the code is right too
+            if !db.get_totp(user.id)?.is_some_and(|totp| totp.enabled) {
+                limiter.login_succeeded(username);
+            }
+            finish_login(db, tokens, &user, &client, cookies)
+        }
+        false => {
+            // Made up usernames are locked out like real ones, so lockouts don't tell which exist
+            limiter.login_failed(username);
+            Err(DatastoreError::Unauthorized("invalid user or password".to_string()))
         }
-        _ => Err("Invalid user or password".to_string()),
     }
 }
 
+// Second step of logging in, for users with two-factor authentication
+#[get("/login/2fa")]
+pub fn login_2fa(context: Context, cookies: &CookieJar) -> Respondable {
+    match pending_login(cookies) {
+        Some(_) => Template::render("login_2fa", &context).into(),
+        None => Redirect::to(uri!(login)).into(),
+    }
+}
+
+#[post("/login/2fa", data = "<form>")]
+pub fn login_2fa_post(db: &State<Db>, tokens: &State<Tokens>, limiter: &State<RateLimiter>, form: Form<Code>, client: ClientInfo, cookies: &CookieJar, mut context: Context) -> Result<Respondable, DatastoreError> {
+    let pending = match pending_login(cookies) {
+        Some(pending) => pending,
+        None => return Ok(Redirect::to(uri!(login)).into()),
+    };
+    let user = db.get_user_by_id(pending.user_id)?;
+    limiter.check_account(RouteGroup::Auth, &user.username)?;
+    limiter.check_lockout(&user.username)?;
+    if let Err(err) = check_second_factor(db, user.id, &form.code) {
+        limiter.login_failed(&user.username);
+        context.error = Some(err.to_string());
+        return Ok(Template::render("login_2fa", &context).into());
+    }
+    limiter.login_succeeded(&user.username);
+    cookies.remove_private(Cookie::named(PENDING_LOGIN_COOKIE));
+    start_session(db, tokens, &user, &client, cookies)?;
+    Ok(Redirect::to(uri!(super::user::user(user.username, _))).into())
+}
+
 #[get("/logout")]
-pub fn logout(cookies: &CookieJar) -> Redirect {
-    cookies.remove_private(Cookie::named("user_id"));
+pub fn logout(db: &State<Db>, context: Context, cookies: &CookieJar) -> Redirect {
+    if let (Some(user), Some(session)) = (context.user, context.session) {
+        db.revoke_session(user.id, &session.id).ok();
+    }
     cookies.remove_private(Cookie::named("jwt"));
     Redirect::to(uri!(super::home))
 }
\ No newline at end of file
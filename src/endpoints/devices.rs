@@ -1,28 +1,57 @@
-use rocket::{State, serde::json::Json, http::Status};
+use rocket::{State, serde::json::Json, http::Status, response::Redirect};
 use serde::{Serialize, Deserialize};
 use uuid::Uuid;
 
-use crate::{db, endpoints::util::Context, db::Db};
+use crate::{db, endpoints::util::{error, ApiUser, Context, HttpErrorJson}, db::Db};
+use crate::endpoints::Respondable;
+use crate::error::DatastoreError;
 
+// A new device, added to the authenticated user
 #[derive(Serialize, Deserialize)]
 pub struct Device {
     id: Uuid,
-    user_id: i32,
     name: String,
 }
 
-#[get("/api/devices")]
-pub fn device(db: &State<Db>, context: Context) -> Json<Vec<db::Device>> {
-    Json(db.get_devices(context.user.unwrap().id).unwrap())
+#[get("/devices")]
+pub fn device(db: &State<Db>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<Vec<db::Device>>, HttpErrorJson> {
+    let auth = auth?;
+    let devices = db.get_devices(auth.user.id)?;
+    Ok(Json(devices.into_iter().filter(|d| auth.check_device(d).is_ok()).collect()))
 }
 
-#[post("/api/devices", format = "json", data = "<device>")]
-pub fn device_post(db: &State<Db>, device: Json<Device>, context: Context) -> Status {
-    match db.add_device(context.user.unwrap().id, device.id, &device.name) {
-        Ok(_) => Status::Created,
-        Err(err) => {
-            println!("Error adding device: {}", err);
-            Status::BadRequest
+#[post("/devices", format = "json", data = "<device>")]
+pub fn device_post(db: &State<Db>, device: Json<Device>, auth: Result<ApiUser, DatastoreError>) -> Result<Status, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    db.add_device(auth.user.id, device.id, &device.name)?;
+    Ok(Status::Created)
+}
+
+// Removes a device with all its activity, its time leaves the leaderboards too
+#[delete("/devices/<id>")]
+pub fn device_delete(db: &State<Db>, id: Uuid, auth: Result<ApiUser, DatastoreError>) -> Result<Status, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    auth.check_device(&db.get_device(&id)?)?;
+    db.delete_device(&id)?;
+    Ok(Status::NoContent)
+}
+
+#[post("/devices/<id>/delete")]
+pub fn device_delete_post(db: &State<Db>, id: Uuid, context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    let deleted = db.get_device(&id).and_then(|device| {
+        if device.user_id != user_id {
+            return Err(DatastoreError::Forbidden("device belongs to another user".to_string()));
         }
+        db.delete_device(&id)
+    });
+    match deleted {
+        Ok(()) => Redirect::to(uri!(super::user::user_self)).into(),
+        Err(err) => error(context, err).into(),
     }
-}
\ No newline at end of file
+}
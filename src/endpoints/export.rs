@@ -0,0 +1,32 @@
+use rocket::{http::{Header, Status}, State};
+use rocket::tokio::{fs::File, task::spawn_blocking};
+
+use crate::db::Db;
+use crate::endpoints::util::{ApiUser, HttpErrorJson};
+use crate::error::DatastoreError;
+use crate::export::export_to_file;
+
+// The archive is streamed from its temporary file rather than read into memory
+#[derive(Responder)]
+#[response(content_type = "application/zip")]
+pub struct Export {
+    file: File,
+    disposition: Header<'static>,
+}
+
+// Downloads everything stored about the caller, see export::write_export
+#[get("/export")]
+pub async fn export(db: &State<Db>, auth: Result<ApiUser, DatastoreError>) -> Result<Export, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    let filename = format!("aw-leaderboard-{}.zip", auth.user.username);
+    // Writing the archive blocks for as long as the history takes, so it runs on a thread of its own
+    let (db, user) = (db.inner().clone(), auth.user);
+    let file = spawn_blocking(move || export_to_file(&db, &user))
+        .await
+        .map_err(|err| HttpErrorJson::new(Status::InternalServerError, format!("export failed: {}", err)))??;
+    Ok(Export {
+        file: File::from_std(file),
+        disposition: Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", filename)),
+    })
+}
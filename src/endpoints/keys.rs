@@ -0,0 +1,47 @@
+use rocket::{State, serde::json::Json, http::Status};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::db::{Db, self};
+use crate::endpoints::util::{ApiUser, HttpErrorJson};
+use crate::error::DatastoreError;
+
+#[derive(Deserialize)]
+pub struct NewApiKey {
+    name: String,
+    // Restricts the key to a single device
+    device_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    key: db::ApiKey,
+    // Only returned once, the server only keeps a hash
+    secret: String,
+}
+
+#[get("/keys")]
+pub fn keys(db: &State<Db>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<Vec<db::ApiKey>>, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    Ok(Json(db.get_api_keys(auth.user.id)?))
+}
+
+#[post("/keys", format = "json", data = "<new_key>")]
+pub fn keys_post(db: &State<Db>, new_key: Json<NewApiKey>, auth: Result<ApiUser, DatastoreError>) -> Result<(Status, Json<CreatedApiKey>), HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    if let Some(device_id) = &new_key.device_id {
+        auth.check_device(&db.get_device(device_id)?)?;
+    }
+    let (key, secret) = db.create_api_key(auth.user.id, new_key.device_id.as_ref(), &new_key.name)?;
+    Ok((Status::Created, Json(CreatedApiKey { key, secret })))
+}
+
+#[delete("/keys/<id>")]
+pub fn keys_delete(db: &State<Db>, id: i64, auth: Result<ApiUser, DatastoreError>) -> Result<Status, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    db.revoke_api_key(auth.user.id, id)?;
+    Ok(Status::NoContent)
+}
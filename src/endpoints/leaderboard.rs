@@ -0,0 +1,18 @@
+use rocket::{State, serde::json::Json};
+
+use crate::db::Db;
+use crate::endpoints::util::{ApiUser, HttpErrorJson};
+use crate::leaderboard::{CategoryTotal, LeaderboardEntry, Window};
+use crate::privacy::Profile;
+
+#[get("/leaderboard?<category>&<window>")]
+pub fn leaderboard(db: &State<Db>, category: Option<&str>, window: Option<Window>) -> Result<Json<Vec<LeaderboardEntry>>, HttpErrorJson> {
+    let category = category.filter(|c| !c.is_empty());
+    Ok(Json(crate::leaderboard::leaderboard(db, category, window.unwrap_or_default())?))
+}
+
+#[get("/users/<username>/categories?<window>")]
+pub fn user_categories(db: &State<Db>, username: &str, window: Option<Window>, auth: Option<ApiUser>) -> Result<Json<Vec<CategoryTotal>>, HttpErrorJson> {
+    let profile = Profile::view(db, username, auth.as_ref().map(|auth| &auth.user))?;
+    Ok(Json(profile.category_totals(db, window.unwrap_or_default())?))
+}
@@ -7,6 +7,16 @@ pub mod util;
 pub mod user;
 pub mod auth;
 pub mod devices;
+pub mod activity;
+pub mod keys;
+pub mod leaderboard;
+pub mod rulesets;
+pub mod sessions;
+pub mod oauth;
+pub mod account;
+pub mod two_factor;
+pub mod privacy;
+pub mod export;
 
 #[derive(Responder)]
 pub enum Respondable {
@@ -42,9 +52,21 @@ pub fn mount(rocket: Rocket<Build>) -> Rocket<Build> {
         // Index & assets
         .mount("/", routes![home])
         // Auth
-        .mount("/", routes![auth::login, auth::login_post, auth::signup, auth::signup_post, auth::logout])
+        .mount("/", routes![auth::login, auth::login_post, auth::signup, auth::signup_post, auth::logout, auth::login_2fa, auth::login_2fa_post])
+        .mount("/", routes![oauth::oauth_login, oauth::oauth_callback])
+        .mount("/", routes![two_factor::two_factor, two_factor::two_factor_setup, two_factor::two_factor_enable, two_factor::two_factor_disable, two_factor::two_factor_recovery_codes])
+        .mount("/", routes![account::verify_email, account::verify_email_resend, account::reset_password, account::reset_password_post, account::reset_password_confirm, account::reset_password_confirm_post])
+        .mount("/", routes![account::delete_account_page, account::delete_account_post, devices::device_delete_post])
         // Views
         .mount("/", routes![user::user, user::user_self, user::users])
+        .mount("/", routes![sessions::sessions_page, sessions::sessions_revoke, sessions::sessions_revoke_others])
+        .mount("/", routes![privacy::privacy_page, privacy::privacy_post, privacy::privacy_categories_post, privacy::retention_post])
         // API
-        .mount("/api", routes![devices::device, devices::device_post]);
+        .mount("/api", routes![devices::device, devices::device_post, devices::device_delete, activity::activity_post, activity::activity_batch_post])
+        .mount("/api", routes![keys::keys, keys::keys_post, keys::keys_delete])
+        .mount("/api", routes![leaderboard::leaderboard, leaderboard::user_categories])
+        .mount("/api", routes![rulesets::rulesets, rulesets::ruleset, rulesets::ruleset_post, rulesets::ruleset_put, rulesets::ruleset_delete, rulesets::ruleset_import])
+        .mount("/api", routes![sessions::sessions, sessions::sessions_delete])
+        .mount("/api", routes![privacy::privacy, privacy::privacy_put, privacy::retention, privacy::retention_put])
+        .mount("/api", routes![export::export, account::account_delete]);
 }
\ No newline at end of file
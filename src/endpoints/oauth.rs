@@ -0,0 +1,108 @@
+use rocket::{response::Redirect, State, http::{Cookie, CookieJar, SameSite}, time};
+
+use crate::config::Config;
+use crate::db::{Db, self};
+use crate::endpoints::account::send_verification;
+use crate::endpoints::auth::finish_login;
+use crate::endpoints::util::{ClientInfo, Context};
+use crate::error::DatastoreError;
+use crate::leaderboard::Window;
+use crate::mailer::Mailer;
+use crate::oauth::{Identity, OAuth, PendingLogin};
+use crate::tokens::Tokens;
+use crate::validation::sanitize_username;
+
+const PENDING_COOKIE: &str = "oauth_login";
+
+// New account for someone logging in with a provider for the first time, named after
+// their account there. Its password is random, so it can only be logged in to through
+// the provider.
+fn create_user(db: &Db, provider: &str, identity: &Identity) -> Result<db::User, DatastoreError> {
+    let email = identity.email.as_deref().ok_or_else(|| {
+        DatastoreError::BadRequest(format!("your {} account has no public email address", provider))
+    })?;
+    let base = sanitize_username(&identity.username);
+    let mut username = base.clone();
+    let mut n = 1;
+    while db.get_user(&username).is_ok() {
+        n += 1;
+        username = format!("{}{}", base, n);
+    }
+    let password = hex::encode(rand::random::<[u8; 32]>());
+    db.add_user(&username, email, &password).map_err(|err| match err {
+        DatastoreError::EmailAlreadyExists { .. } => {
+            DatastoreError::Conflict(format!("the email of your {} account is already in use, log in to link it", provider))
+        }
+        err => err,
+    })?;
+    let user = db.get_user(&username)?;
+    db.link_oauth_identity(user.id, provider, &identity.subject)?;
+    Ok(user)
+}
+
+// Sends the user off to log in at the provider
+#[get("/oauth/<provider>")]
+pub fn oauth_login(oauth: &State<OAuth>, provider: &str, cookies: &CookieJar) -> Result<Redirect, DatastoreError> {
+    let (url, pending) = oauth.provider(provider)?.authorize(provider);
+    // Lax, as the cookie has to come along when the provider redirects back
+    let cookie = Cookie::build(PENDING_COOKIE, serde_json::to_string(&pending).unwrap())
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(10))
+        .finish();
+    cookies.add_private(cookie);
+    Ok(Redirect::to(url))
+}
+
+// Where the provider sends the user back to. Links the provider account to the logged in
+// user, or else logs in the user it is linked to, signing up a new user if there is none.
+#[allow(clippy::too_many_arguments)]
+#[get("/oauth/<provider>/callback?<code>&<state>")]
+pub async fn oauth_callback(
+    db: &State<Db>,
+    tokens: &State<Tokens>,
+    oauth: &State<OAuth>,
+    mailer: &State<Mailer>,
+    config: &State<Config>,
+    provider: &str,
+    code: Option<String>,
+    state: Option<String>,
+    context: Context,
+    client: ClientInfo,
+    cookies: &CookieJar<'_>,
+) -> Result<Redirect, DatastoreError> {
+    let pending = cookies
+        .get_private(PENDING_COOKIE)
+        .and_then(|cookie| serde_json::from_str::<PendingLogin>(cookie.value()).ok());
+    cookies.remove_private(Cookie::named(PENDING_COOKIE));
+    let pending = match (pending, state) {
+        (Some(pending), Some(state)) if pending.provider == provider && pending.state == state => pending,
+        _ => return Err(DatastoreError::Unauthorized("login was not started here or has expired".to_string())),
+    };
+    // Providers leave out the code if the user declined
+    let code = code.ok_or_else(|| DatastoreError::Unauthorized(format!("{} login was cancelled", provider)))?;
+    let identity = oauth.provider(provider)?.identity(code, pending).await?;
+
+    let logged_in = context.user.is_some();
+    let user = match (db.get_oauth_user(provider, &identity.subject), context.user) {
+        (Ok(linked), Some(user)) if linked.id != user.id => {
+            return Err(DatastoreError::Conflict(format!("{} account is linked to another user", provider)));
+        }
+        (Ok(linked), _) => linked,
+        (Err(DatastoreError::NotFound(_)), Some(user)) => {
+            db.link_oauth_identity(user.id, provider, &identity.subject)?;
+            user
+        }
+        (Err(DatastoreError::NotFound(_)), None) => {
+            let user = create_user(db, provider, &identity)?;
+            if let Err(err) = send_verification(mailer, tokens, config, &user) {
+                log::error!("Failed to send verification email: {}", err);
+            }
+            user
+        }
+        (Err(err), _) => return Err(err),
+    };
+    if logged_in {
+        return Ok(Redirect::to(uri!(super::user::user(user.username, _))));
+    }
+    finish_login(db, tokens, &user, &client, cookies)
+}
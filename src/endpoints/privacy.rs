@@ -0,0 +1,212 @@
+use std::collections::BTreeSet;
+
+use rocket::{form::Form, response::Redirect, serde::json::Json, State};
+use rocket_dyn_templates::Template;
+use serde::{Serialize, Deserialize};
+
+use crate::categories::PATH_SEPARATOR;
+use crate::config::Config;
+use crate::db::Db;
+use crate::endpoints::{util::{error, ApiUser, Context, HttpErrorJson}, Respondable};
+use crate::error::DatastoreError;
+use crate::leaderboard::{category_totals, Window};
+use crate::privacy::{CategorySharing, Privacy, Visibility};
+use crate::retention::{check_retention_days, RetentionAction};
+
+#[derive(FromForm)]
+pub struct ProfileSettings {
+    visibility: Visibility,
+    share_devices: bool,
+    share_categories: bool,
+}
+
+#[derive(FromFormField)]
+enum Sharing {
+    Share,
+    Hide,
+    // Follows the parent category, or the default of the profile
+    Inherit,
+}
+
+#[derive(FromForm)]
+pub struct CategorySettings {
+    category: String,
+    sharing: Sharing,
+}
+
+#[derive(FromForm)]
+pub struct RetentionSettings {
+    // Empty for the default of the server
+    days: String,
+}
+
+// How long the hourly activity of a user is kept
+#[derive(Serialize, Deserialize)]
+pub struct RetentionInfo {
+    // None for the default of the server
+    days: Option<u32>,
+    #[serde(skip_deserializing)]
+    default_days: Option<u32>,
+    #[serde(skip_deserializing)]
+    action: RetentionAction,
+}
+
+fn retention_info(db: &Db, config: &Config, user_id: i64) -> Result<RetentionInfo, DatastoreError> {
+    Ok(RetentionInfo {
+        days: db.get_retention(user_id)?,
+        default_days: config.retention.days,
+        action: config.retention.action,
+    })
+}
+
+#[derive(Serialize)]
+struct CategoryRow {
+    name: String,
+    // Setting of the category itself: share, hide or inherit
+    sharing: &'static str,
+    // Whether others see it, after inheriting from its parents
+    visible: bool,
+}
+
+// Every category the user has time in, along with those that have a setting
+fn category_rows(db: &Db, user_id: i64, privacy: &Privacy) -> Result<Vec<CategoryRow>, DatastoreError> {
+    let mut names: BTreeSet<String> = category_totals(db, user_id, Window::All, |_| true)?
+        .into_iter()
+        .map(|total| total.name.join(PATH_SEPARATOR))
+        .collect();
+    names.extend(privacy.categories.iter().map(|setting| setting.category.clone()));
+    Ok(names
+        .into_iter()
+        .map(|name| CategoryRow {
+            sharing: match privacy.categories.iter().find(|setting| setting.category == name) {
+                Some(setting) if setting.shared => "share",
+                Some(_) => "hide",
+                None => "inherit",
+            },
+            visible: privacy.shares(&name),
+            name,
+        })
+        .collect())
+}
+
+#[get("/privacy")]
+pub fn privacy_page(db: &State<Db>, config: &State<Config>, mut context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    let page = db.get_privacy(user_id).and_then(|privacy| {
+        let categories = category_rows(db, user_id, &privacy)?;
+        let retention = retention_info(db, config, user_id)?;
+        Ok(serde_json::json!({ "privacy": privacy, "categories": categories, "retention": retention }))
+    });
+    match page {
+        Ok(page) => {
+            context.requested = Some(page);
+            Template::render("privacy", &context).into()
+        }
+        Err(err) => error(context, err).into(),
+    }
+}
+
+#[post("/privacy", data = "<form>")]
+pub fn privacy_post(db: &State<Db>, form: Form<ProfileSettings>, context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    let updated = db.get_privacy(user_id).and_then(|privacy| {
+        let privacy = Privacy {
+            visibility: form.visibility,
+            share_devices: form.share_devices,
+            share_categories: form.share_categories,
+            ..privacy
+        };
+        db.set_privacy(user_id, &privacy)
+    });
+    match updated {
+        Ok(()) => Redirect::to(uri!(privacy_page)).into(),
+        Err(err) => error(context, err).into(),
+    }
+}
+
+#[post("/privacy/categories", data = "<form>")]
+pub fn privacy_categories_post(db: &State<Db>, form: Form<CategorySettings>, context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    let category = form.category.trim();
+    if category.is_empty() {
+        return error(context, DatastoreError::BadRequest("category can't be empty".to_string())).into();
+    }
+    let updated = db.get_privacy(user_id).and_then(|mut privacy| {
+        privacy.categories.retain(|setting| setting.category != category);
+        match form.sharing {
+            Sharing::Share => privacy.categories.push(CategorySharing { category: category.to_string(), shared: true }),
+            Sharing::Hide => privacy.categories.push(CategorySharing { category: category.to_string(), shared: false }),
+            Sharing::Inherit => {}
+        }
+        db.set_privacy(user_id, &privacy)
+    });
+    match updated {
+        Ok(()) => Redirect::to(uri!(privacy_page)).into(),
+        Err(err) => error(context, err).into(),
+    }
+}
+
+#[post("/retention", data = "<form>")]
+pub fn retention_post(db: &State<Db>, form: Form<RetentionSettings>, context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    let days = match form.days.trim() {
+        "" => Ok(None),
+        days => days.parse().map(Some).map_err(|_| DatastoreError::BadRequest("retention must be a number of days".to_string())),
+    };
+    let updated = days.and_then(|days| {
+        days.map_or(Ok(()), check_retention_days)?;
+        db.set_retention(user_id, days)
+    });
+    match updated {
+        Ok(()) => Redirect::to(uri!(privacy_page)).into(),
+        Err(err) => error(context, err).into(),
+    }
+}
+
+#[get("/privacy")]
+pub fn privacy(db: &State<Db>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<Privacy>, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    Ok(Json(db.get_privacy(auth.user.id)?))
+}
+
+// Replaces all privacy settings, category settings included
+#[put("/privacy", format = "json", data = "<privacy>")]
+pub fn privacy_put(db: &State<Db>, privacy: Json<Privacy>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<Privacy>, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    if privacy.categories.iter().any(|setting| setting.category.trim().is_empty()) {
+        return Err(DatastoreError::BadRequest("category can't be empty".to_string()).into());
+    }
+    db.set_privacy(auth.user.id, &privacy)?;
+    Ok(Json(db.get_privacy(auth.user.id)?))
+}
+
+#[get("/retention")]
+pub fn retention(db: &State<Db>, config: &State<Config>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<RetentionInfo>, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    Ok(Json(retention_info(db, config, auth.user.id)?))
+}
+
+// Sets the retention of the caller, `{"days": null}` goes back to the default
+#[put("/retention", format = "json", data = "<retention>")]
+pub fn retention_put(db: &State<Db>, config: &State<Config>, retention: Json<RetentionInfo>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<RetentionInfo>, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    retention.days.map_or(Ok(()), check_retention_days)?;
+    db.set_retention(auth.user.id, retention.days)?;
+    Ok(Json(retention_info(db, config, auth.user.id)?))
+}
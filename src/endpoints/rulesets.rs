@@ -0,0 +1,104 @@
+use rocket::{State, serde::json::Json, http::Status};
+use serde::{Serialize, Deserialize};
+
+use crate::categories::{compile_rule, import_webui_categories, SkippedCategory, WebUiExport, PATH_SEPARATOR};
+use crate::db::{Db, self};
+use crate::endpoints::util::{ApiUser, HttpErrorJson};
+use crate::error::DatastoreError;
+
+#[derive(Deserialize)]
+pub struct RulesetForm {
+    name: String,
+    rules: Vec<db::Rule>,
+}
+
+impl RulesetForm {
+    fn validate(&self) -> Result<(), DatastoreError> {
+        if self.name.trim().is_empty() {
+            return Err(DatastoreError::BadRequest("ruleset name must not be empty".to_string()));
+        }
+        for rule in &self.rules {
+            if rule.name.is_empty() || rule.name.iter().any(|n| n.trim().is_empty()) {
+                return Err(DatastoreError::BadRequest("rule names must not be empty".to_string()));
+            }
+            if let Err(err) = compile_rule(rule) {
+                return Err(DatastoreError::BadRequest(format!(
+                    "invalid regex for rule `{}`: {}",
+                    rule.name.join(PATH_SEPARATOR),
+                    err
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct ImportResult {
+    ruleset: db::Ruleset,
+    // Categories that could not be translated into rules
+    skipped: Vec<SkippedCategory>,
+}
+
+// Fetches a ruleset, checking that it belongs to the caller
+fn get_own_ruleset(db: &Db, auth: &ApiUser, id: i64) -> Result<db::Ruleset, DatastoreError> {
+    let ruleset = db.get_ruleset(id)?;
+    if ruleset.user_id != auth.user.id {
+        return Err(DatastoreError::Forbidden("ruleset belongs to another user".to_string()));
+    }
+    Ok(ruleset)
+}
+
+#[get("/rulesets")]
+pub fn rulesets(db: &State<Db>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<Vec<db::Ruleset>>, HttpErrorJson> {
+    let auth = auth?;
+    Ok(Json(db.get_rulesets(auth.user.id)?))
+}
+
+#[get("/rulesets/<id>")]
+pub fn ruleset(db: &State<Db>, id: i64, auth: Result<ApiUser, DatastoreError>) -> Result<Json<db::Ruleset>, HttpErrorJson> {
+    let auth = auth?;
+    Ok(Json(get_own_ruleset(db, &auth, id)?))
+}
+
+#[post("/rulesets", format = "json", data = "<form>")]
+pub fn ruleset_post(db: &State<Db>, form: Json<RulesetForm>, auth: Result<ApiUser, DatastoreError>) -> Result<(Status, Json<db::Ruleset>), HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    form.validate()?;
+    let form = form.into_inner();
+    let id = db.create_ruleset(auth.user.id, &form.name, form.rules.clone())?;
+    Ok((Status::Created, Json(db::Ruleset { id, user_id: auth.user.id, name: form.name, rules: form.rules })))
+}
+
+#[put("/rulesets/<id>", format = "json", data = "<form>")]
+pub fn ruleset_put(db: &State<Db>, id: i64, form: Json<RulesetForm>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<db::Ruleset>, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    get_own_ruleset(db, &auth, id)?;
+    form.validate()?;
+    let form = form.into_inner();
+    db.update_ruleset(id, &form.name, form.rules.clone())?;
+    Ok(Json(db::Ruleset { id, user_id: auth.user.id, name: form.name, rules: form.rules }))
+}
+
+#[delete("/rulesets/<id>")]
+pub fn ruleset_delete(db: &State<Db>, id: i64, auth: Result<ApiUser, DatastoreError>) -> Result<Status, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    get_own_ruleset(db, &auth, id)?;
+    db.delete_ruleset(id)?;
+    Ok(Status::NoContent)
+}
+
+// Creates a ruleset from categories exported from the aw-webui settings
+#[post("/rulesets/import?<name>", format = "json", data = "<export>")]
+pub fn ruleset_import(db: &State<Db>, name: Option<String>, export: Json<WebUiExport>, auth: Result<ApiUser, DatastoreError>) -> Result<(Status, Json<ImportResult>), HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    let name = name.filter(|n| !n.trim().is_empty()).unwrap_or_else(|| "aw-webui categories".to_string());
+    let (rules, skipped) = import_webui_categories(export.into_inner().into_categories());
+    let id = db.create_ruleset(auth.user.id, &name, rules.clone())?;
+    let ruleset = db::Ruleset { id, user_id: auth.user.id, name, rules };
+    Ok((Status::Created, Json(ImportResult { ruleset, skipped })))
+}
@@ -0,0 +1,68 @@
+use rocket::{State, serde::json::Json, http::Status, response::Redirect};
+use rocket_dyn_templates::Template;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::db::{Db, self};
+use crate::endpoints::{util::{ApiUser, Context, HttpErrorJson}, Respondable};
+use crate::error::DatastoreError;
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    session: db::Session,
+    // Whether this is the session making the request
+    current: bool,
+}
+
+fn session_infos(db: &Db, user_id: i64, current: Option<Uuid>) -> Result<Vec<SessionInfo>, DatastoreError> {
+    Ok(db
+        .get_sessions(user_id)?
+        .into_iter()
+        .map(|session| SessionInfo { current: Some(session.id) == current, session })
+        .collect())
+}
+
+#[get("/sessions")]
+pub fn sessions(db: &State<Db>, auth: Result<ApiUser, DatastoreError>) -> Result<Json<Vec<SessionInfo>>, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    Ok(Json(session_infos(db, auth.user.id, auth.session_id)?))
+}
+
+#[delete("/sessions/<id>")]
+pub fn sessions_delete(db: &State<Db>, id: Uuid, auth: Result<ApiUser, DatastoreError>) -> Result<Status, HttpErrorJson> {
+    let auth = auth?;
+    auth.check_unscoped()?;
+    db.revoke_session(auth.user.id, &id)?;
+    Ok(Status::NoContent)
+}
+
+// Page listing the active sessions of the logged in user
+#[get("/sessions")]
+pub fn sessions_page(db: &State<Db>, mut context: Context) -> Respondable {
+    let (user, session) = match (&context.user, &context.session) {
+        (Some(user), Some(session)) => (user.id, session.id),
+        _ => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    let sessions = session_infos(db, user, Some(session)).unwrap();
+    context.requested = Some(serde_json::to_value(sessions).unwrap());
+    Template::render("sessions", &context).into()
+}
+
+#[post("/sessions/<id>/revoke")]
+pub fn sessions_revoke(db: &State<Db>, id: Uuid, context: Context) -> Redirect {
+    if let Some(user) = context.user {
+        db.revoke_session(user.id, &id).ok();
+    }
+    Redirect::to(uri!(sessions_page))
+}
+
+// Logs out everywhere except the session making the request
+#[post("/sessions/revoke-others")]
+pub fn sessions_revoke_others(db: &State<Db>, context: Context) -> Redirect {
+    if let (Some(user), Some(session)) = (context.user, context.session) {
+        db.revoke_other_sessions(user.id, Some(&session.id)).ok();
+    }
+    Redirect::to(uri!(sessions_page))
+}
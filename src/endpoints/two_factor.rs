@@ -0,0 +1,129 @@
+use chrono::Utc;
+use rocket::{form::Form, response::Redirect, State};
+use rocket_dyn_templates::Template;
+
+use crate::db::Db;
+use crate::endpoints::{util::{error, Context}, Respondable};
+use crate::error::DatastoreError;
+use crate::totp;
+
+#[derive(FromForm)]
+pub struct Code {
+    pub code: String,
+}
+
+// Checks a code from the authenticator app of a user, or one of their recovery codes.
+// Either can only be used once.
+pub fn check_second_factor(db: &Db, user_id: i64, input: &str) -> Result<(), DatastoreError> {
+    let totp = db
+        .get_totp(user_id)?
+        .filter(|totp| totp.enabled)
+        .ok_or_else(|| DatastoreError::NotFound("two-factor authentication is not enabled".to_string()))?;
+    let valid = if totp::is_code(input) {
+        match totp::verify(&totp.secret, input, Utc::now()) {
+            Some(step) => db.use_totp_step(user_id, step)?,
+            None => false,
+        }
+    } else {
+        db.use_recovery_code(user_id, input)?
+    };
+    match valid {
+        true => Ok(()),
+        false => Err(DatastoreError::Unauthorized("invalid code".to_string())),
+    }
+}
+
+// Enrollment of a new secret, shown until a code of it is confirmed
+fn setup_page(mut context: Context, secret: &str) -> Template {
+    let username = &context.user.as_ref().unwrap().username;
+    context.requested = Some(serde_json::json!({
+        "setup": { "secret": secret, "uri": totp::otpauth_uri(username, secret) },
+    }));
+    Template::render("two_factor", &context)
+}
+
+fn recovery_codes_page(mut context: Context, codes: Vec<String>) -> Template {
+    context.requested = Some(serde_json::json!({ "enabled": true, "recovery_codes": codes }));
+    Template::render("two_factor", &context)
+}
+
+#[get("/2fa")]
+pub fn two_factor(db: &State<Db>, mut context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    match db.get_totp(user_id) {
+        Ok(totp) => {
+            let totp = totp.filter(|totp| totp.enabled);
+            context.requested = Some(serde_json::json!({
+                "enabled": totp.is_some(),
+                "recovery_codes_left": totp.map(|totp| totp.recovery_codes),
+            }));
+            Template::render("two_factor", &context).into()
+        }
+        Err(err) => error(context, err).into(),
+    }
+}
+
+#[post("/2fa/setup")]
+pub fn two_factor_setup(db: &State<Db>, context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    let secret = totp::generate_secret();
+    match db.set_totp_secret(user_id, &secret) {
+        Ok(()) => setup_page(context, &secret).into(),
+        Err(err) => error(context, err).into(),
+    }
+}
+
+// Turns on two-factor authentication once the user shows their app has the secret
+#[post("/2fa/enable", data = "<form>")]
+pub fn two_factor_enable(db: &State<Db>, form: Form<Code>, mut context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    let pending = match db.get_totp(user_id) {
+        Ok(Some(totp)) if !totp.enabled => totp,
+        Ok(_) => return Redirect::to(uri!(two_factor)).into(),
+        Err(err) => return error(context, err).into(),
+    };
+    match totp::verify(&pending.secret, &form.code, Utc::now()) {
+        Some(step) => match db.enable_totp(user_id, step) {
+            Ok(codes) => recovery_codes_page(context, codes).into(),
+            Err(err) => error(context, err).into(),
+        },
+        None => {
+            context.error = Some("invalid code, check the time of your device and try again".to_string());
+            setup_page(context, &pending.secret).into()
+        }
+    }
+}
+
+#[post("/2fa/disable", data = "<form>")]
+pub fn two_factor_disable(db: &State<Db>, form: Form<Code>, context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    match check_second_factor(db, user_id, &form.code).and_then(|()| db.disable_totp(user_id)) {
+        Ok(()) => Redirect::to(uri!(two_factor)).into(),
+        Err(err) => error(context, err).into(),
+    }
+}
+
+// Replaces the recovery codes, for when they ran low or may have been seen by someone else
+#[post("/2fa/recovery-codes", data = "<form>")]
+pub fn two_factor_recovery_codes(db: &State<Db>, form: Form<Code>, context: Context) -> Respondable {
+    let user_id = match &context.user {
+        Some(user) => user.id,
+        None => return Redirect::to(uri!(super::auth::login)).into(),
+    };
+    match check_second_factor(db, user_id, &form.code).and_then(|()| db.reset_recovery_codes(user_id)) {
+        Ok(codes) => recovery_codes_page(context, codes).into(),
+        Err(err) => error(context, err).into(),
+    }
+}
@@ -4,22 +4,36 @@ use serde::Serialize;
 
 use crate::db::{Db, self};
 use crate::endpoints::{util::Context, Respondable};
+use crate::leaderboard::{leaderboard, CategoryTotal, LeaderboardEntry, Window};
+use crate::privacy::{Profile, Visibility};
 
 #[derive(Serialize)]
 struct UserWithDevices {
-    user: db::User,
+    user: db::PublicUser,
     devices: Vec<db::Device>,
+    // Whether the devices are shown, users choose whether others see them
+    shows_devices: bool,
+    visibility: Visibility,
+    window: Window,
+    categories: Vec<CategoryTotal>,
 }
 
-#[get("/user/<id>")]
-pub fn user(db: &State<Db>, id: String, mut context: Context) -> Template {
-    let user_profile = db.get_user(&id);
-    match user_profile {
-        Ok(user) => {
-            context.requested = Some(serde_json::to_value(UserWithDevices {
-                user: user.clone(),
-                devices: db.get_devices(user.id).unwrap(),
-            }).unwrap());
+#[get("/user/<id>?<window>")]
+pub fn user(db: &State<Db>, id: String, window: Option<Window>, mut context: Context) -> Template {
+    let window = window.unwrap_or_default();
+    let page = Profile::view(db, &id, context.user.as_ref()).and_then(|profile| {
+        Ok(UserWithDevices {
+            devices: profile.devices(db)?,
+            categories: profile.category_totals(db, window)?,
+            shows_devices: profile.shows_devices(),
+            visibility: profile.privacy.visibility,
+            user: db::PublicUser::from(&profile.user),
+            window,
+        })
+    });
+    match page {
+        Ok(page) => {
+            context.requested = Some(serde_json::to_value(page).unwrap());
             Template::render("user", &context)
         },
         Err(_) => {
@@ -32,14 +46,32 @@ pub fn user(db: &State<Db>, id: String, mut context: Context) -> Template {
 #[get("/user")]
 pub fn user_self(context: Context) -> Redirect {
     match context.user {
-        Some(user) => Redirect::to(uri!(user(user.username))),
+        Some(user) => Redirect::to(uri!(user(user.username, _))),
         None => Redirect::to(uri!(super::auth::login)),
     }
 }
 
-#[get("/users")]
-pub fn users(db: &State<Db>, mut context: Context) -> Respondable {
-    let users = db.get_users().unwrap();
-    context.requested = Some(serde_json::to_value(users).unwrap());
+#[derive(Serialize)]
+struct UsersPage {
+    category: Option<String>,
+    window: Window,
+    entries: Vec<LeaderboardEntry>,
+    // Users without any time in the window
+    inactive: Vec<String>,
+}
+
+#[get("/users?<category>&<window>")]
+pub fn users(db: &State<Db>, category: Option<String>, window: Option<Window>, mut context: Context) -> Respondable {
+    let category = category.filter(|c| !c.is_empty());
+    let window = window.unwrap_or_default();
+    let entries = leaderboard(db, category.as_deref(), window).unwrap();
+    let mut inactive: Vec<String> = db
+        .get_listed_privacy()
+        .unwrap()
+        .into_keys()
+        .filter(|username| !entries.iter().any(|e| &e.username == username))
+        .collect();
+    inactive.sort();
+    context.requested = Some(serde_json::to_value(UsersPage { category, window, entries, inactive }).unwrap());
     Template::render("users", &context).into()
 }
\ No newline at end of file
@@ -1,67 +1,214 @@
 
-use rocket::{request::{FromRequest, Outcome}, Request, State, http::{CookieJar}};
-use serde::Serialize;
+use std::io::Cursor;
+
+use rocket::{request::{FromRequest, Outcome}, Request, Response, State, http::{CookieJar, ContentType, Status}};
+use rocket::response::{self, Responder};
+use rocket_dyn_templates::Template;
+use serde::{Serialize, Serializer};
 use serde_json::Value;
+use uuid::Uuid;
+
+use crate::db::{Db, self, API_KEY_PREFIX};
+use crate::error::DatastoreError;
+use crate::tokens::Tokens;
 
-use crate::db::{Db, self};
+// Session and user of a login token, if the token is valid and its session still active
+fn token_session(db: &Db, tokens: &Tokens, token: &str) -> Result<(db::User, db::Session), DatastoreError> {
+    let claims = tokens.verify(token)?;
+    let invalid = || DatastoreError::Unauthorized("invalid token".to_string());
+    let user_id: i64 = claims.sub.parse().map_err(|_| invalid())?;
+    let session_id = Uuid::parse_str(&claims.sid).map_err(|_| invalid())?;
+    let session = db.use_session(&session_id)?;
+    if session.user_id != user_id {
+        return Err(invalid());
+    }
+    Ok((db.get_user_by_id(user_id)?, session))
+}
 
 
 // Template context
 #[derive(Default, Serialize)]
 pub struct Context {
-    // Logged in user, if any
+    // Logged in user, if any. Pages only get to see it as a PrivateUser.
+    #[serde(serialize_with = "serialize_private_user")]
     pub user: Option<db::User>,
+    // Session the user is logged in with
+    pub session: Option<db::Session>,
     // Error
     pub error: Option<String>,
     // A requested entity, differs by endpoint
     pub requested: Option<Value>,
 }
 
+fn serialize_private_user<S: Serializer>(user: &Option<db::User>, serializer: S) -> Result<S::Ok, S::Error> {
+    user.as_ref().map(db::PrivateUser::from).serialize(serializer)
+}
+
 #[rocket::async_trait]
 impl <'a> FromRequest<'a> for Context {
     type Error = ();
 
     async fn from_request(request: &'a Request<'_>) -> Outcome<Self, Self::Error> {
         let db = request.guard::<&State<Db>>().await.unwrap();
+        let tokens = request.guard::<&State<Tokens>>().await.unwrap();
         let cookies = request.guard::<&CookieJar>().await.unwrap();
-        let username = cookies.get_private("user_id").map(|cookie| cookie.value().to_string());
-        let user = match username {
-            Some(username) => db.get_user(&username).ok(),
+        let login = match cookies.get_private("jwt") {
+            Some(cookie) => token_session(db, tokens, cookie.value()).ok(),
             None => None,
         };
         let mut context = Context::default();
-        context.user = user;
+        if let Some((user, session)) = login {
+            context.user = Some(user);
+            context.session = Some(session);
+        }
         Outcome::Success(context)
     }
 }
 
-// use std::io::Cursor;
-// use rocket::response::Responder;
-
-// #[derive(Serialize, Debug)]
-// pub struct HttpErrorJson {
-//     #[serde(skip_serializing)]
-//     status: Status,
-//     message: String,
-// }
-
-// impl HttpErrorJson {
-//     pub fn new(status: Status, err: String) -> HttpErrorJson {
-//         HttpErrorJson {
-//             status,
-//             message: err,
-//         }
-//     }
-// }
-
-// impl<'r> Responder<'r, 'static> for HttpErrorJson {
-//     fn respond_to(self, _: &Request) -> response::Result<'static> {
-//         // TODO: Fix unwrap
-//         let body = serde_json::to_string(&self).unwrap();
-//         Response::build()
-//             .status(self.status)
-//             .sized_body(body.len(), Cursor::new(body))
-//             .header(ContentType::new("application", "json"))
-//             .ok()
-//     }
-// }
\ No newline at end of file
+// Page with a short message for the user
+pub fn message(mut context: Context, title: &str, message: &str) -> Template {
+    context.requested = Some(serde_json::json!({ "title": title, "message": message }));
+    Template::render("message", &context)
+}
+
+// Page explaining what went wrong
+pub fn error(mut context: Context, err: DatastoreError) -> Template {
+    context.error = Some(err.to_string());
+    Template::render("error", &context)
+}
+
+// Where a request comes from, recorded with new sessions
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[rocket::async_trait]
+impl <'a> FromRequest<'a> for ClientInfo {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientInfo {
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+            ip: request.client_ip().map(|ip| ip.to_string()),
+        })
+    }
+}
+
+// Caller of an API endpoint, authenticated by an `Authorization: Bearer` header with
+// an API key or login token, or by the login cookie. Keys scoped to a device set `device_id`.
+pub struct ApiUser {
+    pub user: db::User,
+    pub device_id: Option<Uuid>,
+    // Session of a login token, None for API keys
+    pub session_id: Option<Uuid>,
+}
+
+impl ApiUser {
+    fn from_session((user, session): (db::User, db::Session)) -> ApiUser {
+        ApiUser { user, device_id: None, session_id: Some(session.id) }
+    }
+
+    // Checks that the caller may act on behalf of the given device
+    pub fn check_device(&self, device: &db::Device) -> Result<(), DatastoreError> {
+        if device.user_id != self.user.id {
+            return Err(DatastoreError::Forbidden("device belongs to another user".to_string()));
+        }
+        match self.device_id {
+            Some(device_id) if device_id != device.id => {
+                Err(DatastoreError::Forbidden("API key is scoped to another device".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Checks that the caller is not restricted to a single device
+    pub fn check_unscoped(&self) -> Result<(), DatastoreError> {
+        match self.device_id {
+            Some(_) => Err(DatastoreError::Forbidden("API key is scoped to a device".to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl <'a> FromRequest<'a> for ApiUser {
+    type Error = DatastoreError;
+
+    async fn from_request(request: &'a Request<'_>) -> Outcome<Self, Self::Error> {
+        let db = request.guard::<&State<Db>>().await.unwrap();
+        let tokens = request.guard::<&State<Tokens>>().await.unwrap();
+        let result = match request.headers().get_one("Authorization") {
+            Some(header) => match header.strip_prefix("Bearer ").map(str::trim) {
+                Some(secret) if secret.starts_with(API_KEY_PREFIX) => db.use_api_key(secret).and_then(|key| {
+                    let user = db.get_user_by_id(key.user_id)?;
+                    if user.deletion_due.is_some() {
+                        return Err(DatastoreError::Unauthorized("account is pending deletion".to_string()));
+                    }
+                    Ok(ApiUser {
+                        user,
+                        device_id: key.device_id,
+                        session_id: None,
+                    })
+                }),
+                Some(token) => token_session(db, tokens, token).map(ApiUser::from_session),
+                None => Err(DatastoreError::Unauthorized("expected a bearer token".to_string())),
+            },
+            None => {
+                let cookies = request.guard::<&CookieJar>().await.unwrap();
+                match cookies.get_private("jwt") {
+                    Some(cookie) => token_session(db, tokens, cookie.value()).map(ApiUser::from_session),
+                    None => Err(DatastoreError::Unauthorized("not logged in".to_string())),
+                }
+            }
+        };
+        match result {
+            Ok(api_user) => Outcome::Success(api_user),
+            Err(err) => Outcome::Failure((err.status(), err)),
+        }
+    }
+}
+
+// JSON error body for API endpoints
+#[derive(Serialize, Debug)]
+pub struct HttpErrorJson {
+    #[serde(skip_serializing)]
+    status: Status,
+    message: String,
+    #[serde(skip_serializing)]
+    retry_after: Option<u64>,
+}
+
+impl HttpErrorJson {
+    pub fn new(status: Status, err: String) -> HttpErrorJson {
+        HttpErrorJson {
+            status,
+            message: err,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<DatastoreError> for HttpErrorJson {
+    fn from(err: DatastoreError) -> HttpErrorJson {
+        HttpErrorJson {
+            retry_after: err.retry_after(),
+            ..HttpErrorJson::new(err.status(), err.to_string())
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for HttpErrorJson {
+    fn respond_to(self, _: &Request) -> response::Result<'static> {
+        let body = serde_json::to_string(&self).unwrap();
+        let mut response = Response::build();
+        response
+            .status(self.status)
+            .sized_body(body.len(), Cursor::new(body))
+            .header(ContentType::JSON);
+        if let Some(retry_after) = self.retry_after {
+            response.raw_header("Retry-After", retry_after.to_string());
+        }
+        response.ok()
+    }
+}
@@ -1,3 +1,4 @@
+use rocket::http::Status;
 use rocket::response::Responder;
 use thiserror::Error;
 
@@ -5,27 +6,78 @@ use thiserror::Error;
 pub enum DatastoreError {
     #[error("user `{username}` already exists")]
     UserAlreadyExists { username: String },
+    #[error("email `{email}` is already in use")]
+    EmailAlreadyExists { email: String },
     #[error("bad request: {0}")]
     BadRequest(String),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("database schema version {found} is newer than the latest known version {known}")]
+    UnsupportedSchema { found: usize, known: usize },
     #[error("rusqlite error: {0}")]
     Rusqlite(#[from] rusqlite::Error),
     #[error("r2d2 error: {0}")]
     R2d2(#[from] r2d2::Error),
+    #[error("postgres error: {0}")]
+    Postgres(#[from] postgres::Error),
+    #[error("failed to send email: {0}")]
+    Mail(String),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("too many requests, try again in {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+}
+
+impl DatastoreError {
+    pub fn status(&self) -> Status {
+        match self {
+            DatastoreError::UserAlreadyExists { .. } => Status::Conflict,
+            DatastoreError::EmailAlreadyExists { .. } => Status::Conflict,
+            DatastoreError::BadRequest(_) => Status::BadRequest,
+            DatastoreError::Unauthorized(_) => Status::Unauthorized,
+            DatastoreError::Forbidden(_) => Status::Forbidden,
+            DatastoreError::NotFound(_) => Status::NotFound,
+            DatastoreError::Conflict(_) => Status::Conflict,
+            DatastoreError::UnsupportedSchema { .. } => Status::InternalServerError,
+            DatastoreError::Rusqlite(_) => Status::InternalServerError,
+            DatastoreError::R2d2(_) => Status::InternalServerError,
+            DatastoreError::Postgres(_) => Status::InternalServerError,
+            DatastoreError::Mail(_) => Status::InternalServerError,
+            DatastoreError::Io(_) => Status::InternalServerError,
+            DatastoreError::Zip(_) => Status::InternalServerError,
+            DatastoreError::RateLimited { .. } => Status::TooManyRequests,
+        }
+    }
+
+    // Seconds until the request may be retried, sent as `Retry-After`
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            DatastoreError::RateLimited { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
 }
 
 impl<'a> Responder<'a, 'static> for DatastoreError {
     fn respond_to(self, _: &'a rocket::Request<'_>) -> rocket::response::Result<'static> {
-        let status = match self {
-            DatastoreError::UserAlreadyExists { .. } => rocket::http::Status::Conflict,
-            DatastoreError::BadRequest(_) => rocket::http::Status::BadRequest,
-            DatastoreError::Rusqlite(_) => rocket::http::Status::InternalServerError,
-            DatastoreError::R2d2(_) => rocket::http::Status::InternalServerError,
-        };
+        let status = self.status();
         let msg = self.to_string();
-        rocket::Response::build()
+        let mut response = rocket::Response::build();
+        response
             .status(status)
             .header(rocket::http::ContentType::Plain)
-            .sized_body(msg.len(), std::io::Cursor::new(msg))
-            .ok()
+            .sized_body(msg.len(), std::io::Cursor::new(msg));
+        if let Some(retry_after) = self.retry_after() {
+            response.raw_header("Retry-After", retry_after.to_string());
+        }
+        response.ok()
     }
 }
@@ -0,0 +1,134 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::db::{ApiKey, Db, Device, Event, OauthIdentity, Ruleset, Session, User};
+use crate::error::DatastoreError;
+use crate::privacy::Privacy;
+
+// Everything stored about a user but their activity, which has entries of its own.
+// The user sees their own data here, so it has the email, but never the password hash,
+// nor the secrets of their keys, sessions or second factor.
+#[derive(Serialize)]
+struct Account<'a> {
+    exported_at: DateTime<Utc>,
+    username: &'a str,
+    email: &'a str,
+    created_at: Option<DateTime<Utc>>,
+    email_verified: bool,
+    privacy: Privacy,
+    // Days activity is kept for, if the user chose a retention of their own
+    retention_days: Option<u32>,
+    two_factor_enabled: bool,
+    devices: Vec<Device>,
+    rulesets: Vec<Ruleset>,
+    api_keys: Vec<ApiKey>,
+    sessions: Vec<Session>,
+    oauth_identities: Vec<OauthIdentity>,
+}
+
+// Writes the export of a user to `out` as a zip archive of
+// - account.json, the profile with its settings, devices, rulesets, API keys, sessions
+//   and linked OAuth accounts
+// - activity.json, every hour of activity of every device with its events
+// - daily.json, the time per day, device and category, all that's left of compacted hours
+// - devices/<device id>.csv, the events of a device with one row each
+// Activity is written as it is read from the database, a row at a time, so the size of
+// the history doesn't matter.
+pub fn write_export<W: Write + Seek + Send>(db: &Db, user: &User, out: W) -> Result<W, DatastoreError> {
+    let devices = db.get_devices(user.id)?;
+    let device_ids: Vec<Uuid> = devices.iter().map(|device| device.id).collect();
+    let account = Account {
+        exported_at: Utc::now(),
+        username: &user.username,
+        email: &user.email,
+        created_at: user.created_at,
+        email_verified: user.email_verified,
+        privacy: db.get_privacy(user.id)?,
+        retention_days: db.get_retention(user.id)?,
+        two_factor_enabled: db.get_totp(user.id)?.is_some_and(|totp| totp.enabled),
+        devices,
+        rulesets: db.get_rulesets(user.id)?,
+        api_keys: db.get_api_keys(user.id)?,
+        sessions: db.get_sessions(user.id)?,
+        oauth_identities: db.get_oauth_identities(user.id)?,
+    };
+
+    let mut zip = ZipWriter::new(out);
+    // Activity entries may grow past the 4 GiB a zip entry holds without the zip64 extension
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(true);
+
+    zip.start_file("account.json", options)?;
+    serde_json::to_writer_pretty(&mut zip, &account).map_err(std::io::Error::from)?;
+
+    zip.start_file("activity.json", options)?;
+    zip.write_all(b"[")?;
+    let mut first = true;
+    for device_id in &device_ids {
+        db.for_each_activity(device_id, &mut |activity| {
+            zip.write_all(if first { b"\n" } else { b",\n" })?;
+            first = false;
+            serde_json::to_writer(&mut zip, &activity).map_err(std::io::Error::from)?;
+            Ok(())
+        })?;
+    }
+    zip.write_all(b"\n]\n")?;
+
+    zip.start_file("daily.json", options)?;
+    serde_json::to_writer_pretty(&mut zip, &db.get_daily_rollups(user.id)?).map_err(std::io::Error::from)?;
+
+    for device_id in &device_ids {
+        zip.start_file(format!("devices/{}.csv", device_id), options)?;
+        zip.write_all(b"hour,ruleset_id,timestamp,duration,category\n")?;
+        db.for_each_activity(device_id, &mut |activity| {
+            for event in &activity.events {
+                write_csv_row(&mut zip, activity.timestamp, activity.ruleset_id, event)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(zip.finish()?)
+}
+
+// Durations are in seconds, like in the API
+fn write_csv_row(out: &mut impl Write, hour: DateTime<Utc>, ruleset_id: i64, event: &Event) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "{},{},{},{},{}",
+        hour.to_rfc3339(),
+        ruleset_id,
+        event.timestamp.to_rfc3339(),
+        event.duration.as_secs(),
+        csv_field(&event.category)
+    )
+}
+
+// Quotes fields with separators, quotes or line breaks (RFC 4180)
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Writes the export of a user to a temporary file, ready to be read from the start.
+// The file is unlinked right away, it's gone once the returned handle is closed.
+pub fn export_to_file(db: &Db, user: &User) -> Result<File, DatastoreError> {
+    let path = std::env::temp_dir().join(format!("aw-leaderboard-export-{}.zip", Uuid::new_v4()));
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    if let Err(err) = fs::remove_file(&path) {
+        log::warn!("Failed to remove export file {}: {}", path.display(), err);
+    }
+    let mut file = write_export(db, user, file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
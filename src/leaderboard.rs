@@ -0,0 +1,99 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Duration, Utc};
+use rocket::FromFormField;
+use serde::Serialize;
+
+use crate::categories::PATH_SEPARATOR;
+use crate::db::{start_of_day, Db};
+use crate::error::DatastoreError;
+use crate::privacy::is_within;
+
+// Time span a leaderboard covers, in whole UTC days ending today
+#[derive(Debug, Clone, Copy, Default, PartialEq, FromFormField, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Window {
+    Day,
+    #[default]
+    Week,
+    Month,
+    All,
+}
+
+impl Window {
+    // Start of the window, or None if it covers all time
+    pub fn start(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let today = start_of_day(now);
+        match self {
+            Window::Day => Some(today),
+            Window::Week => Some(today - Duration::days(6)),
+            Window::Month => Some(today - Duration::days(29)),
+            Window::All => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    pub username: String,
+    // Total time in seconds
+    pub duration: u64,
+}
+
+// Ranks the listed users by their time across all devices within the window,
+// optionally counting a single category. Only the categories each user shares are
+// counted. Users with no time are left out, ties share a rank.
+pub fn leaderboard(db: &Db, category: Option<&str>, window: Window) -> Result<Vec<LeaderboardEntry>, DatastoreError> {
+    let listed = db.get_listed_privacy()?;
+    let mut totals: HashMap<String, u64> = HashMap::new();
+    for (username, name, duration) in db.get_daily_totals(window.start(Utc::now()))? {
+        let counted = match listed.get(&username) {
+            Some(privacy) => privacy.shares(&name) && category.is_none_or(|category| is_within(&name, category)),
+            None => false,
+        };
+        if counted {
+            *totals.entry(username).or_default() += duration;
+        }
+    }
+    let mut totals: Vec<(String, u64)> = totals.into_iter().filter(|(_, d)| *d > 0).collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut entries: Vec<LeaderboardEntry> = Vec::with_capacity(totals.len());
+    for (i, (username, duration)) in totals.into_iter().enumerate() {
+        let rank = match entries.last() {
+            Some(prev) if prev.duration == duration => prev.rank,
+            _ => i + 1,
+        };
+        entries.push(LeaderboardEntry { rank, username, duration });
+    }
+    Ok(entries)
+}
+
+#[derive(Debug, Serialize)]
+pub struct CategoryTotal {
+    pub name: Vec<String>,
+    // Time in seconds, including all subcategories
+    pub duration: u64,
+}
+
+// Time of a user per category within the window, counting only the categories the
+// filter lets through. Every parent of a reported category is included with the time
+// of its children rolled into it, sorted so that parents come right before their children.
+// Use Profile::category_totals to show them to someone, it applies the user's privacy settings.
+pub fn category_totals(db: &Db, user_id: i64, window: Window, filter: impl Fn(&str) -> bool) -> Result<Vec<CategoryTotal>, DatastoreError> {
+    let mut totals: BTreeMap<Vec<String>, u64> = BTreeMap::new();
+    for (category, duration) in db.get_category_totals(user_id, window.start(Utc::now()))? {
+        if !filter(&category) {
+            continue;
+        }
+        let path: Vec<String> = category.split(PATH_SEPARATOR).map(String::from).collect();
+        for depth in 1..=path.len() {
+            *totals.entry(path[..depth].to_vec()).or_default() += duration;
+        }
+    }
+    Ok(totals
+        .into_iter()
+        .map(|(name, duration)| CategoryTotal { name, duration })
+        .collect())
+}
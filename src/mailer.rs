@@ -0,0 +1,112 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use rocket::{fairing, Build, Rocket};
+
+use crate::config::{Config, MailerConfig, SmtpTls};
+use crate::error::DatastoreError;
+
+// The way the server sends emails, shared between all requests
+pub type Mailer = Box<dyn MailTransport>;
+
+// Emails are plain text, addressed to a single recipient
+pub trait MailTransport: Send + Sync {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), DatastoreError>;
+}
+
+struct LogMailer {
+    from: String,
+}
+
+impl MailTransport for LogMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), DatastoreError> {
+        log::info!("Email from {} to {}: {}\n{}", self.from, to, subject, body);
+        Ok(())
+    }
+}
+
+struct FileMailer {
+    path: PathBuf,
+    from: String,
+}
+
+impl MailTransport for FileMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), DatastoreError> {
+        let message = format!("From: {}\nTo: {}\nSubject: {}\n\n{}\n\n", self.from, to, subject, body);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(message.as_bytes()))
+            .map_err(|err| DatastoreError::Mail(err.to_string()))
+    }
+}
+
+struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl MailTransport for SmtpMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), DatastoreError> {
+        let to: Mailbox = to
+            .parse()
+            .map_err(|_| DatastoreError::BadRequest(format!("invalid email address `{}`", to)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body.to_string())
+            .map_err(|err| DatastoreError::Mail(err.to_string()))?;
+        self.transport
+            .send(&message)
+            .map_err(|err| DatastoreError::Mail(err.to_string()))?;
+        Ok(())
+    }
+}
+
+fn smtp_mailer(host: &str, port: Option<u16>, credentials: Option<Credentials>, tls: &SmtpTls, from: &str) -> Result<Mailer, String> {
+    let from: Mailbox = from.parse().map_err(|err| format!("invalid mail_from: {}", err))?;
+    let mut builder = match tls {
+        SmtpTls::Starttls => SmtpTransport::starttls_relay(host).map_err(|err| err.to_string())?,
+        SmtpTls::Tls => SmtpTransport::relay(host).map_err(|err| err.to_string())?,
+        SmtpTls::None => SmtpTransport::builder_dangerous(host),
+    };
+    if let Some(port) = port {
+        builder = builder.port(port);
+    }
+    if let Some(credentials) = credentials {
+        builder = builder.credentials(credentials);
+    }
+    Ok(Box::new(SmtpMailer { transport: builder.build(), from }))
+}
+
+// Sets up the configured mailer on ignite
+pub async fn init(rocket: Rocket<Build>) -> fairing::Result {
+    let config = rocket.state::<Config>().expect("config is managed");
+    let mailer: Mailer = match &config.mailer {
+        MailerConfig::Log => {
+            log::warn!("Emails are written to the log instead of being sent, configure a mailer to send them");
+            Box::new(LogMailer { from: config.mail_from.clone() })
+        }
+        MailerConfig::File { path } => Box::new(FileMailer { path: path.clone(), from: config.mail_from.clone() }),
+        MailerConfig::Smtp { host, port, username, password, tls } => {
+            let credentials = match (username, password) {
+                (Some(username), Some(password)) => Some(Credentials::new(username.clone(), password.clone())),
+                _ => None,
+            };
+            match smtp_mailer(host, *port, credentials, tls, &config.mail_from) {
+                Ok(mailer) => mailer,
+                Err(err) => {
+                    log::error!("Invalid SMTP mailer configuration: {}", err);
+                    return Err(rocket);
+                }
+            }
+        }
+    };
+    Ok(rocket.manage(mailer))
+}
@@ -6,6 +6,7 @@ extern crate rocket_contrib;
 extern crate rusqlite;
 extern crate r2d2;
 
+use rocket::fairing::AdHoc;
 use rocket::fs::{relative, FileServer};
 use rocket_dyn_templates::Template;
 
@@ -13,17 +14,47 @@ mod db;
 mod tests;
 mod error;
 mod endpoints;
+mod config;
+mod validation;
+mod leaderboard;
+mod categories;
+mod migrations;
+mod seed;
+mod tokens;
+mod oauth;
+mod mailer;
+mod totp;
+mod ratelimit;
+mod privacy;
+mod export;
+mod maintenance;
+mod retention;
 
 use db::Db;
 
 fn rocket() -> rocket::Rocket<rocket::Build> {
-    let db = match Db::new() {
+    let db = match db::open() {
         Ok(db) => db,
         Err(e) => panic!("Error: {:?}", e),
     };
-    db.init_test().expect("Failed to init test db");
+    let rocket = rocket_with_db(db);
+    let config: config::Config = rocket.figment().extract().expect("Invalid configuration");
+    if config.seed {
+        let db = rocket.state::<Db>().unwrap();
+        seed::seed_demo_data(db).expect("Failed to seed demo data");
+    }
+    rocket
+}
+
+fn rocket_with_db(db: Db) -> rocket::Rocket<rocket::Build> {
     let rocket = rocket::build()
         .attach(Template::fairing())
+        .attach(AdHoc::config::<config::Config>())
+        .attach(AdHoc::try_on_ignite("Login tokens", tokens::init))
+        .attach(AdHoc::try_on_ignite("OAuth providers", oauth::init))
+        .attach(AdHoc::try_on_ignite("Mailer", mailer::init))
+        .attach(ratelimit::RateLimit)
+        .attach(maintenance::Maintenance)
         .mount("/static", FileServer::from(relative!("static")))
         .manage(db);
     endpoints::mount(rocket)
@@ -31,6 +62,31 @@ fn rocket() -> rocket::Rocket<rocket::Build> {
 
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
+    // `aw-leaderboard rebuild-rollups` recomputes the leaderboard rollups and exits
+    if std::env::args().nth(1).as_deref() == Some("rebuild-rollups") {
+        let db = db::open().expect("Failed to open db");
+        db.rebuild_rollups().expect("Failed to rebuild rollups");
+        println!("Rebuilt activity rollups");
+        return Ok(());
+    }
+    // `aw-leaderboard prune-activity [--dry-run]` prunes activity past its retention right
+    // away, listing what was pruned or only would be
+    if std::env::args().nth(1).as_deref() == Some("prune-activity") {
+        let dry_run = std::env::args().any(|arg| arg == "--dry-run");
+        let config: config::Config = rocket::Config::figment().extract().expect("Invalid configuration");
+        let db = db::open().expect("Failed to open db");
+        let pruned = retention::prune(&db, &config.retention, chrono::Utc::now(), dry_run).expect("Failed to prune activity");
+        for pruned in &pruned {
+            println!("user {}: {} hours before {}", pruned.user_id, pruned.hours, pruned.before.date_naive());
+        }
+        let hours: usize = pruned.iter().map(|pruned| pruned.hours).sum();
+        match dry_run {
+            true => println!("Would prune {} hours of activity", hours),
+            false => println!("Pruned {} hours of activity", hours),
+        }
+        return Ok(());
+    }
+
     let _rocket = rocket()
         .launch()
         .await;
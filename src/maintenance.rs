@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio;
+use rocket::{Orbit, Rocket};
+
+use crate::config::{Config, Retention};
+use crate::db::Db;
+use crate::error::DatastoreError;
+use crate::retention;
+
+// How often the background task runs, the first time right after launch
+const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Removes the accounts whose grace period ended by the given time, returning how many
+pub fn purge_deleted_users(db: &Db, now: DateTime<Utc>) -> Result<usize, DatastoreError> {
+    let users = db.get_users_due_for_deletion(now)?;
+    for user_id in &users {
+        db.delete_user(*user_id)?;
+        log::info!("Deleted user {} after the grace period", user_id);
+    }
+    Ok(users.len())
+}
+
+// Prunes activity past its retention, or only logs what would be pruned in a dry run
+pub fn prune_activity(db: &Db, config: &Retention, now: DateTime<Utc>) -> Result<(), DatastoreError> {
+    for pruned in retention::prune(db, config, now, config.dry_run)? {
+        let verb = if config.dry_run { "Would prune" } else { "Pruned" };
+        log::info!(
+            "{} {} hours of activity of user {} from before {}",
+            verb,
+            pruned.hours,
+            pruned.user_id,
+            pruned.before.date_naive()
+        );
+    }
+    Ok(())
+}
+
+fn run(db: &Db, retention: &Retention) {
+    let now = Utc::now();
+    if let Err(err) = purge_deleted_users(db, now) {
+        log::error!("Failed to purge deleted users: {}", err);
+    }
+    if let Err(err) = prune_activity(db, retention, now) {
+        log::error!("Failed to prune activity: {}", err);
+    }
+}
+
+// Runs housekeeping of the database in the background while the server is up
+pub struct Maintenance;
+
+#[rocket::async_trait]
+impl Fairing for Maintenance {
+    fn info(&self) -> Info {
+        Info { name: "Maintenance", kind: Kind::Liftoff }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let config = rocket.state::<Config>().expect("config is managed");
+        if !config.maintenance {
+            log::info!("Maintenance task is disabled");
+            return;
+        }
+        let db = rocket.state::<Db>().expect("db is managed").clone();
+        let retention = config.retention.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(INTERVAL);
+            loop {
+                interval.tick().await;
+                let (db, retention) = (db.clone(), retention.clone());
+                // The datastore blocks, so it runs on a thread of its own
+                if let Err(err) = tokio::task::spawn_blocking(move || run(&db, &retention)).await {
+                    log::error!("Maintenance task failed: {}", err);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,6 @@
+// Forward migrations of each backend, in the order they are applied.
+// A database at schema version N has had the first N migrations applied, and
+// both backends keep the same numbering so a version means the same schema on either.
+// Never edit a migration once released, add a new one instead.
+pub mod postgres;
+pub mod sqlite;
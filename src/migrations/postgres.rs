@@ -0,0 +1,137 @@
+use postgres::GenericClient;
+
+use crate::error::DatastoreError;
+
+pub const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema
+    "CREATE TABLE \"user\" (
+          id              BIGSERIAL PRIMARY KEY,
+          username        TEXT NOT NULL UNIQUE,
+          email           TEXT NOT NULL UNIQUE,
+          password        TEXT NOT NULL
+     );
+     CREATE TABLE device (
+          id              UUID PRIMARY KEY,
+          user_id         BIGINT NOT NULL REFERENCES \"user\"(id),
+          name            TEXT NOT NULL
+     );
+     CREATE TABLE ruleset (
+          id              BIGSERIAL PRIMARY KEY,
+          user_id         BIGINT NOT NULL REFERENCES \"user\"(id),
+          name            TEXT NOT NULL,
+          rules           TEXT NOT NULL
+     );
+     -- Contains hourly rows of activity for each device, one per device, hour and ruleset
+     CREATE TABLE activity (
+          id              BIGSERIAL PRIMARY KEY,
+          timestamp       BIGINT NOT NULL,
+          device_id       UUID NOT NULL REFERENCES device(id),
+          events          TEXT NOT NULL,
+          ruleset_id      BIGINT NOT NULL REFERENCES ruleset(id)
+     );
+     CREATE UNIQUE INDEX activity_device_hour ON activity (device_id, timestamp, ruleset_id);
+     -- Keys are scoped to a user, or to a single device if device_id is set
+     CREATE TABLE api_key (
+          id              BIGSERIAL PRIMARY KEY,
+          user_id         BIGINT NOT NULL REFERENCES \"user\"(id),
+          device_id       UUID REFERENCES device(id),
+          name            TEXT NOT NULL,
+          key_hash        TEXT NOT NULL UNIQUE,
+          created         BIGINT NOT NULL,
+          last_used       BIGINT
+     );
+     -- Rollup of activity per user, UTC day and category, kept up to date by report_activity
+     CREATE TABLE activity_daily (
+          user_id         BIGINT NOT NULL REFERENCES \"user\"(id),
+          day             BIGINT NOT NULL,
+          category        TEXT NOT NULL,
+          duration        BIGINT NOT NULL,
+          PRIMARY KEY(user_id, day, category)
+     );
+     CREATE INDEX activity_daily_day ON activity_daily (day);",
+    // 2: Signup time of users, unknown for existing users
+    "ALTER TABLE \"user\" ADD COLUMN created_at BIGINT;",
+    // 3: Login sessions
+    "CREATE TABLE session (
+          id              UUID PRIMARY KEY,
+          user_id         BIGINT NOT NULL REFERENCES \"user\"(id),
+          created         BIGINT NOT NULL,
+          last_used       BIGINT NOT NULL,
+          expires         BIGINT NOT NULL,
+          user_agent      TEXT,
+          ip              TEXT
+     );
+     CREATE INDEX session_user_id ON session (user_id);",
+    // 4: Accounts of OAuth providers users log in with
+    "CREATE TABLE oauth_identity (
+          provider        TEXT NOT NULL,
+          subject         TEXT NOT NULL,
+          user_id         BIGINT NOT NULL REFERENCES \"user\"(id),
+          created         BIGINT NOT NULL,
+          PRIMARY KEY(provider, subject)
+     );",
+    // 5: Whether users confirmed their email, existing accounts are taken as verified
+    "ALTER TABLE \"user\" ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+     UPDATE \"user\" SET email_verified = TRUE;",
+    // 6: Two-factor authentication
+    "CREATE TABLE totp (
+          user_id         BIGINT PRIMARY KEY REFERENCES \"user\"(id),
+          secret          TEXT NOT NULL,
+          enabled         BOOLEAN NOT NULL,
+          last_step       BIGINT
+     );
+     CREATE TABLE recovery_code (
+          user_id         BIGINT NOT NULL REFERENCES \"user\"(id),
+          code_hash       TEXT NOT NULL,
+          PRIMARY KEY(user_id, code_hash)
+     );",
+    // 7: Privacy settings, devices are only listed once their user chooses to
+    "ALTER TABLE \"user\" ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public';
+     ALTER TABLE \"user\" ADD COLUMN share_devices BOOLEAN NOT NULL DEFAULT FALSE;
+     ALTER TABLE \"user\" ADD COLUMN share_categories BOOLEAN NOT NULL DEFAULT TRUE;
+     CREATE TABLE category_sharing (
+          user_id         BIGINT NOT NULL REFERENCES \"user\"(id),
+          category        TEXT NOT NULL,
+          shared          BOOLEAN NOT NULL,
+          PRIMARY KEY(user_id, category)
+     );",
+    // 8: Accounts deleted by their user are kept until this time, if there is a grace period
+    "ALTER TABLE \"user\" ADD COLUMN deletion_due BIGINT;",
+    // 9: Retention of hourly activity, rollups before compacted_until are no longer rebuilt
+    "ALTER TABLE \"user\" ADD COLUMN retention_days BIGINT;
+     ALTER TABLE \"user\" ADD COLUMN compacted_until BIGINT;",
+    // 10: Rollups per device. Those of compacted days keep no device, the others are
+    // rebuilt on startup.
+    "ALTER TABLE activity_daily DROP CONSTRAINT activity_daily_pkey;
+     ALTER TABLE activity_daily ADD COLUMN device_id UUID REFERENCES device(id);
+     DELETE FROM activity_daily WHERE NOT EXISTS (
+          SELECT 1 FROM \"user\" WHERE \"user\".id = activity_daily.user_id AND activity_daily.day < \"user\".compacted_until
+     );
+     CREATE UNIQUE INDEX activity_daily_device_day ON activity_daily (user_id, device_id, day, category);",
+];
+
+pub fn schema_version(conn: &mut impl GenericClient) -> Result<usize, DatastoreError> {
+    let version: Option<i64> = conn
+        .query_opt("SELECT version FROM schema_version", &[])?
+        .map(|row| row.get(0));
+    Ok(version.unwrap_or(0) as usize)
+}
+
+// Brings the schema up to date in a single transaction.
+// Refuses to touch databases written by a newer version of the server.
+pub fn migrate(conn: &mut postgres::Client) -> Result<(), DatastoreError> {
+    let mut tx = conn.transaction()?;
+    tx.batch_execute("CREATE TABLE IF NOT EXISTS schema_version (version BIGINT NOT NULL)")?;
+    let version = schema_version(&mut tx)?;
+    if version > MIGRATIONS.len() {
+        return Err(DatastoreError::UnsupportedSchema { found: version, known: MIGRATIONS.len() });
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::info!("Applying database migration {}", i + 1);
+        tx.batch_execute(migration)?;
+    }
+    tx.execute("DELETE FROM schema_version", &[])?;
+    tx.execute("INSERT INTO schema_version (version) VALUES ($1)", &[&(MIGRATIONS.len() as i64)])?;
+    tx.commit()?;
+    Ok(())
+}
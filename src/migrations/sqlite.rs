@@ -0,0 +1,192 @@
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::error::DatastoreError;
+
+pub const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema
+    // Databases created before migrations existed are at version 0 but may already
+    // contain some of these tables, hence IF NOT EXISTS.
+    "CREATE TABLE IF NOT EXISTS user (
+          id              INTEGER PRIMARY KEY,
+          username        TEXT NOT NULL UNIQUE,
+          email           TEXT NOT NULL UNIQUE,
+          password        TEXT NOT NULL
+     );
+     -- Device IDs are UUIDs
+     CREATE TABLE IF NOT EXISTS device (
+          id              TEXT PRIMARY KEY,
+          user_id         INTEGER NOT NULL,
+          name            TEXT NOT NULL,
+          FOREIGN KEY(user_id) REFERENCES user(id)
+     );
+     -- Contains hourly rows of activity for each device
+     CREATE TABLE IF NOT EXISTS activity (
+          id              INTEGER PRIMARY KEY,
+          timestamp       INTEGER NOT NULL,
+          device_id       TEXT NOT NULL,
+          events          BLOB NOT NULL,
+          ruleset_id      INTEGER NOT NULL,
+          FOREIGN KEY(device_id) REFERENCES device(id)
+          FOREIGN KEY(ruleset_id) REFERENCES ruleset(id)
+     );
+     -- One row per device, hour and ruleset, so re-sent hours replace the earlier report.
+     -- Older databases may contain duplicates, keep the latest one.
+     DELETE FROM activity WHERE id NOT IN (
+          SELECT MAX(id) FROM activity GROUP BY device_id, timestamp, ruleset_id
+     );
+     CREATE UNIQUE INDEX IF NOT EXISTS activity_device_hour
+          ON activity (device_id, timestamp, ruleset_id);
+     CREATE TABLE IF NOT EXISTS ruleset (
+          id              INTEGER PRIMARY KEY,
+          user_id         INTEGER NOT NULL,
+          name            TEXT NOT NULL,
+          rules           BLOB NOT NULL,
+          FOREIGN KEY(user_id) REFERENCES user(id)
+     );
+     -- Keys are scoped to a user, or to a single device if device_id is set
+     CREATE TABLE IF NOT EXISTS api_key (
+          id              INTEGER PRIMARY KEY,
+          user_id         INTEGER NOT NULL,
+          device_id       TEXT,
+          name            TEXT NOT NULL,
+          key_hash        TEXT NOT NULL UNIQUE,
+          created         INTEGER NOT NULL,
+          last_used       INTEGER,
+          FOREIGN KEY(user_id) REFERENCES user(id)
+          FOREIGN KEY(device_id) REFERENCES device(id)
+     );
+     -- Rollup of activity per user, UTC day and category, kept up to date by report_activity
+     CREATE TABLE IF NOT EXISTS activity_daily (
+          user_id         INTEGER NOT NULL,
+          day             INTEGER NOT NULL,
+          category        TEXT NOT NULL,
+          duration        INTEGER NOT NULL,
+          PRIMARY KEY(user_id, day, category),
+          FOREIGN KEY(user_id) REFERENCES user(id)
+     );
+     CREATE INDEX IF NOT EXISTS activity_daily_day ON activity_daily (day);",
+    // 2: Signup time of users, unknown for existing users
+    "ALTER TABLE user ADD COLUMN created_at INTEGER;",
+    // 3: Login sessions, session IDs are UUIDs
+    "CREATE TABLE session (
+          id              TEXT PRIMARY KEY,
+          user_id         INTEGER NOT NULL,
+          created         INTEGER NOT NULL,
+          last_used       INTEGER NOT NULL,
+          expires         INTEGER NOT NULL,
+          user_agent      TEXT,
+          ip              TEXT,
+          FOREIGN KEY(user_id) REFERENCES user(id)
+     );
+     CREATE INDEX session_user_id ON session (user_id);",
+    // 4: Accounts of OAuth providers users log in with, by provider name and the
+    // provider's ID of the account
+    "CREATE TABLE oauth_identity (
+          provider        TEXT NOT NULL,
+          subject         TEXT NOT NULL,
+          user_id         INTEGER NOT NULL,
+          created         INTEGER NOT NULL,
+          PRIMARY KEY(provider, subject),
+          FOREIGN KEY(user_id) REFERENCES user(id)
+     );",
+    // 5: Whether users confirmed their email. Accounts from before verification
+    // existed are taken as verified, so they don't drop off the leaderboards.
+    "ALTER TABLE user ADD COLUMN email_verified INTEGER NOT NULL DEFAULT 0;
+     UPDATE user SET email_verified = 1;",
+    // 6: Two-factor authentication. A secret is pending until the user confirmed a
+    // code of it, and recovery codes are stored hashed.
+    "CREATE TABLE totp (
+          user_id         INTEGER PRIMARY KEY,
+          secret          TEXT NOT NULL,
+          enabled         INTEGER NOT NULL,
+          -- Time step of the last accepted code, codes can't be used twice
+          last_step       INTEGER,
+          FOREIGN KEY(user_id) REFERENCES user(id)
+     );
+     CREATE TABLE recovery_code (
+          user_id         INTEGER NOT NULL,
+          code_hash       TEXT NOT NULL,
+          PRIMARY KEY(user_id, code_hash),
+          FOREIGN KEY(user_id) REFERENCES user(id)
+     );",
+    // 7: Privacy settings. Profiles stay public and categories shared like before,
+    // but devices are only listed once their user chooses to.
+    "ALTER TABLE user ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public';
+     ALTER TABLE user ADD COLUMN share_devices INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE user ADD COLUMN share_categories INTEGER NOT NULL DEFAULT 1;
+     -- Categories shared or hidden against share_categories, with their subcategories
+     CREATE TABLE category_sharing (
+          user_id         INTEGER NOT NULL,
+          category        TEXT NOT NULL,
+          shared          INTEGER NOT NULL,
+          PRIMARY KEY(user_id, category),
+          FOREIGN KEY(user_id) REFERENCES user(id)
+     );",
+    // 8: Accounts deleted by their user are kept until this time, if there is a grace period
+    "ALTER TABLE user ADD COLUMN deletion_due INTEGER;",
+    // 9: Retention of hourly activity. Rollups of days before compacted_until are all
+    // that's left of their activity, so they are no longer rebuilt from it.
+    "ALTER TABLE user ADD COLUMN retention_days INTEGER;
+     ALTER TABLE user ADD COLUMN compacted_until INTEGER;",
+    // 10: Rollups per device, so deleting a device takes its compacted days with it.
+    // Rollups of compacted days can't tell their device and keep none, the others are
+    // rebuilt on startup.
+    "CREATE TABLE activity_daily_by_device (
+          user_id         INTEGER NOT NULL,
+          device_id       TEXT,
+          day             INTEGER NOT NULL,
+          category        TEXT NOT NULL,
+          duration        INTEGER NOT NULL,
+          FOREIGN KEY(user_id) REFERENCES user(id)
+          FOREIGN KEY(device_id) REFERENCES device(id)
+     );
+     INSERT INTO activity_daily_by_device (user_id, day, category, duration)
+          SELECT activity_daily.user_id, activity_daily.day, activity_daily.category, activity_daily.duration
+          FROM activity_daily JOIN user ON activity_daily.user_id = user.id
+          WHERE activity_daily.day < user.compacted_until;
+     DROP TABLE activity_daily;
+     ALTER TABLE activity_daily_by_device RENAME TO activity_daily;
+     CREATE UNIQUE INDEX activity_daily_device_day ON activity_daily (user_id, device_id, day, category);
+     CREATE INDEX activity_daily_day ON activity_daily (day);",
+];
+
+pub fn schema_version(conn: &Connection) -> Result<usize, DatastoreError> {
+    let version: Option<i64> = conn
+        .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+        .optional()?;
+    Ok(version.unwrap_or(0) as usize)
+}
+
+// Brings the schema up to date in a single transaction.
+// Refuses to touch databases written by a newer version of the server.
+pub fn migrate(conn: &mut Connection) -> Result<(), DatastoreError> {
+    // Migrations may touch tables before those they reference exist, so foreign keys are
+    // only enforced again once they are done. The pragma can't change within a transaction.
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let migrated = migrate_tx(conn);
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    migrated?;
+    // Rows written before foreign keys were enforced may point to rows that are gone
+    let violations: i64 = conn.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| row.get(0))?;
+    if violations > 0 {
+        log::warn!("Database has {} rows referencing missing rows", violations);
+    }
+    Ok(())
+}
+
+fn migrate_tx(conn: &mut Connection) -> Result<(), DatastoreError> {
+    let tx = conn.transaction()?;
+    tx.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)", [])?;
+    let version = schema_version(&tx)?;
+    if version > MIGRATIONS.len() {
+        return Err(DatastoreError::UnsupportedSchema { found: version, known: MIGRATIONS.len() });
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::info!("Applying database migration {}", i + 1);
+        tx.execute_batch(migration)?;
+    }
+    tx.execute("DELETE FROM schema_version", [])?;
+    tx.execute("INSERT INTO schema_version (version) VALUES (?1)", params![MIGRATIONS.len() as i64])?;
+    tx.commit()?;
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+
+use oauth2::basic::BasicClient;
+use oauth2::reqwest::async_http_client;
+use oauth2::url::ParseError;
+use oauth2::{
+    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl,
+    Scope, TokenResponse, TokenUrl,
+};
+use reqwest::header::{ACCEPT, USER_AGENT};
+use rocket::{fairing, Build, Rocket};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::{Config, OAuthProvider};
+use crate::error::DatastoreError;
+
+// A login that was sent off to a provider, remembered in a private cookie until
+// the provider redirects back
+#[derive(Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub provider: String,
+    // CSRF token the provider has to hand back
+    pub state: String,
+    pub pkce_verifier: String,
+}
+
+// The account a user logged in to at a provider
+pub struct Identity {
+    // The provider's ID of the account, which unlike the username never changes
+    pub subject: String,
+    pub username: String,
+    pub email: Option<String>,
+}
+
+pub struct Provider {
+    client: BasicClient,
+    userinfo_url: String,
+    scopes: Vec<String>,
+    id_field: String,
+    username_field: String,
+    email_field: String,
+}
+
+impl Provider {
+    fn new(config: &OAuthProvider, redirect_url: String) -> Result<Provider, ParseError> {
+        let client = BasicClient::new(
+            ClientId::new(config.client_id.clone()),
+            Some(ClientSecret::new(config.client_secret.clone())),
+            AuthUrl::new(config.auth_url.clone())?,
+            Some(TokenUrl::new(config.token_url.clone())?),
+        )
+        .set_redirect_uri(RedirectUrl::new(redirect_url)?);
+        Ok(Provider {
+            client,
+            userinfo_url: config.userinfo_url.clone(),
+            scopes: config.scopes.clone(),
+            id_field: config.id_field.clone(),
+            username_field: config.username_field.clone(),
+            email_field: config.email_field.clone(),
+        })
+    }
+
+    // The URL to send the user to, and the login to remember until they come back
+    pub fn authorize(&self, name: &str) -> (String, PendingLogin) {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (url, state) = self
+            .client
+            .authorize_url(CsrfToken::new_random)
+            .add_scopes(self.scopes.iter().cloned().map(Scope::new))
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+        let pending = PendingLogin {
+            provider: name.to_string(),
+            state: state.secret().clone(),
+            pkce_verifier: pkce_verifier.secret().clone(),
+        };
+        (url.to_string(), pending)
+    }
+
+    // Trades the code the provider redirected back with for an access token, and
+    // looks up the account it belongs to
+    pub async fn identity(&self, code: String, pending: PendingLogin) -> Result<Identity, DatastoreError> {
+        let failed = || DatastoreError::Unauthorized(format!("{} login failed", pending.provider));
+        let token = self
+            .client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pending.pkce_verifier.clone()))
+            .request_async(async_http_client)
+            .await
+            .map_err(|err| {
+                log::warn!("{} token request failed: {}", pending.provider, err);
+                failed()
+            })?;
+        let info: Value = async {
+            reqwest::Client::new()
+                .get(&self.userinfo_url)
+                .bearer_auth(token.access_token().secret())
+                .header(ACCEPT, "application/json")
+                // GitHub rejects requests without one
+                .header(USER_AGENT, "aw-leaderboard")
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        }
+        .await
+        .map_err(|err: reqwest::Error| {
+            log::warn!("{} userinfo request failed: {}", pending.provider, err);
+            failed()
+        })?;
+
+        let subject = match &info[&self.id_field] {
+            Value::String(id) => id.clone(),
+            Value::Number(id) => id.to_string(),
+            _ => return Err(failed()),
+        };
+        let username = info[&self.username_field].as_str().ok_or_else(failed)?.to_string();
+        let email = info[&self.email_field].as_str().map(String::from);
+        Ok(Identity { subject, username, email })
+    }
+}
+
+// The configured OAuth providers
+pub struct OAuth {
+    providers: BTreeMap<String, Provider>,
+}
+
+impl OAuth {
+    pub fn names(&self) -> Vec<&str> {
+        self.providers.keys().map(String::as_str).collect()
+    }
+
+    pub fn provider(&self, name: &str) -> Result<&Provider, DatastoreError> {
+        self.providers
+            .get(name)
+            .ok_or_else(|| DatastoreError::NotFound(format!("OAuth provider `{}`", name)))
+    }
+}
+
+// Sets up the providers from the configuration on ignite, aborting launch if one of
+// them is misconfigured
+pub async fn init(rocket: Rocket<Build>) -> fairing::Result {
+    let config = rocket.state::<Config>().expect("config is managed");
+    let mut providers = BTreeMap::new();
+    for (name, provider) in &config.oauth_providers {
+        let redirect_url = config.link(&format!("/oauth/{}/callback", name));
+        match Provider::new(provider, redirect_url) {
+            Ok(provider) => providers.insert(name.clone(), provider),
+            Err(err) => {
+                log::error!("Invalid URL for OAuth provider `{}`: {}", name, err);
+                return Err(rocket);
+            }
+        };
+    }
+    Ok(rocket.manage(OAuth { providers }))
+}
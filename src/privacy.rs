@@ -0,0 +1,125 @@
+use rocket::FromFormField;
+use serde::{Serialize, Deserialize};
+
+use crate::categories::PATH_SEPARATOR;
+use crate::db::{Db, Device, User};
+use crate::error::DatastoreError;
+use crate::leaderboard::{category_totals, CategoryTotal, Window};
+
+// Who can see a profile
+#[derive(Debug, Clone, Copy, Default, PartialEq, FromFormField, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    // Listed on the leaderboards and the users page
+    #[default]
+    Public,
+    // Left out of the leaderboards, but anyone with the link can see the profile
+    Unlisted,
+    // Only the user can see the profile
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Private => "private",
+        }
+    }
+
+    // Unknown values hide the profile rather than show it
+    pub fn parse(s: &str) -> Visibility {
+        match s {
+            "public" => Visibility::Public,
+            "unlisted" => Visibility::Unlisted,
+            _ => Visibility::Private,
+        }
+    }
+}
+
+// A category shared with others or hidden from them, along with its subcategories
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CategorySharing {
+    pub category: String,
+    pub shared: bool,
+}
+
+// What others may see of a user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Privacy {
+    pub visibility: Visibility,
+    // Whether the devices of the user are listed on their profile
+    pub share_devices: bool,
+    // Whether categories without a setting of their own are shared
+    pub share_categories: bool,
+    #[serde(default)]
+    pub categories: Vec<CategorySharing>,
+}
+
+impl Default for Privacy {
+    fn default() -> Self {
+        Privacy { visibility: Visibility::Public, share_devices: false, share_categories: true, categories: Vec::new() }
+    }
+}
+
+impl Privacy {
+    // Whether others may see the time of a category. The setting of the category
+    // itself wins over those of its parents, the closest parent over further ones.
+    pub fn shares(&self, category: &str) -> bool {
+        self.categories
+            .iter()
+            .filter(|setting| is_within(category, &setting.category))
+            .max_by_key(|setting| setting.category.len())
+            .map_or(self.share_categories, |setting| setting.shared)
+    }
+}
+
+// Whether the category is the given one or one of its subcategories
+pub fn is_within(category: &str, parent: &str) -> bool {
+    category
+        .strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(PATH_SEPARATOR))
+}
+
+// A profile as seen by a viewer. Views of a single user's activity go through it, so
+// what the user hides stays hidden from everyone but themselves.
+pub struct Profile {
+    pub user: User,
+    pub privacy: Privacy,
+    // Whether the viewer is the user
+    pub is_owner: bool,
+}
+
+impl Profile {
+    // Private profiles of others are missing just like users that don't exist, and so are
+    // accounts pending deletion
+    pub fn view(db: &Db, username: &str, viewer: Option<&User>) -> Result<Profile, DatastoreError> {
+        let user = db.get_user(username)?;
+        let privacy = db.get_privacy(user.id)?;
+        let is_owner = viewer.is_some_and(|viewer| viewer.id == user.id);
+        if (privacy.visibility == Visibility::Private || user.deletion_due.is_some()) && !is_owner {
+            return Err(DatastoreError::NotFound(format!("user `{}`", username)));
+        }
+        Ok(Profile { user, privacy, is_owner })
+    }
+
+    pub fn shows(&self, category: &str) -> bool {
+        self.is_owner || self.privacy.shares(category)
+    }
+
+    pub fn shows_devices(&self) -> bool {
+        self.is_owner || self.privacy.share_devices
+    }
+
+    pub fn devices(&self, db: &Db) -> Result<Vec<Device>, DatastoreError> {
+        match self.shows_devices() {
+            true => db.get_devices(self.user.id),
+            false => Ok(Vec::new()),
+        }
+    }
+
+    pub fn category_totals(&self, db: &Db, window: Window) -> Result<Vec<CategoryTotal>, DatastoreError> {
+        category_totals(db, self.user.id, window, |category| self.shows(category))
+    }
+}
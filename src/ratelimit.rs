@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::{uri::Origin, Method};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Build, Data, Request, Rocket};
+
+use crate::config::{Config, Limit, RateLimits, RouteLimits};
+use crate::error::DatastoreError;
+
+// Tables never grow past this many entries, so requests from ever changing addresses
+// or for made up accounts can't grow them without bound
+const MAX_ENTRIES: usize = 10_000;
+// Once full, a table is cut down to this many so that happens only every so often
+const KEEP_ENTRIES: usize = MAX_ENTRIES * 3 / 4;
+// Requests over the limit of their IP are rerouted here, before they reach their handler
+const LIMITED_PATH: &str = "/rate-limited";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    Auth,
+    Ingest,
+}
+
+impl RouteGroup {
+    // The group of a request, going by its method and path as it isn't routed yet
+    fn of(request: &Request) -> Option<RouteGroup> {
+        let path = request.uri().path();
+        let path = path.as_str().trim_end_matches('/');
+        match request.method() {
+            Method::Post => match path {
+                "/login" | "/login/2fa" | "/signup" | "/verify-email" | "/reset-password" | "/reset-password/confirm"
+                | "/2fa/enable" | "/2fa/disable" | "/2fa/recovery-codes" => Some(RouteGroup::Auth),
+                "/api/activity" | "/api/activity/batch" => Some(RouteGroup::Ingest),
+                _ => None,
+            },
+            Method::Get if path.starts_with("/oauth/") && path.ends_with("/callback") => Some(RouteGroup::Auth),
+            _ => None,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum Subject {
+    Ip(Option<IpAddr>),
+    Account(String),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_minute as f64 / 60.0).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+// Failed logins of an account in a row
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+// Token buckets and login failures, shared between all requests. Nothing is persisted,
+// so limits start over when the server restarts.
+pub struct RateLimiter {
+    config: RateLimits,
+    buckets: Mutex<HashMap<(RouteGroup, Subject), Bucket>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimits) -> RateLimiter {
+        RateLimiter { config, buckets: Mutex::new(HashMap::new()), failures: Mutex::new(HashMap::new()) }
+    }
+
+    fn limit(&self, group: RouteGroup, subject: &Subject) -> &Limit {
+        let limits = match group {
+            RouteGroup::Auth => &self.config.auth,
+            RouteGroup::Ingest => &self.config.ingest,
+        };
+        match subject {
+            Subject::Ip(_) => &limits.per_ip,
+            Subject::Account(_) => &limits.per_account,
+        }
+    }
+
+    // Takes a token from the bucket of the subject, or tells how many seconds until
+    // there is one
+    fn take(&self, group: RouteGroup, subject: Subject) -> Result<(), u64> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // A full bucket is the same as no bucket
+        evict(
+            &mut buckets,
+            |(group, subject), bucket| {
+                let limit = self.limit(*group, subject);
+                bucket.refill(limit, now);
+                bucket.tokens < limit.burst as f64
+            },
+            |bucket| bucket.updated,
+        );
+        let limit = self.limit(group, &subject);
+        let bucket = buckets
+            .entry((group, subject))
+            .or_insert(Bucket { tokens: limit.burst as f64, updated: now });
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(((1.0 - bucket.tokens) * 60.0 / limit.per_minute as f64).ceil() as u64)
+    }
+
+    // Limits requests on behalf of an account, such as logins with its username or
+    // uploads with its keys
+    pub fn check_account(&self, group: RouteGroup, account: &str) -> Result<(), DatastoreError> {
+        self.take(group, Subject::Account(account.to_string()))
+            .map_err(|retry_after| DatastoreError::RateLimited { retry_after })
+    }
+
+    // Refuses logins to an account that is locked after failed attempts, without
+    // checking the password
+    pub fn check_lockout(&self, account: &str) -> Result<(), DatastoreError> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        match failures.get(account).and_then(|failures| failures.locked_until) {
+            Some(until) if until > now => {
+                let retry_after = (until - now).as_secs_f64().ceil() as u64;
+                Err(DatastoreError::RateLimited { retry_after })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Counts a wrong password or code. After enough of them in a row the account is
+    // locked, for twice as long with every further failure.
+    pub fn login_failed(&self, account: &str) {
+        if !self.config.enabled {
+            return;
+        }
+        let lockout = &self.config.lockout;
+        // Failures are forgotten once the longest lockout has passed without another
+        let forget_after = Duration::from_secs(lockout.max_seconds);
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        evict(&mut failures, |_, failures| now.duration_since(failures.last) < forget_after, |failures| failures.last);
+        let entry = failures
+            .entry(account.to_string())
+            .or_insert(Failures { count: 0, last: now, locked_until: None });
+        if now.duration_since(entry.last) >= forget_after {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        if entry.count >= lockout.failures {
+            let doublings = (entry.count - lockout.failures).min(32);
+            let seconds = lockout.seconds.saturating_mul(1 << doublings).min(lockout.max_seconds);
+            entry.locked_until = Some(now + Duration::from_secs(seconds));
+        }
+    }
+
+    pub fn login_succeeded(&self, account: &str) {
+        self.failures.lock().unwrap().remove(account);
+    }
+}
+
+// Makes room in a full table, first by dropping the entries `keep` refuses and then the
+// least recently updated ones
+fn evict<K, V>(table: &mut HashMap<K, V>, mut keep: impl FnMut(&K, &mut V) -> bool, updated: impl Fn(&V) -> Instant) {
+    if table.len() < MAX_ENTRIES {
+        return;
+    }
+    table.retain(|key, value| keep(key, value));
+    if table.len() > KEEP_ENTRIES {
+        let mut times: Vec<Instant> = table.values().map(&updated).collect();
+        let cutoff = *times.select_nth_unstable(table.len() - KEEP_ENTRIES).1;
+        table.retain(|_, value| updated(value) > cutoff);
+    }
+}
+
+fn validate(config: &RateLimits) -> Result<(), String> {
+    let groups: [(&str, &RouteLimits); 2] = [("auth", &config.auth), ("ingest", &config.ingest)];
+    for (name, limits) in groups {
+        for limit in [&limits.per_ip, &limits.per_account] {
+            if limit.burst == 0 || limit.per_minute == 0 {
+                return Err(format!("limits of `{}` must allow at least one request, set `enabled = false` to turn them off", name));
+            }
+        }
+    }
+    if config.lockout.failures == 0 {
+        return Err("lockout can't start before the first failure".to_string());
+    }
+    Ok(())
+}
+
+// Set on requests the fairing rerouted, with the seconds until they may be retried
+struct Exceeded(Option<u64>);
+
+#[rocket::async_trait]
+impl <'a> FromRequest<'a> for &'a Exceeded {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.local_cache(|| Exceeded(None)) {
+            exceeded @ Exceeded(Some(_)) => Outcome::Success(exceeded),
+            Exceeded(None) => Outcome::Forward(()),
+        }
+    }
+}
+
+#[get("/rate-limited")]
+fn rate_limited(exceeded: &Exceeded) -> DatastoreError {
+    DatastoreError::RateLimited { retry_after: exceeded.0.unwrap_or_default() }
+}
+
+// Limits requests to the routes of each group per client IP. Limits per account are
+// checked by the endpoints, once they know the account.
+pub struct RateLimit;
+
+#[rocket::async_trait]
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info { name: "Rate limits", kind: Kind::Ignite | Kind::Request }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let config = rocket.state::<Config>().expect("config is managed");
+        if let Err(err) = validate(&config.rate_limits) {
+            log::error!("Invalid rate limits: {}", err);
+            return Err(rocket);
+        }
+        let limiter = RateLimiter::new(config.rate_limits.clone());
+        Ok(rocket.manage(limiter).mount("/", routes![rate_limited]))
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let (Some(limiter), Some(group)) = (request.rocket().state::<RateLimiter>(), RouteGroup::of(request)) else {
+            return;
+        };
+        if let Err(retry_after) = limiter.take(group, Subject::Ip(request.client_ip())) {
+            request.local_cache(|| Exceeded(Some(retry_after)));
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(LIMITED_PATH).unwrap());
+        }
+    }
+}
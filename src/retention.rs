@@ -0,0 +1,61 @@
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::config::Retention;
+use crate::db::{start_of_day, Db};
+use crate::error::DatastoreError;
+
+// Users may keep their activity for at most about a century
+pub const MAX_RETENTION_DAYS: u32 = 36_500;
+
+// What happens to hourly activity once it's past its retention
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetentionAction {
+    // The hours are removed but their time stays in the daily rollups, so the
+    // leaderboards and profiles still count it
+    #[default]
+    Compact,
+    // The hours are removed along with their time
+    Delete,
+}
+
+// Hours of activity of a user that were pruned, or would be in a dry run
+#[derive(Debug, Serialize)]
+pub struct Pruned {
+    pub user_id: i64,
+    pub before: DateTime<Utc>,
+    pub hours: usize,
+}
+
+pub fn check_retention_days(days: u32) -> Result<(), DatastoreError> {
+    if days == 0 || days > MAX_RETENTION_DAYS {
+        return Err(DatastoreError::BadRequest(format!("retention must be between 1 and {} days", MAX_RETENTION_DAYS)));
+    }
+    Ok(())
+}
+
+// Start of the first day of hourly activity that is kept, None if all of it is.
+// Whole days are kept so the daily rollups of a day are either all there is or not.
+pub fn cutoff(days: Option<u32>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    days.map(|days| start_of_day(now) - chrono::Duration::days(days.into()))
+}
+
+// Prunes the activity of every user past their retention, or the default one for users
+// without their own. A dry run only counts what would be pruned.
+pub fn prune(db: &Db, config: &Retention, now: DateTime<Utc>, dry_run: bool) -> Result<Vec<Pruned>, DatastoreError> {
+    let mut pruned = Vec::new();
+    for (user_id, days) in db.get_retentions()? {
+        let Some(before) = cutoff(days.or(config.days), now) else {
+            continue;
+        };
+        let hours = match dry_run {
+            true => db.count_activity_before(user_id, before)?,
+            false => db.prune_activity(user_id, before, config.action)?,
+        };
+        if hours > 0 {
+            pruned.push(Pruned { user_id, before, hours });
+        }
+    }
+    Ok(pruned)
+}
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use uuid::Uuid;
+
+use crate::db::{start_of_day, Db, Event, ReportMode, Rule};
+use crate::error::DatastoreError;
+
+// Demo users, all with the password `demo`
+pub const DEMO_USERS: &[&str] = &["alice", "bob", "carol", "dave", "erin", "frank"];
+const DEMO_PASSWORD: &str = "demo";
+const DEMO_DEVICES: &[&str] = &["laptop", "desktop"];
+// Days of activity, ending with the current hour
+const DEMO_DAYS: i64 = 28;
+
+// Categories of the demo ruleset. Events are reported with the leaf name only.
+const DEMO_CATEGORIES: &[&[&str]] = &[
+    &["Work", "Programming"],
+    &["Work", "Meetings"],
+    &["Work", "Email"],
+    &["Media", "Video"],
+    &["Media", "Music"],
+    &["Social"],
+    &["Games"],
+];
+
+// Pseudo-random number for a seed (splitmix64), so the fixtures are the same on every run
+fn mix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Up to four back to back events for an hour of a demo device, or none if it was idle
+fn demo_events(user: usize, device: usize, hour: DateTime<Utc>) -> Vec<Event> {
+    let seed = ((user as u64) << 48) ^ ((device as u64) << 40) ^ (hour.timestamp() as u64 / 3600);
+    let mut rng = mix(seed);
+    let mut next = || {
+        rng = mix(rng);
+        rng
+    };
+
+    // Users are busy for a different share of their waking hours
+    let busy = 30 + (user as u64 * 11) % 50;
+    if !(8..23).contains(&hour.hour()) || next() % 100 >= busy {
+        return Vec::new();
+    }
+    // Weekends are for the laptop
+    let weekend = hour.weekday().number_from_monday() > 5;
+    if weekend && device > 0 {
+        return Vec::new();
+    }
+
+    let mut events = Vec::new();
+    let mut offset = 0;
+    for _ in 0..1 + next() % 4 {
+        let duration = 60 + next() % 780;
+        let category = DEMO_CATEGORIES[((next() % 10) as usize + user) % DEMO_CATEGORIES.len()];
+        events.push(Event {
+            timestamp: hour + chrono::Duration::seconds(offset as i64),
+            duration: Duration::from_secs(duration),
+            category: category.last().unwrap().to_string(),
+        });
+        offset += duration + next() % 60;
+    }
+    events
+}
+
+// Fills the database with demo users, each with a ruleset, two devices and four weeks
+// of activity, for development. Does nothing if the demo users already exist.
+pub fn seed_demo_data(db: &Db) -> Result<(), DatastoreError> {
+    if db.get_user(DEMO_USERS[0]).is_ok() {
+        log::info!("Demo data is already present");
+        return Ok(());
+    }
+
+    let rules: Vec<Rule> = DEMO_CATEGORIES
+        .iter()
+        .map(|path| Rule {
+            name: path.iter().map(|n| n.to_string()).collect(),
+            regex: path.last().unwrap().to_string(),
+            ignore_case: true,
+        })
+        .collect();
+    let now = Utc::now();
+    let first_hour = start_of_day(now) - chrono::Duration::days(DEMO_DAYS - 1);
+
+    for (u, username) in DEMO_USERS.iter().enumerate() {
+        db.add_user(username, &format!("{}@example.com", username), DEMO_PASSWORD)?;
+        let user = db.get_user(username)?;
+        db.set_email_verified(user.id)?;
+        let ruleset_id = db.create_ruleset(user.id, "Demo categories", rules.clone())?;
+        for (d, name) in DEMO_DEVICES.iter().enumerate() {
+            let device_id = Uuid::from_u128(((u as u128 + 1) << 64) | (d as u128 + 1));
+            db.add_device(user.id, device_id, name)?;
+
+            let mut reports = Vec::new();
+            let mut hour = first_hour;
+            while hour <= now {
+                let events = demo_events(u, d, hour);
+                if !events.is_empty() {
+                    reports.push((hour, events));
+                }
+                hour += chrono::Duration::hours(1);
+            }
+            for outcome in db.report_activity_batch(&device_id, ruleset_id, reports, ReportMode::Replace)? {
+                outcome?;
+            }
+        }
+    }
+    log::info!("Seeded demo data for {} users", DEMO_USERS.len());
+    Ok(())
+}
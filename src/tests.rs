@@ -1,11 +1,115 @@
 #[cfg(test)]
 mod tests {
-    use rocket::http::{ContentType, Status};
+    use rocket::http::{ContentType, Header, Status};
     use rocket::local::blocking::Client;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use chrono::{TimeZone, Timelike, Utc};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    use crate::db::{postgres::PostgresDb, sqlite::SqliteDb, Datastore, Db, Event, ReportMode, Rule};
+
+    // An empty database for a test. Runs on PostgreSQL if TEST_DATABASE_URL points to
+    // a server, in a schema of its own, and on in-memory SQLite otherwise. The SQLite
+    // database is shared by all connections of the pool, each would get an empty one of
+    // its own with a plain in-memory database.
+    fn test_db() -> Db {
+        match std::env::var("TEST_DATABASE_URL") {
+            Ok(url) => {
+                let mut config: postgres::Config = url.parse().unwrap();
+                let schema = format!("test_{}", Uuid::new_v4().simple());
+                let mut conn = config.connect(postgres::NoTls).unwrap();
+                conn.batch_execute(&format!("CREATE SCHEMA {}", schema)).unwrap();
+                config.options(&format!("-c search_path={}", schema));
+                Arc::new(PostgresDb::new(config).unwrap())
+            }
+            Err(_) => {
+                let uri = format!("file:{}?mode=memory&cache=shared", Uuid::new_v4());
+                Arc::new(SqliteDb::new(SqliteConnectionManager::file(uri)).unwrap())
+            }
+        }
+    }
+
+    // Hours of activity stored for a device
+    fn activity_count(db: &Db, device_id: &Uuid) -> usize {
+        let mut count = 0;
+        db.for_each_activity(device_id, &mut |_| {
+            count += 1;
+            Ok(())
+        })
+        .unwrap();
+        count
+    }
+
+    // The user `test` with a device, a ruleset and an hour of activity
+    fn init_test(db: &Db) {
+        // Creates a test user
+        db.add_user("test", "test@example.com", "test").unwrap();
+
+        // Create a test device
+        let user = db.get_user("test").unwrap();
+        db.set_email_verified(user.id).unwrap();
+        let device_id = Uuid::new_v4();
+        db.add_device(user.id, device_id, "test").unwrap();
+
+        // Create a ruleset
+        let rules: Vec<Rule> = vec![
+            Rule {
+                name: vec!["Work".to_string()],
+                regex: ".*".to_string(),
+                ignore_case: false,
+            },
+            Rule {
+                name: vec!["Media".to_string()],
+                regex: ".*".to_string(),
+                ignore_case: false,
+            },
+        ];
+        let ruleset_id = db.create_ruleset(user.id, "Ruleset example", rules).unwrap();
+
+        // Create some test activity
+        // Whole hour
+        let now = Utc::now()
+            .with_minute(0)
+            .unwrap()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let events = vec![
+            Event {
+                timestamp: now,
+                duration: Duration::from_secs(60),
+                category: "Work".to_string(),
+            },
+            Event {
+                timestamp: now + chrono::Duration::seconds(60),
+                duration: Duration::from_secs(60),
+                category: "Media".to_string(),
+            },
+        ];
+        db.report_activity(&device_id, ruleset_id, now, events, ReportMode::Replace).unwrap();
+    }
+
+    // Settings of the test servers, without the maintenance task running alongside the test
+    fn test_figment() -> rocket::figment::Figment {
+        rocket::Config::figment().merge(("maintenance", false))
+    }
+
+    fn rocket() -> rocket::Rocket<rocket::Build> {
+        let db = test_db();
+        init_test(&db);
+        crate::rocket_with_db(db).configure(test_figment())
+    }
 
     #[test]
     fn test_home() {
-        let rocket = crate::rocket();
+        let rocket = rocket();
         let client = Client::tracked(rocket).expect("valid rocket instance");
         let response = client.get("/").dispatch();
         assert_eq!(response.status(), Status::Ok);
@@ -13,7 +117,7 @@ mod tests {
 
     #[test]
     fn test_user_profile() {
-        let rocket = crate::rocket();
+        let rocket = rocket();
         let client = Client::tracked(rocket).expect("valid rocket instance");
         let response = client.get("/user/test").dispatch();
         assert_eq!(response.status(), Status::Ok);
@@ -21,11 +125,11 @@ mod tests {
 
     #[test]
     fn test_user_login_logout() {
-        let rocket = crate::rocket();
+        let rocket = rocket();
         let client = Client::tracked(rocket).expect("valid rocket instance");
 
         // Register a user
-        let response = client.post("/signup").header(ContentType::Form).body("username=testuser&email=testunit@example.com&password=test").dispatch();
+        let response = client.post("/signup").header(ContentType::Form).body("username=testuser&email=testunit@example.com&password=correct-horse").dispatch();
         assert_eq!(response.status(), Status::SeeOther); // Expect a redirect after successful registration
 
         // Attempt to login
@@ -40,4 +144,1405 @@ mod tests {
         let response = client.get("/logout").dispatch();
         assert_eq!(response.status(), Status::SeeOther); // Expect a redirect after successful logout
     }
+
+    #[test]
+    fn test_signup_validation() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let signup = |body: &str| client.post("/signup").header(ContentType::Form).body(body).dispatch();
+
+        let response = signup("username=a%20b&email=nope&password=password");
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let page = response.into_string().unwrap();
+        assert!(page.contains("Username may only contain letters"));
+        assert!(page.contains("Email is not a valid email address"));
+        assert!(page.contains("Password must mix at least two of"));
+        // What was entered is kept, but the password
+        assert!(page.contains(r#"value="nope""#));
+
+        let page = signup("username=Admin&email=admin@example.com&password=admin-1234").into_string().unwrap();
+        assert!(page.contains("Username is reserved"));
+        assert!(page.contains("Password must not contain your username or email"));
+
+        let response = signup("username=test&email=new@example.com&password=correct-horse");
+        assert_eq!(response.status(), Status::Conflict);
+        assert!(response.into_string().unwrap().contains("Username is already taken"));
+        let response = signup("username=newbie&email=test@example.com&password=correct-horse");
+        assert_eq!(response.status(), Status::Conflict);
+        assert!(response.into_string().unwrap().contains("Email already has an account"));
+
+        assert_eq!(signup("username=new_bie&email=%20new@example.com&password=correct-horse").status(), Status::SeeOther);
+        let db = client.rocket().state::<Db>().unwrap();
+        assert_eq!(db.get_user("new_bie").unwrap().email, "new@example.com");
+
+        assert_eq!(crate::validation::sanitize_username("Jane Doe!"), "JaneDoe");
+        assert_eq!(crate::validation::sanitize_username("_api"), "user-api");
+    }
+
+    #[test]
+    fn test_report_activity() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+        let device_id = Uuid::new_v4();
+        let report = format!(
+            r#"{{"device_id": "{}", "ruleset_id": 1, "hour": "2023-06-01T12:00:00Z", "events": [{{"timestamp": "2023-06-01T12:05:00Z", "duration": 600, "category": "Work"}}]}}"#,
+            device_id
+        );
+
+        // Reporting requires a logged in user
+        let response = client.post("/api/activity").header(ContentType::JSON).body(&report).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        client
+            .post("/login")
+            .header(ContentType::Form)
+            .body("username=test&password=test")
+            .dispatch();
+
+        // Unknown device
+        let response = client.post("/api/activity").header(ContentType::JSON).body(&report).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .post("/api/devices")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"id": "{}", "name": "laptop"}}"#, device_id))
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+
+        // Device ids are unique
+        let response = client
+            .post("/api/devices")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"id": "{}", "name": "laptop"}}"#, device_id))
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+
+        let response = client.post("/api/activity").header(ContentType::JSON).body(&report).dispatch();
+        assert_eq!(response.status(), Status::Created);
+        assert!(response.into_string().unwrap().contains("created"));
+
+        // Re-sending the same hour does not duplicate it
+        let response = client.post("/api/activity").header(ContentType::JSON).body(&report).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.into_string().unwrap().contains("unchanged"));
+
+        let merged = report
+            .replace("12:05:00Z", "12:30:00Z")
+            .replace(r#""events""#, r#""mode": "merge", "events""#);
+        let response = client.post("/api/activity").header(ContentType::JSON).body(&merged).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.into_string().unwrap().contains("replaced"));
+
+        // Events must lie within the reported hour
+        let response = client
+            .post("/api/activity")
+            .header(ContentType::JSON)
+            .body(report.replace("12:05:00Z", "13:05:00Z"))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response.into_string().unwrap().contains("\"message\""));
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+        let device_id = Uuid::new_v4();
+
+        client
+            .post("/login")
+            .header(ContentType::Form)
+            .body("username=test&password=test")
+            .dispatch();
+        client
+            .post("/api/devices")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"id": "{}", "name": "laptop"}}"#, device_id))
+            .dispatch();
+
+        let response = client
+            .post("/api/keys")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"name": "aw-server", "device_id": "{}"}}"#, device_id))
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let created: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let key_id = created["key"]["id"].as_i64().unwrap();
+        let bearer = Header::new("Authorization", format!("Bearer {}", created["secret"].as_str().unwrap()));
+
+        let response = client.get("/api/keys").dispatch();
+        assert!(!response.into_string().unwrap().contains("awlb_"));
+
+        client.get("/logout").dispatch();
+
+        let report = format!(
+            r#"{{"device_id": "{}", "ruleset_id": 1, "hour": "2023-06-01T12:00:00Z", "events": []}}"#,
+            device_id
+        );
+        let response = client
+            .post("/api/activity")
+            .header(ContentType::JSON)
+            .header(bearer.clone())
+            .body(&report)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+
+        // Device-scoped keys cannot manage keys
+        let response = client.get("/api/keys").header(bearer.clone()).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client
+            .post("/api/activity")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer awlb_invalid"))
+            .body(&report)
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // Revoked keys stop working
+        client
+            .post("/login")
+            .header(ContentType::Form)
+            .body("username=test&password=test")
+            .dispatch();
+        let response = client.delete(format!("/api/keys/{}", key_id)).dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        client.get("/logout").dispatch();
+        let response = client
+            .post("/api/activity")
+            .header(ContentType::JSON)
+            .header(bearer)
+            .body(&report)
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn test_login_tokens() {
+        use crate::tokens::Claims;
+        use jsonwebtoken::{encode, EncodingKey, Header as JwtHeader};
+
+        let figment = test_figment().merge(("jwt_secret", "test-secret"));
+        let client = Client::tracked(rocket().configure(figment)).expect("valid rocket instance");
+        let bearer = |token: &str| Header::new("Authorization", format!("Bearer {}", token));
+
+        // Logging in sets the token cookie, which authenticates API calls
+        client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+        let token = client.cookies().get_private("jwt").unwrap().value().to_string();
+        assert_eq!(client.get("/api/keys").dispatch().status(), Status::Ok);
+
+        // The token also works as a bearer token, but not once tampered with
+        assert_eq!(client.get("/api/keys").header(bearer(&token)).dispatch().status(), Status::Ok);
+        let tampered = format!("{}x", token);
+        assert_eq!(client.get("/api/keys").header(bearer(&tampered)).dispatch().status(), Status::Unauthorized);
+
+        // Logging out ends the session of the token
+        client.get("/logout").dispatch();
+        assert_eq!(client.get("/api/keys").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/api/keys").header(bearer(&token)).dispatch().status(), Status::Unauthorized);
+
+        // Expired tokens and tokens for another audience are rejected
+        let db = client.rocket().state::<Db>().unwrap();
+        let user = db.get_user("test").unwrap();
+        let session = db.create_session(user.id, Utc::now() + chrono::Duration::hours(1), None, None).unwrap();
+        let sign = |exp: i64, aud: &str| {
+            let claims = Claims {
+                sub: user.id.to_string(),
+                sid: session.id.to_string(),
+                iss: "aw-leaderboard".to_string(),
+                aud: aud.to_string(),
+                iat: Utc::now().timestamp() - 7200,
+                exp,
+            };
+            encode(&JwtHeader::default(), &claims, &EncodingKey::from_secret(b"test-secret")).unwrap()
+        };
+        let valid = sign(Utc::now().timestamp() + 3600, "aw-leaderboard");
+        assert_eq!(client.get("/api/keys").header(bearer(&valid)).dispatch().status(), Status::Ok);
+        let expired = sign(Utc::now().timestamp() - 3600, "aw-leaderboard");
+        let response = client.get("/api/keys").header(bearer(&expired)).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(response.into_string().unwrap().contains("expired"));
+        let other = sign(Utc::now().timestamp() + 3600, "elsewhere");
+        assert_eq!(client.get("/api/keys").header(bearer(&other)).dispatch().status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn test_sessions() {
+        let rocket = rocket();
+        let laptop = Client::tracked(rocket).expect("valid rocket instance");
+        let login = |client: &Client, user_agent: &'static str| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .header(Header::new("User-Agent", user_agent))
+                .body("username=test&password=test")
+                .dispatch();
+        };
+        login(&laptop, "Laptop browser");
+
+        // The same account is also logged in on a phone
+        let db = laptop.rocket().state::<Db>().unwrap();
+        let user = db.get_user("test").unwrap();
+        let phone_session = db.create_session(user.id, Utc::now() + chrono::Duration::hours(1), Some("Phone browser"), None).unwrap();
+
+        let response = laptop.get("/api/sessions").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let sessions: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let sessions = sessions.as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        let current: Vec<_> = sessions.iter().filter(|s| s["current"] == true).collect();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0]["user_agent"], "Laptop browser");
+
+        let page = laptop.get("/sessions").dispatch().into_string().unwrap();
+        assert!(page.contains("Phone browser"));
+
+        // Revoking the other session leaves the current one logged in
+        let response = laptop.delete(format!("/api/sessions/{}", phone_session.id)).dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert!(db.use_session(&phone_session.id).is_err());
+        let response = laptop.delete(format!("/api/sessions/{}", phone_session.id)).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(laptop.get("/api/keys").dispatch().status(), Status::Ok);
+
+        // Logging out everywhere else from the page
+        let other = db.create_session(user.id, Utc::now() + chrono::Duration::hours(1), None, None).unwrap();
+        let response = laptop.post("/sessions/revoke-others").dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert!(db.use_session(&other.id).is_err());
+        assert_eq!(db.get_sessions(user.id).unwrap().len(), 1);
+    }
+
+    // A minimal OAuth provider on a local port. The token endpoint hands out the code it
+    // is given as the access token, and the userinfo endpoint returns the account of that code.
+    fn mock_oauth_server(accounts: HashMap<&'static str, serde_json::Value>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let (mut length, mut authorization) = (0, String::new());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(':') {
+                        Some((name, value)) if name.eq_ignore_ascii_case("content-length") => length = value.trim().parse().unwrap(),
+                        Some((name, value)) if name.eq_ignore_ascii_case("authorization") => authorization = value.trim().to_string(),
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let body = String::from_utf8(body).unwrap();
+
+                let code = body.split('&').find_map(|param| param.strip_prefix("code="));
+                let token = authorization.strip_prefix("Bearer ");
+                let (status, response) = if request_line.starts_with("POST /token") && body.contains("code_verifier=") {
+                    match code {
+                        Some(code) => ("200 OK", serde_json::json!({"access_token": code, "token_type": "bearer"})),
+                        None => ("400 Bad Request", serde_json::json!({"error": "invalid_grant"})),
+                    }
+                } else if request_line.starts_with("GET /user ") && token.is_some_and(|t| accounts.contains_key(t)) {
+                    ("200 OK", accounts[token.unwrap()].clone())
+                } else {
+                    ("401 Unauthorized", serde_json::json!({}))
+                };
+                let response = response.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_oauth_login() {
+        let accounts = HashMap::from([
+            ("octocat", serde_json::json!({"id": 1, "login": "octocat", "email": "octocat@example.com"})),
+            ("test", serde_json::json!({"id": 2, "login": "test", "email": "test@users.example.com"})),
+            ("taken", serde_json::json!({"id": 3, "login": "someone", "email": "test@example.com"})),
+        ]);
+        let server = mock_oauth_server(accounts);
+        let figment = test_figment()
+            .merge(("oauth_providers.mock.client_id", "client"))
+            .merge(("oauth_providers.mock.client_secret", "secret"))
+            .merge(("oauth_providers.mock.auth_url", format!("{}/authorize", server)))
+            .merge(("oauth_providers.mock.token_url", format!("{}/token", server)))
+            .merge(("oauth_providers.mock.userinfo_url", format!("{}/user", server)));
+        let client = Client::tracked(rocket().configure(figment)).expect("valid rocket instance");
+        let db = client.rocket().state::<Db>().unwrap();
+
+        let page = client.get("/login").dispatch().into_string().unwrap();
+        assert!(page.contains("/oauth/mock"));
+
+        // Goes through the provider, which sends the user back with a code
+        let login = |code: &str| {
+            let response = client.get("/oauth/mock").dispatch();
+            assert_eq!(response.status(), Status::SeeOther);
+            let location = response.headers().get_one("Location").unwrap();
+            assert!(location.starts_with(&format!("{}/authorize?", server)));
+            assert!(location.contains("code_challenge="));
+            let state = location.split(['?', '&']).find_map(|param| param.strip_prefix("state=")).unwrap();
+            client.get(format!("/oauth/mock/callback?code={}&state={}", code, state)).dispatch()
+        };
+
+        // The state has to match the login that was started
+        client.get("/oauth/mock").dispatch();
+        let response = client.get("/oauth/mock/callback?code=octocat&state=forged").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // Signs up a new user on the first login, and logs in the same user afterwards
+        let response = login("octocat");
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some("/user/octocat"));
+        assert_eq!(client.get("/api/sessions").dispatch().status(), Status::Ok);
+        let user = db.get_user("octocat").unwrap();
+        assert_eq!(user.email, "octocat@example.com");
+        client.get("/logout").dispatch();
+        assert_eq!(login("octocat").headers().get_one("Location"), Some("/user/octocat"));
+        assert_eq!(db.get_oauth_user("mock", "1").unwrap().id, user.id);
+        client.get("/logout").dispatch();
+
+        // Logged in users link the provider account to theirs
+        client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+        assert_eq!(login("test").headers().get_one("Location"), Some("/user/test"));
+        assert_eq!(db.get_oauth_user("mock", "2").unwrap().username, "test");
+        assert_eq!(login("octocat").status(), Status::Conflict);
+        client.get("/logout").dispatch();
+        assert_eq!(login("test").headers().get_one("Location"), Some("/user/test"));
+        client.get("/logout").dispatch();
+
+        // Existing emails aren't taken over by signing up through a provider
+        assert_eq!(login("taken").status(), Status::Conflict);
+        assert!(db.get_user("someone").is_err());
+
+        // An unknown code fails at the provider
+        assert_eq!(login("unknown").status(), Status::Unauthorized);
+    }
+
+    // A server whose emails are appended to a file of their own
+    fn mail_client() -> (Client, PathBuf) {
+        let mail = std::env::temp_dir().join(format!("aw-leaderboard-mail-{}.txt", Uuid::new_v4()));
+        let figment = test_figment()
+            .merge(("mailer.kind", "file"))
+            .merge(("mailer.path", &mail));
+        (Client::tracked(rocket().configure(figment)).expect("valid rocket instance"), mail)
+    }
+
+    // Path of the last link that was emailed
+    fn last_mailed_link(mail: &PathBuf) -> String {
+        let mail = std::fs::read_to_string(mail).unwrap();
+        let link = mail.split_whitespace().rev().find(|word| word.starts_with("http://")).unwrap();
+        link.strip_prefix("http://localhost:8000").unwrap().to_string()
+    }
+
+    #[test]
+    fn test_email_verification() {
+        let (client, mail) = mail_client();
+        let db = client.rocket().state::<Db>().unwrap();
+        let response = client
+            .post("/signup")
+            .header(ContentType::Form)
+            .body("username=newbie&email=newbie@example.com&password=correct-horse")
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert!(std::fs::read_to_string(&mail).unwrap().contains("To: newbie@example.com"));
+
+        // Unverified users have their activity left out of the leaderboards
+        let user = db.get_user("newbie").unwrap();
+        assert!(!user.email_verified);
+        let device_id = Uuid::new_v4();
+        db.add_device(user.id, device_id, "laptop").unwrap();
+        let rules = vec![Rule { name: vec!["Work".to_string()], regex: ".*".to_string(), ignore_case: false }];
+        let ruleset_id = db.create_ruleset(user.id, "Work", rules).unwrap();
+        let hour = crate::db::start_of_day(Utc::now());
+        let events = vec![Event { timestamp: hour, duration: Duration::from_secs(600), category: "Work".to_string() }];
+        db.report_activity(&device_id, ruleset_id, hour, events, ReportMode::Replace).unwrap();
+        let leaderboard = client.get("/api/leaderboard").dispatch().into_string().unwrap();
+        assert!(!leaderboard.contains("newbie"));
+        assert!(!client.get("/users").dispatch().into_string().unwrap().contains("newbie"));
+
+        let response = client.get("/verify-email?token=forged").dispatch();
+        assert!(response.into_string().unwrap().contains("invalid token"));
+        let response = client.get(last_mailed_link(&mail)).dispatch();
+        assert!(response.into_string().unwrap().contains("Email confirmed"));
+        assert!(db.get_user("newbie").unwrap().email_verified);
+        let leaderboard = client.get("/api/leaderboard").dispatch().into_string().unwrap();
+        assert!(leaderboard.contains("newbie"));
+
+        std::fs::remove_file(&mail).unwrap();
+    }
+
+    #[test]
+    fn test_password_reset() {
+        let (client, mail) = mail_client();
+        let db = client.rocket().state::<Db>().unwrap();
+        client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+
+        // Unknown emails get the same answer, without anything being sent
+        let response = client.post("/reset-password").header(ContentType::Form).body("email=nobody@example.com").dispatch();
+        assert!(response.into_string().unwrap().contains("Check your email"));
+        assert!(!mail.exists());
+
+        let response = client.post("/reset-password").header(ContentType::Form).body("email=test@example.com").dispatch();
+        assert!(response.into_string().unwrap().contains("Check your email"));
+        let link = last_mailed_link(&mail);
+        assert!(link.starts_with("/reset-password/confirm?token="));
+        let token = link.split("token=").nth(1).unwrap().to_string();
+        assert!(client.get(&link).dispatch().into_string().unwrap().contains(&token));
+
+        // Reset tokens are not login tokens
+        let bearer = Header::new("Authorization", format!("Bearer {}", token));
+        assert_eq!(client.get("/api/sessions").header(bearer).dispatch().status(), Status::Unauthorized);
+
+        let set_password = |password: &str| {
+            client
+                .post("/reset-password/confirm")
+                .header(ContentType::Form)
+                .body(format!("token={}&password={}", token, password))
+                .dispatch()
+        };
+        assert!(set_password("short").into_string().unwrap().contains("at least 8 characters"));
+        let response = set_password("changed-1");
+        assert_eq!(response.status(), Status::SeeOther);
+        assert!(!db.check_password("test", "test").unwrap());
+        assert!(db.check_password("test", "changed-1").unwrap());
+        // Existing logins are ended
+        assert_eq!(client.get("/api/sessions").dispatch().status(), Status::Unauthorized);
+
+        // Links work only once
+        assert!(set_password("again-123").into_string().unwrap().contains("already been used"));
+        assert!(db.check_password("test", "changed-1").unwrap());
+
+        std::fs::remove_file(&mail).unwrap();
+    }
+
+    #[test]
+    fn test_totp_codes() {
+        // Test vectors of RFC 6238, cut to six digits
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+        for (time, code) in [(59, "287082"), (1111111109, "081804"), (1234567890, "005924")] {
+            let now = Utc.timestamp_opt(time, 0).unwrap();
+            assert_eq!(crate::totp::current_code(secret, now), code);
+            assert!(crate::totp::verify(secret, code, now).is_some());
+        }
+        let now = Utc.timestamp_opt(59, 0).unwrap();
+        assert!(crate::totp::verify(secret, "287082", now + chrono::Duration::seconds(90)).is_none());
+        assert!(crate::totp::verify(secret, "28708", now).is_none());
+    }
+
+    #[test]
+    fn test_two_factor() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let db = client.rocket().state::<Db>().unwrap();
+        let user = db.get_user("test").unwrap();
+        let login = || {
+            client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch()
+        };
+        let post_code = |path: &str, code: &str| {
+            client.post(path.to_string()).header(ContentType::Form).body(format!("code={}", code)).dispatch()
+        };
+        login();
+
+        let page = client.post("/2fa/setup").dispatch().into_string().unwrap();
+        let secret = db.get_totp(user.id).unwrap().unwrap().secret;
+        assert!(page.contains("otpauth:") && page.contains(&secret));
+        assert_eq!(
+            crate::totp::otpauth_uri("test", &secret),
+            format!("otpauth://totp/aw-leaderboard:test?secret={}&issuer=aw-leaderboard&algorithm=SHA1&digits=6&period=30", secret)
+        );
+
+        // Enabling takes a code of the new secret
+        let code = crate::totp::current_code(&secret, Utc::now());
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        assert!(post_code("/2fa/enable", wrong).into_string().unwrap().contains("invalid code"));
+        assert!(!db.get_totp(user.id).unwrap().unwrap().enabled);
+        let page = post_code("/2fa/enable", &code).into_string().unwrap();
+        let recovery_codes: Vec<String> = regex::Regex::new(r"[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}")
+            .unwrap()
+            .find_iter(&page)
+            .map(|m| m.as_str().to_string())
+            .collect();
+        assert_eq!(recovery_codes.len(), 10);
+        assert_eq!(db.get_totp(user.id).unwrap().unwrap().recovery_codes, 10);
+
+        // The password alone no longer logs in
+        client.get("/logout").dispatch();
+        let response = login();
+        assert_eq!(response.headers().get_one("Location"), Some("/login/2fa"));
+        assert_eq!(client.get("/api/sessions").dispatch().status(), Status::Unauthorized);
+        // Codes work only once
+        let response = post_code("/login/2fa", &code);
+        assert!(response.into_string().unwrap().contains("invalid code"));
+        let next = crate::totp::current_code(&secret, Utc::now() + chrono::Duration::seconds(30));
+        let response = post_code("/login/2fa", &next);
+        assert_eq!(response.headers().get_one("Location"), Some("/user/test"));
+        assert_eq!(client.get("/api/sessions").dispatch().status(), Status::Ok);
+
+        // Recovery codes, however they are typed
+        client.get("/logout").dispatch();
+        login();
+        assert_eq!(post_code("/login/2fa", &recovery_codes[0]).status(), Status::SeeOther);
+        client.get("/logout").dispatch();
+        login();
+        assert!(post_code("/login/2fa", &recovery_codes[0]).into_string().unwrap().contains("invalid code"));
+        let typed = recovery_codes[1].replace('-', "").to_uppercase();
+        assert_eq!(post_code("/login/2fa", &typed).status(), Status::SeeOther);
+        assert_eq!(db.get_totp(user.id).unwrap().unwrap().recovery_codes, 8);
+
+        // Disabling takes a code too
+        assert!(post_code("/2fa/disable", "0000-0000-0000-0000").into_string().unwrap().contains("invalid code"));
+        assert_eq!(post_code("/2fa/disable", &recovery_codes[2]).status(), Status::SeeOther);
+        assert!(db.get_totp(user.id).unwrap().is_none());
+        client.get("/logout").dispatch();
+        assert_eq!(login().headers().get_one("Location"), Some("/user/test"));
+    }
+
+    #[test]
+    fn test_login_lockout() {
+        let figment = test_figment()
+            .merge(("rate_limits.lockout.failures", 3))
+            .merge(("rate_limits.lockout.seconds", 60));
+        let client = Client::tracked(rocket().configure(figment)).expect("valid rocket instance");
+        let login = |password: &str| {
+            client
+                .post("/login")
+                .header(ContentType::Form)
+                .body(format!("username=test&password={}", password))
+                .dispatch()
+        };
+
+        // A successful login forgives earlier failures
+        for _ in 0..2 {
+            assert_eq!(login("wrong").status(), Status::Unauthorized);
+        }
+        assert_eq!(login("test").status(), Status::SeeOther);
+        for _ in 0..2 {
+            assert_eq!(login("wrong").status(), Status::Unauthorized);
+        }
+        assert_eq!(login("wrong").status(), Status::Unauthorized);
+
+        // Locked now, even with the right password
+        let response = login("test");
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry_after: u64 = response.headers().get_one("Retry-After").unwrap().parse().unwrap();
+        assert!(retry_after > 0 && retry_after <= 60);
+        // Usernames without an account are locked the same way
+        let login_other = || client.post("/login").header(ContentType::Form).body("username=other&password=test").dispatch();
+        for _ in 0..3 {
+            assert_eq!(login_other().status(), Status::Unauthorized);
+        }
+        assert_eq!(login_other().status(), Status::TooManyRequests);
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let figment = test_figment()
+            .merge(("rate_limits.auth.per_ip.burst", 2))
+            .merge(("rate_limits.auth.per_ip.per_minute", 1))
+            .merge(("rate_limits.auth.per_account.burst", 10))
+            .merge(("rate_limits.auth.per_account.per_minute", 10))
+            .merge(("rate_limits.ingest.per_ip.burst", 10))
+            .merge(("rate_limits.ingest.per_ip.per_minute", 10))
+            .merge(("rate_limits.ingest.per_account.burst", 1))
+            .merge(("rate_limits.ingest.per_account.per_minute", 1));
+        let client = Client::tracked(rocket().configure(figment)).expect("valid rocket instance");
+        let login = || client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+
+        // Logins of the same IP run out, other pages don't
+        assert_eq!(login().status(), Status::SeeOther);
+        assert_eq!(login().status(), Status::SeeOther);
+        let response = login();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry_after: u64 = response.headers().get_one("Retry-After").unwrap().parse().unwrap();
+        assert!(retry_after > 0 && retry_after <= 60);
+        assert_eq!(client.get("/login").dispatch().status(), Status::Ok);
+        assert_eq!(client.get("/rate-limited").dispatch().status(), Status::NotFound);
+
+        // Uploads are limited per account
+        let device_id = Uuid::new_v4();
+        client
+            .post("/api/devices")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"id": "{}", "name": "laptop"}}"#, device_id))
+            .dispatch();
+        let report = format!(r#"{{"device_id": "{}", "ruleset_id": 1, "hour": "2023-06-01T12:00:00Z", "events": []}}"#, device_id);
+        let response = client.post("/api/activity").header(ContentType::JSON).body(&report).dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let response = client.post("/api/activity").header(ContentType::JSON).body(&report).dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry_after: u64 = response.headers().get_one("Retry-After").unwrap().parse().unwrap();
+        assert!(retry_after > 0 && retry_after <= 60);
+        assert!(response.into_string().unwrap().contains("too many requests"));
+    }
+
+    #[test]
+    fn test_release_requires_jwt_secret() {
+        let secret_key = hex::encode([7u8; 32]);
+        let release = test_figment()
+            .select(rocket::Config::RELEASE_PROFILE)
+            .merge(("secret_key", secret_key));
+        match Client::tracked(rocket().configure(release.clone())) {
+            Err(err) => assert!(matches!(err.kind(), rocket::error::ErrorKind::FailedFairings(_))),
+            Ok(_) => panic!("launched without a jwt_secret"),
+        }
+        assert!(Client::tracked(rocket().configure(release.merge(("jwt_secret", "release-secret")))).is_ok());
+    }
+
+    #[test]
+    fn test_report_activity_batch() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+        let device_id = Uuid::new_v4();
+
+        client
+            .post("/login")
+            .header(ContentType::Form)
+            .body("username=test&password=test")
+            .dispatch();
+        client
+            .post("/api/devices")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{"id": "{}", "name": "laptop"}}"#, device_id))
+            .dispatch();
+
+        let batch = format!(
+            r#"{{"device_id": "{}", "ruleset_id": 1, "reports": [
+                {{"hour": "2023-06-01T12:00:00Z", "events": [{{"timestamp": "2023-06-01T12:05:00Z", "duration": 600, "category": "Work"}}]}},
+                {{"hour": "2023-06-01T13:30:00Z", "events": []}},
+                {{"hour": "2023-06-01T14:00:00Z", "events": [{{"timestamp": "2023-06-01T12:05:00Z", "duration": 600, "category": "Work"}}]}},
+                {{"hour": "2023-06-01T15:00:00Z", "events": []}}
+            ]}}"#,
+            device_id
+        );
+        let response = client.post("/api/activity/batch").header(ContentType::JSON).body(&batch).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let results = body["results"].as_array().unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0]["outcome"], "created");
+        assert!(results[1]["error"].as_str().unwrap().contains("on the hour"));
+        assert!(results[2]["error"].as_str().unwrap().contains("not within the reported hour"));
+        assert_eq!(results[3]["outcome"], "created");
+
+        // Retrying the batch leaves the accepted hours unchanged
+        let response = client.post("/api/activity/batch").header(ContentType::JSON).body(&batch).dispatch();
+        let body: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(body["results"][0]["outcome"], "unchanged");
+        assert_eq!(body["results"][3]["outcome"], "unchanged");
+    }
+
+    #[test]
+    fn test_sanitize_events() {
+        use crate::validation::{sanitize_events, EventPolicy};
+        use chrono::TimeZone;
+
+        let hour = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+        let event = |minute: i64, secs: u64, category: &str| Event {
+            timestamp: hour + chrono::Duration::minutes(minute),
+            duration: Duration::from_secs(secs),
+            category: category.to_string(),
+        };
+        let events = vec![
+            event(-5, 600, "Work"),
+            event(10, 600, "Work"),
+            event(15, 600, "Work"),
+            event(20, 600, "Media"),
+            event(55, 600, "Media"),
+        ];
+
+        let err = sanitize_events(hour, events.clone(), EventPolicy::Reject).unwrap_err();
+        assert!(err.to_string().contains("not within the reported hour"));
+        let err = sanitize_events(hour, events[1..3].to_vec(), EventPolicy::Reject).unwrap_err();
+        assert!(err.to_string().contains("overlaps"));
+
+        let clipped = sanitize_events(hour, events.clone(), EventPolicy::Clip).unwrap();
+        assert_eq!(clipped, vec![
+            event(0, 300, "Work"),
+            event(10, 600, "Work"),
+            event(20, 300, "Work"),
+            event(25, 300, "Media"),
+            event(55, 300, "Media"),
+        ]);
+
+        let merged = sanitize_events(hour, events, EventPolicy::Merge).unwrap();
+        assert_eq!(merged, vec![
+            event(0, 300, "Work"),
+            event(10, 900, "Work"),
+            event(25, 300, "Media"),
+            event(55, 300, "Media"),
+        ]);
+
+        let err = sanitize_events(hour, vec![event(0, 7200, "Work")], EventPolicy::Reject).unwrap_err();
+        assert!(err.to_string().contains("longer than an hour"));
+
+        // Long events are clipped to the part within the hour, not cut to an hour first
+        let clipped = sanitize_events(hour, vec![event(-30, 7200, "Work")], EventPolicy::Clip).unwrap();
+        assert_eq!(clipped, vec![event(0, 3600, "Work")]);
+        let clipped = sanitize_events(hour, vec![event(30, u64::MAX, "Work")], EventPolicy::Merge).unwrap();
+        assert_eq!(clipped, vec![event(30, 1800, "Work")]);
+    }
+
+    #[test]
+    fn test_leaderboard() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        let response = client.get("/api/leaderboard?window=day").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let entries: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(entries[0]["rank"], 1);
+        assert_eq!(entries[0]["username"], "test");
+        assert_eq!(entries[0]["duration"], 120);
+
+        let response = client.get("/api/leaderboard?category=Work&window=all").dispatch();
+        let entries: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(entries[0]["duration"], 60);
+
+        let response = client.get("/api/leaderboard?category=Games").dispatch();
+        assert_eq!(response.into_string().unwrap(), "[]");
+
+        let response = client.get("/users?category=Work&window=month").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.into_string().unwrap().contains("@test"));
+    }
+
+    #[test]
+    fn test_leaderboard_rollups() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+        let work_total = |client: &Client| {
+            let response = client.get("/api/leaderboard?category=Work&window=all").dispatch();
+            let entries: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            entries[0]["duration"].as_u64().unwrap()
+        };
+
+        client
+            .post("/login")
+            .header(ContentType::Form)
+            .body("username=test&password=test")
+            .dispatch();
+        let response = client.get("/api/devices").dispatch();
+        let devices: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let device_id = devices[0]["id"].as_str().unwrap().to_string();
+
+        let report = |duration: u64| format!(
+            r#"{{"device_id": "{}", "ruleset_id": 1, "hour": "2023-06-01T12:00:00Z", "events": [{{"timestamp": "2023-06-01T12:00:00Z", "duration": {}, "category": "Work"}}]}}"#,
+            device_id, duration
+        );
+        client.post("/api/activity").header(ContentType::JSON).body(report(600)).dispatch();
+        assert_eq!(work_total(&client), 660);
+
+        // Replacing an hour updates the rollup instead of adding to it
+        client.post("/api/activity").header(ContentType::JSON).body(report(300)).dispatch();
+        assert_eq!(work_total(&client), 360);
+
+        client.rocket().state::<Db>().unwrap().rebuild_rollups().unwrap();
+        assert_eq!(work_total(&client), 360);
+
+        // An hour reported with another ruleset counts once, with the ruleset added last
+        let db = client.rocket().state::<Db>().unwrap();
+        let ruleset_id = db.create_ruleset(db.get_user("test").unwrap().id, "other", Vec::new()).unwrap();
+        let other_report = |duration: u64| report(duration).replace(r#""ruleset_id": 1"#, &format!(r#""ruleset_id": {}"#, ruleset_id));
+        client.post("/api/activity").header(ContentType::JSON).body(other_report(120)).dispatch();
+        assert_eq!(work_total(&client), 180);
+        client.post("/api/activity").header(ContentType::JSON).body(report(900)).dispatch();
+        assert_eq!(work_total(&client), 180);
+        db.rebuild_rollups().unwrap();
+        assert_eq!(work_total(&client), 180);
+    }
+
+    #[test]
+    fn test_retention() {
+        use crate::config::Retention;
+        use crate::db::start_of_day;
+        use crate::retention::{prune, RetentionAction};
+
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let db = client.rocket().state::<Db>().unwrap();
+        let user = db.get_user("test").unwrap();
+        let device_id = db.get_devices(user.id).unwrap()[0].id;
+        let now = Utc::now();
+        let report_old = |days_ago: i64, seconds: u64| {
+            let hour = start_of_day(now - chrono::Duration::days(days_ago)) + chrono::Duration::hours(12);
+            let events = vec![Event { timestamp: hour, duration: Duration::from_secs(seconds), category: "Work".to_string() }];
+            db.report_activity(&device_id, 1, hour, events, ReportMode::Replace)
+        };
+        let work_total = || {
+            db.get_category_totals(user.id, None).unwrap().into_iter().find(|(category, _)| category == "Work").unwrap().1
+        };
+        report_old(40, 600).unwrap();
+        let config = Retention { days: Some(30), action: RetentionAction::Compact, dry_run: false };
+
+        // A dry run only counts the hours past the retention
+        let pruned = prune(db, &config, now, true).unwrap();
+        assert_eq!(pruned.len(), 1);
+        assert_eq!((pruned[0].user_id, pruned[0].hours), (user.id, 1));
+        assert_eq!(activity_count(db, &device_id), 2);
+
+        // Compacting keeps the time of the pruned hours, even through a rebuild of the rollups
+        assert_eq!(prune(db, &config, now, false).unwrap()[0].hours, 1);
+        assert_eq!(activity_count(db, &device_id), 1);
+        assert_eq!(work_total(), 660);
+        db.rebuild_rollups().unwrap();
+        assert_eq!(work_total(), 660);
+        assert!(prune(db, &config, now, false).unwrap().is_empty());
+
+        // Compacted hours can't be reported again, their time is already in the rollups
+        let err = report_old(40, 600).unwrap_err();
+        assert!(err.to_string().contains("compacted"));
+        assert_eq!(work_total(), 660);
+        assert_eq!(db.get_daily_rollups(user.id).unwrap()[0].device_id, Some(device_id));
+
+        // Users choose their own retention
+        client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+        let response = client.put("/api/retention").header(ContentType::JSON).body(r#"{"days": 0}"#).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client.put("/api/retention").header(ContentType::JSON).body(r#"{"days": 7}"#).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(db.get_retention(user.id).unwrap(), Some(7));
+
+        // Deleting takes the time off the rollups too, along with the days compacted before
+        report_old(10, 300).unwrap();
+        assert_eq!(work_total(), 960);
+        let config = Retention { days: None, action: RetentionAction::Delete, dry_run: false };
+        assert_eq!(prune(db, &config, now, false).unwrap()[0].hours, 1);
+        assert_eq!(work_total(), 60);
+        let kept_from = start_of_day(now - chrono::Duration::days(7));
+        assert!(db.get_daily_rollups(user.id).unwrap().iter().all(|rollup| rollup.day >= kept_from));
+
+        // Without a retention of the user or the server, activity is kept
+        report_old(10, 300).unwrap();
+        client.put("/api/retention").header(ContentType::JSON).body(r#"{"days": null}"#).dispatch();
+        assert!(prune(db, &config, now, false).unwrap().is_empty());
+        assert_eq!(activity_count(db, &device_id), 2);
+
+        // Deleting a device takes its compacted days with it
+        let config = Retention { days: Some(7), action: RetentionAction::Compact, dry_run: false };
+        assert_eq!(prune(db, &config, now, false).unwrap()[0].hours, 1);
+        db.delete_device(&device_id).unwrap();
+        assert!(db.get_category_totals(user.id, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rulesets() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        client
+            .post("/login")
+            .header(ContentType::Form)
+            .body("username=test&password=test")
+            .dispatch();
+
+        let response = client
+            .post("/api/rulesets")
+            .header(ContentType::JSON)
+            .body(r#"{"name": "Mine", "rules": [{"name": ["Work", "Programming"], "regex": "vim|code"}]}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let created: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let id = created["id"].as_i64().unwrap();
+
+        let response = client
+            .put(format!("/api/rulesets/{}", id))
+            .header(ContentType::JSON)
+            .body(r#"{"name": "Renamed", "rules": []}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get(format!("/api/rulesets/{}", id)).dispatch();
+        assert!(response.into_string().unwrap().contains("Renamed"));
+
+        let response = client
+            .post("/api/rulesets")
+            .header(ContentType::JSON)
+            .body(r#"{"name": "Broken", "rules": [{"name": ["Work"], "regex": "("}]}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = client.get("/api/rulesets").dispatch();
+        let rulesets: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(rulesets.as_array().unwrap().len(), 2);
+
+        // The seeded ruleset has activity reported with it
+        let response = client.delete("/api/rulesets/1").dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+
+        let response = client.delete(format!("/api/rulesets/{}", id)).dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let response = client.get(format!("/api/rulesets/{}", id)).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_import_webui_categories() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        client
+            .post("/login")
+            .header(ContentType::Form)
+            .body("username=test&password=test")
+            .dispatch();
+
+        let export = r##"{"categories": [
+            {"id": 0, "name": ["Work"], "rule": {"type": "regex", "regex": "Google Docs"}, "data": {"color": "#0F0"}},
+            {"id": 1, "name": ["Work", "Programming"], "rule": {"type": "regex", "regex": "GitHub|Stack Overflow", "ignore_case": true}},
+            {"id": 2, "name": ["Media"], "rule": {"type": "none"}},
+            {"id": 3, "name": ["Media", "Games"], "rule": {"type": "regex", "regex": "Steam("}},
+            {"id": 4, "name": ["Comms"], "rule": {"type": "glob", "regex": "*slack*"}}
+        ]}"##;
+        let response = client
+            .post("/api/rulesets/import?name=Imported")
+            .header(ContentType::JSON)
+            .body(export)
+            .dispatch();
+        assert_eq!(response.status(), Status::Created);
+        let result: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+
+        let rules = result["ruleset"]["rules"].as_array().unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[1]["name"], serde_json::json!(["Work", "Programming"]));
+        assert_eq!(rules[1]["ignore_case"], true);
+
+        let skipped = result["skipped"].as_array().unwrap();
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped[0]["reason"].as_str().unwrap().contains("invalid regex"));
+        assert!(skipped[1]["reason"].as_str().unwrap().contains("unsupported rule type"));
+    }
+
+    #[test]
+    fn test_hierarchical_categories() {
+        let rocket = rocket();
+        let client = Client::tracked(rocket).expect("valid rocket instance");
+
+        client
+            .post("/login")
+            .header(ContentType::Form)
+            .body("username=test&password=test")
+            .dispatch();
+        let response = client.get("/api/devices").dispatch();
+        let devices: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let device_id = devices[0]["id"].as_str().unwrap().to_string();
+        let response = client
+            .post("/api/rulesets")
+            .header(ContentType::JSON)
+            .body(r#"{"name": "Nested", "rules": [{"name": ["Work"], "regex": "docs"}, {"name": ["Work", "Programming"], "regex": "vim"}]}"#)
+            .dispatch();
+        let ruleset: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+
+        // Leaf categories are resolved to their full path using the ruleset
+        let report = format!(
+            r#"{{"device_id": "{}", "ruleset_id": {}, "hour": "2023-06-01T12:00:00Z", "events": [
+                {{"timestamp": "2023-06-01T12:00:00Z", "duration": 600, "category": "Programming"}},
+                {{"timestamp": "2023-06-01T12:10:00Z", "duration": 300, "category": "Work"}}
+            ]}}"#,
+            device_id, ruleset["id"]
+        );
+        let response = client.post("/api/activity").header(ContentType::JSON).body(report).dispatch();
+        assert_eq!(response.status(), Status::Created);
+
+        let duration = |category: &str| {
+            let response = client.get(format!("/api/leaderboard?category={}&window=all", category)).dispatch();
+            let entries: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            entries[0]["duration"].as_u64().unwrap()
+        };
+        // The seeded activity adds 60 seconds of Work
+        assert_eq!(duration("Work"), 960);
+        assert_eq!(duration("Work%20%3E%20Programming"), 600);
+
+        let response = client.get("/api/users/test/categories?window=all").dispatch();
+        let categories: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(categories[1]["name"], serde_json::json!(["Work"]));
+        assert_eq!(categories[1]["duration"], 960);
+        assert_eq!(categories[2]["name"], serde_json::json!(["Work", "Programming"]));
+
+        let response = client.get("/user/test?window=all").dispatch();
+        assert!(response.into_string().unwrap().contains("Programming"));
+
+        // Changing the rules moves the reported time to the new categories, and later
+        // reports of the hour take off what the new rules added
+        let response = client
+            .put(format!("/api/rulesets/{}", ruleset["id"]))
+            .header(ContentType::JSON)
+            .body(r#"{"name": "Nested", "rules": [{"name": ["Work"], "regex": "docs"}, {"name": ["Code", "Programming"], "regex": "vim"}]}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(duration("Work"), 360);
+        assert_eq!(duration("Code"), 600);
+        let report = format!(
+            r#"{{"device_id": "{}", "ruleset_id": {}, "hour": "2023-06-01T12:00:00Z", "events": [
+                {{"timestamp": "2023-06-01T12:00:00Z", "duration": 300, "category": "Programming"}}
+            ]}}"#,
+            device_id, ruleset["id"]
+        );
+        client.post("/api/activity").header(ContentType::JSON).body(report).dispatch();
+        assert_eq!(duration("Work"), 60);
+        assert_eq!(duration("Code"), 300);
+    }
+
+    #[test]
+    fn test_privacy() {
+        use crate::privacy::{CategorySharing, Privacy};
+
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let login = || client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+        let get_json = |uri: &str| -> serde_json::Value {
+            let response = client.get(uri.to_string()).dispatch();
+            serde_json::from_str(&response.into_string().unwrap()).unwrap()
+        };
+
+        // Hidden categories are still shown to their user
+        login();
+        assert!(client.get("/privacy").dispatch().into_string().unwrap().contains("Media"));
+        let response = client.post("/privacy/categories").header(ContentType::Form).body("category=Work&sharing=hide").dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(get_json("/api/users/test/categories?window=all").as_array().unwrap().len(), 2);
+
+        // Others don't see them anywhere, and they don't count on the leaderboards
+        client.get("/logout").dispatch();
+        let categories = get_json("/api/users/test/categories?window=all");
+        assert_eq!(categories, serde_json::json!([{"name": ["Media"], "duration": 60}]));
+        assert_eq!(get_json("/api/leaderboard?window=all")[0]["duration"], 60);
+        assert_eq!(get_json("/api/leaderboard?category=Work&window=all"), serde_json::json!([]));
+        let page = client.get("/user/test?window=all").dispatch().into_string().unwrap();
+        assert!(!page.contains("Work"));
+        assert!(page.contains("Not shared."));
+
+        // Unlisted profiles are left out of the leaderboards but can still be seen
+        login();
+        let response = client
+            .put("/api/privacy")
+            .header(ContentType::JSON)
+            .body(r#"{"visibility": "unlisted", "share_devices": true, "share_categories": true}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        client.get("/logout").dispatch();
+        assert_eq!(get_json("/api/leaderboard?window=all"), serde_json::json!([]));
+        assert!(!client.get("/users?window=all").dispatch().into_string().unwrap().contains("@test"));
+        let page = client.get("/user/test?window=all").dispatch().into_string().unwrap();
+        assert!(page.contains("Work") && !page.contains("Not shared."));
+
+        // Private profiles are only seen by their user
+        login();
+        client.post("/privacy").header(ContentType::Form).body("visibility=private").dispatch();
+        assert_eq!(client.get("/api/users/test/categories").dispatch().status(), Status::Ok);
+        client.get("/logout").dispatch();
+        assert_eq!(client.get("/api/users/test/categories").dispatch().status(), Status::NotFound);
+        assert!(client.get("/user/test").dispatch().into_string().unwrap().contains("User not found"));
+
+        // The closest setting wins
+        let privacy = Privacy {
+            share_categories: false,
+            categories: vec![
+                CategorySharing { category: "Work".to_string(), shared: true },
+                CategorySharing { category: "Work > Meetings".to_string(), shared: false },
+            ],
+            ..Privacy::default()
+        };
+        assert!(privacy.shares("Work > Programming"));
+        assert!(!privacy.shares("Work > Meetings > Standup"));
+        assert!(!privacy.shares("Workout"));
+        assert!(!privacy.shares("Media"));
+    }
+
+    #[test]
+    fn test_no_credentials_in_responses() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let db = client.rocket().state::<Db>().unwrap();
+        let user = db.get_user("test").unwrap();
+        db.set_privacy(user.id, &crate::privacy::Privacy { share_devices: true, ..Default::default() }).unwrap();
+        let pages = [
+            "/", "/user/test?window=all", "/users?window=all", "/sessions", "/privacy", "/2fa", "/login", "/signup",
+            "/api/leaderboard?window=all", "/api/users/test/categories?window=all", "/api/sessions", "/api/keys",
+            "/api/devices", "/api/rulesets", "/api/privacy",
+        ];
+        let check = |logged_in: bool| {
+            for page in pages {
+                let response = client.get(page).dispatch();
+                let body = response.into_string().unwrap_or_default();
+                assert!(!body.contains(&user.password), "{} shows the password hash", page);
+                assert!(!body.contains("$2b$") && !body.contains("$2y$"), "{} shows a bcrypt hash", page);
+                assert!(!body.contains(&user.email), "{} shows the email", page);
+                assert!(!body.contains("user_id"), "{} shows the id of a user", page);
+                if logged_in && page == "/user/test?window=all" {
+                    assert!(body.contains("(you!)"));
+                }
+            }
+        };
+        check(false);
+        client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+        check(true);
+    }
+
+    #[test]
+    fn test_export() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let response = client.get("/api/export").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let db = client.rocket().state::<Db>().unwrap();
+        let user = db.get_user("test").unwrap();
+        let device_id = db.get_devices(user.id).unwrap()[0].id;
+        let ruleset_id = db.get_rulesets(user.id).unwrap()[0].id;
+        let hour = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+        let events = vec![Event { timestamp: hour, duration: Duration::from_secs(90), category: "Work, \"deep\"".to_string() }];
+        db.report_activity(&device_id, ruleset_id, hour, events, ReportMode::Replace).unwrap();
+        db.set_retention(user.id, Some(30)).unwrap();
+        let (_, api_key) = db.create_api_key(user.id, Some(&device_id), "laptop key").unwrap();
+        db.link_oauth_identity(user.id, "github", "12345").unwrap();
+
+        client
+            .post("/login")
+            .header(ContentType::Form)
+            .header(Header::new("User-Agent", "export-test"))
+            .body("username=test&password=test")
+            .dispatch();
+        db.set_totp_secret(user.id, "JBSWY3DPEHPK3PXP").unwrap();
+        let recovery_codes = db.enable_totp(user.id, 1).unwrap();
+        let response = client.get("/api/export").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::new("application", "zip")));
+        assert!(response.headers().get_one("Content-Disposition").unwrap().starts_with("attachment"));
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(response.into_bytes().unwrap())).unwrap();
+        let mut read = |name: &str| {
+            let mut contents = String::new();
+            archive.by_name(name).unwrap().read_to_string(&mut contents).unwrap();
+            contents
+        };
+
+        let account: serde_json::Value = serde_json::from_str(&read("account.json")).unwrap();
+        assert_eq!(account["username"], "test");
+        assert_eq!(account["email"], "test@example.com");
+        assert_eq!(account["devices"][0]["id"], device_id.to_string());
+        assert_eq!(account["rulesets"][0]["name"], "Ruleset example");
+        assert_eq!(account["privacy"]["visibility"], "public");
+        assert_eq!(account["retention_days"], 30);
+        assert_eq!(account["two_factor_enabled"], true);
+        assert_eq!(account["api_keys"][0]["name"], "laptop key");
+        assert_eq!(account["api_keys"][0]["device_id"], device_id.to_string());
+        assert_eq!(account["sessions"][0]["user_agent"], "export-test");
+        assert_eq!(account["oauth_identities"][0]["provider"], "github");
+        assert_eq!(account["oauth_identities"][0]["subject"], "12345");
+
+        let activity: Vec<serde_json::Value> = serde_json::from_str(&read("activity.json")).unwrap();
+        assert_eq!(activity.len(), 2);
+        assert_eq!(activity[0]["events"][0]["category"], "Work, \"deep\"");
+        assert_eq!(activity[0]["events"][0]["duration"], 90);
+
+        let daily: Vec<serde_json::Value> = serde_json::from_str(&read("daily.json")).unwrap();
+        assert_eq!(daily[0]["day"], "2023-06-01T00:00:00Z");
+        assert_eq!(daily[0]["device_id"], device_id.to_string());
+        assert_eq!(daily[0]["duration"], 90);
+
+        let csv = read(&format!("devices/{}.csv", device_id));
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "hour,ruleset_id,timestamp,duration,category");
+        assert_eq!(
+            lines[1],
+            format!("2023-06-01T12:00:00+00:00,{},2023-06-01T12:00:00+00:00,90,\"Work, \"\"deep\"\"\"", ruleset_id)
+        );
+
+        let everything: String = ["account.json", "activity.json"].iter().map(|name| read(name)).collect();
+        assert!(!everything.contains(&user.password), "the export has the password hash");
+        assert!(!everything.contains(&api_key), "the export has an API key secret");
+        assert!(!everything.contains("JBSWY3DPEHPK3PXP"), "the export has the second factor secret");
+        assert!(recovery_codes.iter().all(|code| !everything.contains(code)), "the export has a recovery code");
+    }
+
+    #[test]
+    fn test_delete_device() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let db = client.rocket().state::<Db>().unwrap();
+        let user = db.get_user("test").unwrap();
+        let device_id = db.get_devices(user.id).unwrap()[0].id;
+        let other = Uuid::new_v4();
+        db.add_device(user.id, other, "other").unwrap();
+        db.create_api_key(user.id, Some(&device_id), "scoped").unwrap();
+        assert_eq!(db.get_category_totals(user.id, None).unwrap().len(), 2);
+
+        let path = format!("/api/devices/{}", device_id);
+        assert_eq!(client.delete(&path).dispatch().status(), Status::Unauthorized);
+        client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+        assert_eq!(client.delete(&path).dispatch().status(), Status::NoContent);
+        assert_eq!(client.delete(&path).dispatch().status(), Status::NotFound);
+
+        // Its activity, keys and time on the leaderboards are gone, other devices stay
+        let devices = db.get_devices(user.id).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, other);
+        assert_eq!(activity_count(db, &device_id), 0);
+        assert!(db.get_api_keys(user.id).unwrap().is_empty());
+        assert!(db.get_category_totals(user.id, None).unwrap().is_empty());
+
+        // Devices of others can't be deleted
+        db.add_user("other", "other@example.com", "other").unwrap();
+        let theirs = Uuid::new_v4();
+        db.add_device(db.get_user("other").unwrap().id, theirs, "theirs").unwrap();
+        let response = client.delete(format!("/api/devices/{}", theirs)).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(db.get_device(&theirs).is_ok());
+    }
+
+    #[test]
+    fn test_delete_account() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let db = client.rocket().state::<Db>().unwrap().clone();
+        let user = db.get_user("test").unwrap();
+        let device_id = db.get_devices(user.id).unwrap()[0].id;
+        let (_, secret) = db.create_api_key(user.id, None, "key").unwrap();
+        db.set_privacy(user.id, &crate::privacy::Privacy::default()).unwrap();
+        let bearer = Header::new("Authorization", format!("Bearer {}", secret));
+
+        // Takes a login, and typing the username on the page
+        let response = client.delete("/api/account").header(bearer.clone()).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+        let response = client.post("/account/delete").header(ContentType::Form).body("username=nope").dispatch();
+        assert!(response.into_string().unwrap().contains("type your username"));
+        let response = client.post("/account/delete").header(ContentType::Form).body("username=test").dispatch();
+        assert!(response.into_string().unwrap().contains("were removed"));
+
+        // Everything of the user is gone, and others may take their name
+        assert!(matches!(db.get_user("test"), Err(crate::error::DatastoreError::NotFound(_))));
+        assert!(db.get_device(&device_id).is_err());
+        assert_eq!(activity_count(&db, &device_id), 0);
+        assert!(db.get_rulesets(user.id).unwrap().is_empty());
+        assert!(db.get_daily_totals(None).unwrap().is_empty());
+        assert!(db.get_sessions(user.id).unwrap().is_empty());
+        assert_eq!(client.get("/api/keys").header(bearer).dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/api/privacy").dispatch().status(), Status::Unauthorized);
+        db.add_user("test", "test@example.com", "test").unwrap();
+
+        // With a grace period the account is hidden until it's over, logging in restores it
+        let figment = test_figment().merge(("account_deletion_grace_days", 30));
+        let client = Client::tracked(rocket().configure(figment)).expect("valid rocket instance");
+        let db = client.rocket().state::<Db>().unwrap();
+        let user = db.get_user("test").unwrap();
+        let login = || client.post("/login").header(ContentType::Form).body("username=test&password=test").dispatch();
+        login();
+        let response = client.delete("/api/account").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let due: serde_json::Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert!(due["deletion_due"].is_string());
+        assert_eq!(client.get("/api/privacy").dispatch().status(), Status::Unauthorized);
+        assert!(client.get("/user/test").dispatch().into_string().unwrap().contains("User not found"));
+        assert!(crate::leaderboard::leaderboard(db, None, crate::leaderboard::Window::All).unwrap().is_empty());
+        login();
+        assert!(db.get_user("test").unwrap().deletion_due.is_none());
+        assert_eq!(crate::maintenance::purge_deleted_users(db, Utc::now() + chrono::Duration::days(31)).unwrap(), 0);
+
+        client.delete("/api/account").dispatch();
+        assert_eq!(crate::maintenance::purge_deleted_users(db, Utc::now()).unwrap(), 0);
+        assert_eq!(crate::maintenance::purge_deleted_users(db, Utc::now() + chrono::Duration::days(31)).unwrap(), 1);
+        assert!(db.get_user_by_id(user.id).is_err());
+    }
+
+    #[test]
+    fn test_seed_demo_data() {
+        use crate::leaderboard::{leaderboard, Window};
+        use crate::seed::{seed_demo_data, DEMO_USERS};
+
+        // A new database has no accounts until it is seeded
+        let db = test_db();
+        assert!(DEMO_USERS.iter().all(|username| db.get_user(username).is_err()));
+
+        seed_demo_data(&db).unwrap();
+        for username in DEMO_USERS {
+            let user = db.get_user(username).unwrap();
+            assert_eq!(db.get_devices(user.id).unwrap().len(), 2);
+        }
+        assert!(db.check_password("alice", "demo").unwrap());
+        let month = leaderboard(&db, None, Window::Month).unwrap();
+        assert_eq!(month.len(), DEMO_USERS.len());
+        assert!(!leaderboard(&db, Some("Work"), Window::Month).unwrap().is_empty());
+
+        // Seeding again keeps the existing data as it is
+        seed_demo_data(&db).unwrap();
+        assert_eq!(db.get_devices(db.get_user("alice").unwrap().id).unwrap().len(), 2);
+        let again = leaderboard(&db, None, Window::Month).unwrap();
+        let totals = |entries: &[crate::leaderboard::LeaderboardEntry]| {
+            entries.iter().map(|e| (e.username.clone(), e.duration)).collect::<Vec<_>>()
+        };
+        assert_eq!(totals(&month), totals(&again));
+    }
+
+    // Schema as created by Db::init before migrations existed
+    const LEGACY_SCHEMA: &str = "
+        CREATE TABLE user (
+              id              INTEGER PRIMARY KEY,
+              username        TEXT NOT NULL UNIQUE,
+              email           TEXT NOT NULL UNIQUE,
+              password        TEXT NOT NULL
+        );
+        CREATE TABLE device (
+              id              TEXT PRIMARY KEY,
+              user_id         INTEGER NOT NULL,
+              name            TEXT NOT NULL,
+              FOREIGN KEY(user_id) REFERENCES user(id)
+        );
+        CREATE TABLE activity (
+              id              INTEGER PRIMARY KEY,
+              timestamp       INTEGER NOT NULL,
+              device_id       TEXT NOT NULL,
+              events          BLOB NOT NULL,
+              ruleset_id      INTEGER NOT NULL,
+              FOREIGN KEY(device_id) REFERENCES device(id)
+              FOREIGN KEY(ruleset_id) REFERENCES ruleset(id)
+        );
+        CREATE TABLE ruleset (
+              id              INTEGER PRIMARY KEY,
+              user_id         INTEGER NOT NULL,
+              name            TEXT NOT NULL,
+              rules           BLOB NOT NULL,
+              FOREIGN KEY(user_id) REFERENCES user(id)
+        );
+        INSERT INTO user (id, username, email, password) VALUES (1, 'old', 'old@example.com', 'x');
+        INSERT INTO device (id, user_id, name) VALUES ('4fd5bd5e-3f16-4b40-8ad1-3a9e5d8a6a3b', 1, 'laptop');
+        INSERT INTO ruleset (id, user_id, name, rules) VALUES (1, 1, 'Rules', '[]');
+        INSERT INTO activity (timestamp, device_id, events, ruleset_id) VALUES
+            (1685620800, '4fd5bd5e-3f16-4b40-8ad1-3a9e5d8a6a3b', '[{\"timestamp\":\"2023-06-01T12:00:00Z\",\"duration\":60,\"category\":\"Work\"}]', 1),
+            (1685620800, '4fd5bd5e-3f16-4b40-8ad1-3a9e5d8a6a3b', '[{\"timestamp\":\"2023-06-01T12:00:00Z\",\"duration\":90,\"category\":\"Work\"}]', 1);
+    ";
+
+    #[test]
+    fn test_migrate_legacy_database() {
+        use crate::error::DatastoreError;
+        use crate::migrations::sqlite::{schema_version, MIGRATIONS};
+
+        let path = std::env::temp_dir().join(format!("aw-leaderboard-{}.db", Uuid::new_v4()));
+        rusqlite::Connection::open(&path).unwrap().execute_batch(LEGACY_SCHEMA).unwrap();
+
+        let db = SqliteDb::new(SqliteConnectionManager::file(&path)).unwrap();
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+
+        // Existing data survives, duplicate hours are dropped and rollups are backfilled
+        let user = db.get_user("old").unwrap();
+        assert!(user.created_at.is_none());
+        assert_eq!(db.get_daily_totals(None).unwrap(), vec![("old".to_string(), "Work".to_string(), 90)]);
+        drop(db);
+
+        // Migrating an up to date database is a no-op
+        SqliteDb::new(SqliteConnectionManager::file(&path)).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+
+        // Databases from a newer version are refused
+        conn.execute("UPDATE schema_version SET version = ?1", [MIGRATIONS.len() as i64 + 1]).unwrap();
+        let result = SqliteDb::new(SqliteConnectionManager::file(&path));
+        assert!(matches!(result, Err(DatastoreError::UnsupportedSchema { .. })));
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
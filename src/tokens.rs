@@ -0,0 +1,173 @@
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rocket::{fairing, Build, Rocket};
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::User;
+use crate::error::DatastoreError;
+
+// Signing key used when none is configured, only accepted in debug builds
+const DEV_JWT_SECRET: &str = "aw-leaderboard-development-secret";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    // ID of the logged in user
+    pub sub: String,
+    // ID of the session the token belongs to, so it stops working once revoked
+    pub sid: String,
+    pub iss: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+// What a token sent by email lets its holder do
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Purpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl Purpose {
+    pub fn lifetime(self) -> Duration {
+        match self {
+            Purpose::VerifyEmail => Duration::days(2),
+            Purpose::ResetPassword => Duration::hours(1),
+        }
+    }
+
+    // The part of the user the token is for. Once it changes the token stops working,
+    // which makes reset tokens single use.
+    fn subject_state(self, user: &User) -> &str {
+        match self {
+            Purpose::VerifyEmail => &user.email,
+            Purpose::ResetPassword => &user.password,
+        }
+    }
+}
+
+// Claims of the tokens in links sent by email. They lack the `sid` of login tokens,
+// and login tokens lack the `purpose`, so neither passes for the other.
+#[derive(Debug, Serialize, Deserialize)]
+struct EmailClaims {
+    sub: String,
+    purpose: Purpose,
+    // Hash of the state of the user the token was issued for
+    state: String,
+    iss: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
+
+fn state_hash(purpose: Purpose, user: &User) -> String {
+    hex::encode(Sha256::digest(purpose.subject_state(user).as_bytes()))
+}
+
+// Issues and verifies the JSON web tokens that keep users logged in, and the
+// time-limited tokens of links sent by email
+pub struct Tokens {
+    secret: Vec<u8>,
+    issuer: String,
+    audience: String,
+    pub expiry: Duration,
+}
+
+impl Tokens {
+    pub fn new(secret: &str, config: &Config) -> Tokens {
+        Tokens {
+            secret: secret.as_bytes().to_vec(),
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            expiry: Duration::seconds(config.jwt_expiry),
+        }
+    }
+
+    pub fn issue(&self, user_id: i64, session_id: &Uuid) -> String {
+        let now = Utc::now();
+        let claims = Claims {
+            sub: user_id.to_string(),
+            sid: session_id.to_string(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now.timestamp(),
+            exp: (now + self.expiry).timestamp(),
+        };
+        encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(&self.secret)).unwrap()
+    }
+
+    // Checks the signature, issuer, audience and expiry of a token
+    fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, DatastoreError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.iss = Some(self.issuer.clone());
+        validation.set_audience(&[&self.audience]);
+        match decode::<T>(token, &DecodingKey::from_secret(&self.secret), &validation) {
+            Ok(data) => Ok(data.claims),
+            Err(err) => match err.kind() {
+                ErrorKind::ExpiredSignature => Err(DatastoreError::Unauthorized("token has expired".to_string())),
+                _ => Err(DatastoreError::Unauthorized("invalid token".to_string())),
+            },
+        }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, DatastoreError> {
+        self.decode(token)
+    }
+
+    pub fn issue_link(&self, purpose: Purpose, user: &User) -> String {
+        let now = Utc::now();
+        let claims = EmailClaims {
+            sub: user.id.to_string(),
+            purpose,
+            state: state_hash(purpose, user),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now.timestamp(),
+            exp: (now + purpose.lifetime()).timestamp(),
+        };
+        encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(&self.secret)).unwrap()
+    }
+
+    // Checks the token of a link sent by email, returning the user it was issued for
+    pub fn verify_link(
+        &self,
+        token: &str,
+        purpose: Purpose,
+        get_user: impl FnOnce(i64) -> Result<User, DatastoreError>,
+    ) -> Result<User, DatastoreError> {
+        let claims: EmailClaims = self.decode(token)?;
+        let invalid = || DatastoreError::Unauthorized("invalid token".to_string());
+        if claims.purpose != purpose {
+            return Err(invalid());
+        }
+        let user = get_user(claims.sub.parse().map_err(|_| invalid())?).map_err(|_| invalid())?;
+        if claims.state != state_hash(purpose, &user) {
+            return Err(DatastoreError::Unauthorized("link has already been used".to_string()));
+        }
+        Ok(user)
+    }
+}
+
+// Sets up token signing from the configuration on ignite. Release builds must be
+// configured with a key of their own, otherwise launching is aborted.
+pub async fn init(rocket: Rocket<Build>) -> fairing::Result {
+    let config = rocket.state::<Config>().expect("config is managed");
+    let release = rocket.figment().profile() == rocket::Config::RELEASE_PROFILE;
+    let secret = match config.jwt_secret.as_deref() {
+        Some(secret) if !secret.is_empty() && secret != DEV_JWT_SECRET => secret,
+        _ if release => {
+            log::error!("jwt_secret must be configured in release mode");
+            return Err(rocket);
+        }
+        _ => {
+            log::warn!("jwt_secret is not configured, using the insecure development key");
+            DEV_JWT_SECRET
+        }
+    };
+    let tokens = Tokens::new(secret, config);
+    Ok(rocket.manage(tokens))
+}
@@ -0,0 +1,106 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+// Time-based one-time passwords (RFC 6238) with the parameters authenticator apps
+// default to: HMAC-SHA1, 6 digits and 30 second steps
+const DIGITS: u32 = 6;
+const STEP: i64 = 30;
+// Codes of the steps right before and after the current one are accepted too, for
+// clocks that are a little off
+const SKEW: i64 = 1;
+const ISSUER: &str = "aw-leaderboard";
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// A new secret, base32 encoded like authenticator apps expect it
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base32_encode(&bytes)
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = buf.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            encoded.push(BASE32[((bits >> (35 - i * 5)) & 31) as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut bits, mut count) = (0u64, 0);
+    for c in encoded.trim_end_matches('=').chars() {
+        let value = BASE32.iter().position(|&b| b as char == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+// The code of a time step (RFC 4226)
+fn code(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let value = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS)
+}
+
+// The time step a code is valid for, if it is one of the codes of the secret
+// around the given time
+pub fn verify(secret: &str, input: &str, now: DateTime<Utc>) -> Option<i64> {
+    if !is_code(input) {
+        return None;
+    }
+    let key = base32_decode(secret)?;
+    let input: u32 = input.trim().parse().ok()?;
+    let current = now.timestamp() / STEP;
+    (current - SKEW..=current + SKEW).find(|&step| code(&key, step) == input)
+}
+
+// Whether the input looks like a TOTP code rather than a recovery code
+pub fn is_code(input: &str) -> bool {
+    let input = input.trim();
+    input.len() == DIGITS as usize && input.chars().all(|c| c.is_ascii_digit())
+}
+
+// The otpauth:// URI authenticator apps add the secret from, usually shown as a QR code
+pub fn otpauth_uri(username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(ISSUER),
+        percent_encode(username),
+        secret,
+        percent_encode(ISSUER),
+        DIGITS,
+        STEP
+    )
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub fn current_code(secret: &str, now: DateTime<Utc>) -> String {
+    let key = base32_decode(secret).unwrap();
+    format!("{:0width$}", code(&key, now.timestamp() / STEP), width = DIGITS as usize)
+}
@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::db::Event;
+use crate::error::DatastoreError;
+
+// How uploaded events that don't fit their hour are handled.
+// Negative durations never get this far, they fail to deserialize.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventPolicy {
+    // Refuse the whole hour if any event is out of bounds or overlaps another
+    #[default]
+    Reject,
+    // Cut events to the hour and trim overlapping events so they follow each other
+    Clip,
+    // Like clip, but overlapping events of the same category are joined into one
+    Merge,
+}
+
+// Checks the events of an hour against the policy, returning the events to store
+// sorted by timestamp.
+pub fn sanitize_events(hour: DateTime<Utc>, mut events: Vec<Event>, policy: EventPolicy) -> Result<Vec<Event>, DatastoreError> {
+    let end = hour + Duration::hours(1);
+    events.sort_by_key(|e| e.timestamp);
+
+    let mut sanitized: Vec<Event> = Vec::with_capacity(events.len());
+    for mut event in events {
+        if event.duration > std::time::Duration::from_secs(3600) && policy == EventPolicy::Reject {
+            return Err(DatastoreError::BadRequest(format!(
+                "Event at {} has a duration of {}s, longer than an hour",
+                event.timestamp,
+                event.duration.as_secs()
+            )));
+        }
+        let mut start = event.timestamp;
+        // Events too long to represent end after the hour anyway, the clipping below cuts them to it
+        let mut stop = Duration::from_std(event.duration)
+            .ok()
+            .and_then(|duration| start.checked_add_signed(duration))
+            .unwrap_or(end);
+
+        if start < hour || start >= end || stop > end {
+            if policy == EventPolicy::Reject {
+                return Err(DatastoreError::BadRequest(format!(
+                    "Event at {} is not within the reported hour {}",
+                    event.timestamp, hour
+                )));
+            }
+            start = start.max(hour);
+            stop = stop.min(end);
+            if start >= stop {
+                continue;
+            }
+        }
+
+        if let Some(prev) = sanitized.last_mut() {
+            let prev_stop = prev.timestamp + Duration::from_std(prev.duration).unwrap();
+            if start < prev_stop {
+                match policy {
+                    EventPolicy::Reject => {
+                        return Err(DatastoreError::BadRequest(format!(
+                            "Event at {} overlaps the event at {}",
+                            event.timestamp, prev.timestamp
+                        )));
+                    }
+                    EventPolicy::Merge if prev.category == event.category => {
+                        if stop > prev_stop {
+                            prev.duration = (stop - prev.timestamp).to_std().unwrap();
+                        }
+                        continue;
+                    }
+                    _ => {
+                        start = prev_stop;
+                        if start >= stop {
+                            continue;
+                        }
+                    }
+                }
+            }
+        }
+
+        event.timestamp = start;
+        event.duration = (stop - start).to_std().unwrap();
+        sanitized.push(event);
+    }
+
+    let total: u64 = sanitized.iter().map(|e| e.duration.as_secs()).sum();
+    if total > 3600 {
+        return Err(DatastoreError::BadRequest(format!(
+            "Events add up to {}s, more than an hour",
+            total
+        )));
+    }
+    Ok(sanitized)
+}
+
+pub const USERNAME_MIN_LENGTH: usize = 3;
+pub const USERNAME_MAX_LENGTH: usize = 32;
+pub const PASSWORD_MIN_LENGTH: usize = 8;
+// bcrypt ignores everything after the first 72 bytes
+pub const PASSWORD_MAX_BYTES: usize = 72;
+
+// Names that would be confused with pages of the site or its staff
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "api", "static", "login", "logout", "signup", "user", "users",
+    "oauth", "root", "support", "system", "leaderboard",
+];
+
+// Passwords that are tried first when guessing, and fit the other rules
+const COMMON_PASSWORDS: &[&str] = &[
+    "password1", "password123", "passw0rd", "12345678a", "abc12345", "abcd1234", "qwerty123",
+    "qwerty12", "iloveyou1", "letmein1", "welcome1", "admin123", "trustno1", "1q2w3e4r",
+];
+
+// Usernames appear in URLs, so they are limited to letters, digits, `-` and `_`,
+// starting with a letter or digit
+pub fn check_username(username: &str) -> Result<(), String> {
+    let length = username.chars().count();
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        return Err(format!("must be {} to {} characters long", USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH));
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("may only contain letters, digits, `-` and `_`".to_string());
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("must start with a letter or digit".to_string());
+    }
+    if RESERVED_USERNAMES.contains(&username.to_ascii_lowercase().as_str()) {
+        return Err("is reserved".to_string());
+    }
+    Ok(())
+}
+
+// A plausible address, whether it exists is shown by confirming it
+pub fn check_email(email: &str) -> Result<(), String> {
+    let invalid = || Err("is not a valid email address".to_string());
+    if email.len() > 254 || email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return invalid();
+    }
+    let (local, domain) = match email.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return invalid(),
+    };
+    if local.is_empty() || local.len() > 64 || local.contains('@') {
+        return invalid();
+    }
+    let labels: Vec<&str> = domain.split('.').collect();
+    let valid_label = |label: &&str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+    };
+    if labels.len() < 2 || !labels.iter().all(valid_label) {
+        return invalid();
+    }
+    Ok(())
+}
+
+// Passwords need some length, more than one kind of character, and must not be
+// guessable from the account itself
+pub fn check_password(password: &str, username: &str, email: &str) -> Result<(), String> {
+    if password.chars().count() < PASSWORD_MIN_LENGTH {
+        return Err(format!("must be at least {} characters long", PASSWORD_MIN_LENGTH));
+    }
+    if password.len() > PASSWORD_MAX_BYTES {
+        return Err(format!("must be at most {} bytes long", PASSWORD_MAX_BYTES));
+    }
+    let kinds = [
+        password.chars().any(|c| c.is_alphabetic()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if kinds.iter().filter(|&&kind| kind).count() < 2 {
+        return Err("must mix at least two of letters, digits and symbols".to_string());
+    }
+    let lowercase = password.to_lowercase();
+    let local = email.split('@').next().unwrap_or_default();
+    for part in [username, local] {
+        if part.chars().count() >= USERNAME_MIN_LENGTH && lowercase.contains(&part.to_lowercase()) {
+            return Err("must not contain your username or email".to_string());
+        }
+    }
+    if COMMON_PASSWORDS.contains(&lowercase.as_str()) {
+        return Err("is too common".to_string());
+    }
+    Ok(())
+}
+
+// Problems with the fields of a signup, by field name. Empty if the signup can go ahead.
+pub fn check_signup(username: &str, email: &str, password: &str) -> BTreeMap<&'static str, String> {
+    let checks = [
+        ("username", check_username(username)),
+        ("email", check_email(email)),
+        ("password", check_password(password, username, email)),
+    ];
+    checks
+        .into_iter()
+        .filter_map(|(field, check)| check.err().map(|err| (field, err)))
+        .collect()
+}
+
+// A valid username from a name picked elsewhere, such as an OAuth account. Room is
+// left to add digits when it is taken.
+pub fn sanitize_username(name: &str) -> String {
+    let username: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .skip_while(|c| !c.is_ascii_alphanumeric())
+        .take(USERNAME_MAX_LENGTH - 4)
+        .collect();
+    match check_username(&username) {
+        Ok(()) => username,
+        Err(_) => format!("user-{}", username).chars().take(USERNAME_MAX_LENGTH - 4).collect(),
+    }
+}